@@ -3,7 +3,7 @@ mod schema;
 mod services;
 mod utils;
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use schema::ConfigFile;
 use utils::init_config;
 
@@ -21,6 +21,15 @@ struct Cli {
   command: Commands,
 }
 
+#[derive(Clone, Copy, Default, ValueEnum)]
+enum LogFormat {
+  /// Human readable lines
+  #[default]
+  Text,
+  /// One JSON object per line, for log aggregators such as Loki or ELK
+  Json,
+}
+
 #[derive(Subcommand)]
 enum Commands {
   #[command(
@@ -50,19 +59,26 @@ enum Commands {
                   This command starts the Relay, which is a client that will connect to the user Broker and send data to the TagoIO platform.\n\n\
                   The `config_path` option sets the path for the `config.toml` file. If not passed, it defaults to the environment variable `RELAY_CONFIG_PATH` or `$HOME/.config/tagoio-mqtt-relay.toml`.\n\n\
                   The `verbose` option accepts a string with types of logs: `info`, `error`, `mqtt`, `network`. Defaults to `info,error`.\n\n\
+                  The `log_format` option selects `text` (default) or `json` output. JSON lines carry the timestamp, level, target, message and fields such as `relay_id`, `topic`, `message_id` and `error`.\n\n\
                   Examples:\n\
                   - Start with default configuration:\n\
                     tago-relay start\n\
                   - Start with verbose mode:\n\
                     tago-relay start --verbose info,error,mqtt,network\n\
                   - Start with custom configuration path:\n\
-                    tago-relay start --config-path /path/to/config.toml"
+                    tago-relay start --config-path /path/to/config.toml\n\
+                  - Start with JSON logs:\n\
+                    tago-relay start --log-format json"
   )]
   Start {
     /// Verbose mode (-v)
     #[arg(short, long)]
     verbose: Option<String>,
 
+    /// Log output format
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
     /// Path to the configuration file
     #[arg(short, long)]
     config_path: Option<String>,
@@ -71,15 +87,96 @@ enum Commands {
     #[arg(long)]
     unsafe_mode: bool,
   },
+  #[command(
+    about = "Validate the configuration file",
+    long_about = "Validate the configuration file and the certificates it references, without connecting to TagoIO or the broker.\n\n\
+                  The command fails if a certificate or key cannot be loaded, or if a certificate is already expired.\n\n\
+                  Examples:\n\
+                  - Validate the default configuration:\n\
+                    tago-relay validate\n\
+                  - Validate a custom configuration path:\n\
+                    tago-relay validate --config-path /path/to/config.toml"
+  )]
+  Validate {
+    /// Verbose mode (-v)
+    #[arg(short, long)]
+    verbose: Option<String>,
+
+    /// Log output format
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Path to the configuration file
+    #[arg(short, long)]
+    config_path: Option<String>,
+  },
+  #[command(
+    about = "Send archived uplinks to TagoIO again",
+    long_about = "Send the uplinks kept by `[relay.archive]` through the current filters, decoders, scripts and routes again, e.g. after fixing a parser bug.\n\n\
+                  Records keep the time the uplink was originally received. `--from` and `--to` take RFC 3339 times (`--to` is exclusive) and `--topic` may be repeated.\n\n\
+                  Examples:\n\
+                  - Preview what one day of a topic would send:\n\
+                    tago-relay replay --from 2024-05-01T00:00:00Z --to 2024-05-02T00:00:00Z --topic 'sensors/#' --dry-run\n\
+                  - Replay everything at 5 messages per second:\n\
+                    tago-relay replay --rate 5"
+  )]
+  Replay {
+    /// Verbose mode (-v)
+    #[arg(short, long)]
+    verbose: Option<String>,
+
+    /// Log output format
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Path to the configuration file
+    #[arg(short, long)]
+    config_path: Option<String>,
+
+    /// Replay uplinks received at or after this time
+    #[arg(long)]
+    from: Option<jiff::Timestamp>,
+
+    /// Replay uplinks received before this time
+    #[arg(long)]
+    to: Option<jiff::Timestamp>,
+
+    /// Only replay topics matching this filter
+    #[arg(long)]
+    topic: Vec<String>,
+
+    /// Messages sent per second
+    #[arg(long, default_value_t = 10.0, value_parser = parse_rate)]
+    rate: f64,
+
+    /// Log the records instead of sending them
+    #[arg(long)]
+    dry_run: bool,
+  },
 }
 
-fn init_log_level(verbose: &Option<String>) {
+/**
+ * Replay rate: a finite number of messages per second above 0, at most one per nanosecond
+ */
+fn parse_rate(value: &str) -> Result<f64, String> {
+  let rate: f64 = value.parse().map_err(|e| format!("{}", e))?;
+  match std::time::Duration::try_from_secs_f64(1.0 / rate) {
+    Ok(period) if rate.is_finite() && rate > 0.0 && !period.is_zero() => Ok(rate),
+    _ => Err("must be a number of messages per second above 0, up to 1000000000".to_string()),
+  }
+}
+
+fn init_log_level(verbose: &Option<String>, log_format: LogFormat) {
   let log_level: String = verbose
     .as_ref()
     .map(|v| v.to_string())
     .unwrap_or_else(|| "error,info".to_string());
 
-  env_logger::init_from_env(env_logger::Env::new().default_filter_or(log_level));
+  let mut builder = env_logger::Builder::from_env(env_logger::Env::new().default_filter_or(log_level));
+  if let LogFormat::Json = log_format {
+    builder.format(utils::format_json_log);
+  }
+  builder.init();
 }
 
 #[tokio::main]
@@ -87,8 +184,16 @@ async fn main() {
   let cli = Cli::parse();
   // Initialize log level
   match &cli.command {
-    Commands::Init { verbose, .. } => init_log_level(verbose),
-    Commands::Start { verbose, .. } => init_log_level(verbose),
+    Commands::Init { verbose, .. } => init_log_level(verbose, LogFormat::Text),
+    Commands::Start {
+      verbose, log_format, ..
+    } => init_log_level(verbose, *log_format),
+    Commands::Validate {
+      verbose, log_format, ..
+    } => init_log_level(verbose, *log_format),
+    Commands::Replay {
+      verbose, log_format, ..
+    } => init_log_level(verbose, *log_format),
   }
 
   match &cli.command {
@@ -100,20 +205,62 @@ async fn main() {
     }
     Commands::Start {
       verbose: _,
+      log_format: _,
       config_path,
       unsafe_mode,
     } => {
-      let config = utils::fetch_config_file(config_path.clone());
-      if let Some(config) = config {
-        *CONFIG_FILE.write().unwrap() = Some(config);
-      } else {
-        log::error!("Failed to load configuration file.");
+      load_config(config_path);
+
+      if let Err(e) = relay::start_relay(*unsafe_mode).await {
+        log::error!(target: "error", "Error starting relay: {}", e);
+      }
+    }
+    Commands::Validate {
+      verbose: _,
+      log_format: _,
+      config_path,
+    } => {
+      load_config(config_path);
+
+      if let Err(e) = relay::validate_relay().await {
+        log::error!(target: "error", "Invalid configuration: {:#}", e);
         std::process::exit(1);
       }
+      log::info!(target: "info", "Configuration is valid");
+    }
+    Commands::Replay {
+      verbose: _,
+      log_format: _,
+      config_path,
+      from,
+      to,
+      topic,
+      rate,
+      dry_run,
+    } => {
+      load_config(config_path);
 
-      if let Err(e) = relay::start_relay(*unsafe_mode).await {
-        log::error!("Error starting relay: {}", e);
+      let options = services::archive::ReplayOptions {
+        from: *from,
+        to: *to,
+        topics: topic.clone(),
+        rate: *rate,
+        dry_run: *dry_run,
+      };
+      if let Err(e) = relay::replay_relay(options).await {
+        log::error!(target: "error", "Replay failed: {:#}", e);
+        std::process::exit(1);
       }
     }
   }
 }
+
+fn load_config(config_path: &Option<String>) {
+  let config = utils::fetch_config_file(config_path.clone());
+  if let Some(config) = config {
+    *CONFIG_FILE.write().unwrap() = Some(config);
+  } else {
+    log::error!(target: "error", "Failed to load configuration file.");
+    std::process::exit(1);
+  }
+}
@@ -1,8 +1,13 @@
 use crate::{
+  schema::RelayConfig,
   services::{
-    mosquitto_auth,
-    mqttrelay::{run_mqtt_relay_connection, PublishMessage},
-    tagoio::get_relay_list,
+    archive::{self, ReplayOptions},
+    certificates,
+    dedup::dedup_status,
+    lorawan, metrics, mosquitto_auth,
+    mqttrelay::{initialize_mqtt_options, run_mqtt_relay_connection, PublishMessage},
+    sparkplug::sparkplug_status,
+    tagoio::{circuit_breaker_status, get_relay_list},
   },
   CONFIG_FILE,
 };
@@ -51,6 +56,9 @@ fn create_ssl_acceptor(unsafe_mode: bool) -> Result<Arc<SslAcceptor>, openssl::e
   let key = PKey::private_key_from_pem(key)?;
   let ca = X509::from_pem(ca)?;
 
+  certificates::track("api/server", &cert);
+  certificates::track("api/ca", &ca);
+
   let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
   acceptor.set_private_key(&key)?;
   acceptor.set_certificate(&cert)?;
@@ -76,6 +84,54 @@ fn create_ssl_acceptor(unsafe_mode: bool) -> Result<Arc<SslAcceptor>, openssl::e
   Ok(Arc::new(acceptor.build()))
 }
 
+/**
+ * Days before expiry at which certificate warnings are logged
+ */
+fn cert_expiry_warn_days() -> Vec<i64> {
+  let config_file = CONFIG_FILE.read().unwrap();
+  config_file
+    .as_ref()
+    .and_then(|config| config.cert_expiry_warn_days.clone())
+    .unwrap_or_else(|| certificates::DEFAULT_WARN_DAYS.to_vec())
+}
+
+/**
+ * Validate the configuration without connecting anywhere: HTTP client settings,
+ * broker TLS material, Publish API certificates and certificate expiry
+ */
+pub async fn validate_relay() -> Result<()> {
+  let relay_list = get_relay_list().await?;
+  for relay in &relay_list {
+    initialize_mqtt_options(relay)
+      .map_err(|e| anyhow::anyhow!("Invalid MQTT settings for relay {}: {:#}", relay.id, e))?;
+  }
+
+  create_ssl_acceptor(false).map_err(|e| anyhow::anyhow!("Invalid Publish API certificates: {}", e))?;
+
+  certificates::check_expiry(&cert_expiry_warn_days());
+  let expired = certificates::expired();
+  if !expired.is_empty() {
+    anyhow::bail!("Expired certificates: {}", expired.join("; "));
+  }
+
+  for (source, certificate) in certificates::status().as_object().into_iter().flatten() {
+    log::info!(target: "info", "Certificate {} expires in {} day(s)", source, certificate["days_left"]);
+  }
+  Ok(())
+}
+
+/**
+ * Send the archived uplinks of every relay through the current pipeline again
+ */
+pub async fn replay_relay(options: ReplayOptions) -> Result<()> {
+  let relay_list = get_relay_list().await?;
+  for relay in &relay_list {
+    let replayed = archive::replay(relay, &options).await?;
+    log::info!(target: "info", relay_id = relay.id.as_str(); "Replayed {} archived uplink(s) for relay {}", replayed, relay.id);
+  }
+  Ok(())
+}
+
 /**
  * Start the MQTT Relay service
  */
@@ -88,7 +144,13 @@ pub async fn start_relay(unsafe_mode: bool) -> Result<()> {
     let mut relays = relay_list.write().await;
     for relay in relays.iter_mut() {
       if let Err(e) = Arc::make_mut(relay).verify().await {
-        log::error!(target: "network", "Failed to verify relay {}: {}", relay.id, e);
+        log::error!(target: "network", relay_id = relay.id.as_str(), error:% = e; "Failed to verify relay {}: {}", relay.id, e);
+        std::process::exit(1);
+      }
+
+      // Certificates and keys are loaded up front so a bad TLS setup stops the relay instead of downgrading it
+      if let Err(e) = initialize_mqtt_options(relay) {
+        log::error!(target: "mqtt", relay_id = relay.id.as_str(), error:% = format!("{:#}", e); "Invalid MQTT settings for relay {}: {:#}", relay.id, e);
         std::process::exit(1);
       }
     }
@@ -100,6 +162,7 @@ pub async fn start_relay(unsafe_mode: bool) -> Result<()> {
   let app = Router::new()
     .route("/publish", post(handle_publish))
     .route("/status", get(handle_status))
+    .route("/metrics", get(handle_metrics))
     .route("/auth", post(mosquitto_auth::handle_auth))
     .route("/superuser", post(mosquitto_auth::handle_superuser))
     .route("/acl", post(mosquitto_auth::handle_acl))
@@ -114,6 +177,9 @@ pub async fn start_relay(unsafe_mode: bool) -> Result<()> {
   let test = create_ssl_acceptor(unsafe_mode).unwrap();
   let acceptor = OpenSSLConfig::from_acceptor(test);
 
+  let warn_days = cert_expiry_warn_days();
+  certificates::check_expiry(&warn_days);
+
   let addr = SocketAddr::from((HOST_ADDRESS.parse::<std::net::IpAddr>().unwrap(), api_port));
 
   tokio::spawn(async move {
@@ -129,6 +195,8 @@ pub async fn start_relay(unsafe_mode: bool) -> Result<()> {
     let relay_list = relay_list.read().await.clone();
 
     for relay in &relay_list {
+      relay.config.refresh_secrets();
+
       let relay_id = relay.id.clone();
       if !tasks.read().await.contains_key(&relay_id) {
         let relay_clone = Arc::clone(relay);
@@ -142,6 +210,8 @@ pub async fn start_relay(unsafe_mode: bool) -> Result<()> {
 
     tasks.write().await.retain(|_, (task, _)| !task.is_finished());
 
+    certificates::check_expiry(&warn_days);
+
     // Relay will be restarted after 120 seconds
     sleep(Duration::from_secs(RESTART_DELAY_SECS)).await;
   }
@@ -149,11 +219,15 @@ pub async fn start_relay(unsafe_mode: bool) -> Result<()> {
 
 #[derive(serde::Deserialize)]
 struct PublishRequest {
+  #[serde(default)]
   topic: String,
   message: String,
   relay_id: Option<String>,
   qos: u8,
   retain: bool,
+  device: Option<String>,  // LoRaWAN downlink target: devEUI for ChirpStack, device ID for TTN
+  fport: Option<u8>,       // 1 to 223. Default is 1
+  confirmed: Option<bool>, // Default is false
 }
 
 /**
@@ -175,6 +249,7 @@ type SharedTaskMap = Arc<RwLock<TaskMap>>;
 
 async fn handle_publish(
   Extension(tasks): Extension<SharedTaskMap>,
+  Extension(relay_list): Extension<Arc<RwLock<Vec<Arc<RelayConfig>>>>>,
   payload: Result<Json<PublishRequest>, JsonRejection>,
 ) -> Response {
   let payload = match payload {
@@ -196,6 +271,11 @@ async fn handle_publish(
     }
   };
 
+  if rumqttc::qos(payload.qos).is_err() {
+    let error = "Invalid JSON data: qos must be 0, 1 or 2";
+    return (StatusCode::UNPROCESSABLE_ENTITY, Json(json!({ "error": error }))).into_response();
+  }
+
   let tasks = tasks.read().await;
   let relay_id = if payload.relay_id.is_none() {
     if let Some(first_relay_id) = tasks.keys().next() {
@@ -207,12 +287,46 @@ async fn handle_publish(
     payload.relay_id.clone().unwrap()
   };
 
+  // Downlinks to a LoRaWAN device go through the network server of the relay
+  let (topic, message) = match &payload.device {
+    Some(device) => {
+      let relays = relay_list.read().await;
+      let Some(relay) = relays.iter().find(|relay| relay.id == relay_id) else {
+        return JsonError(axum::http::StatusCode::NOT_FOUND).into_response();
+      };
+      let lorawan = &relay.config.mqtt.lorawan;
+      let fport = payload.fport.unwrap_or(1);
+      match lorawan::downlink(
+        lorawan,
+        device,
+        fport,
+        payload.confirmed.unwrap_or(false),
+        &payload.message,
+      ) {
+        Ok(downlink) => downlink,
+        Err(e) => {
+          return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({ "error": format!("{:#}", e) })),
+          )
+            .into_response();
+        }
+      }
+    }
+    None if payload.topic.is_empty() => {
+      let error = "Invalid JSON data: missing field `topic`";
+      return (StatusCode::UNPROCESSABLE_ENTITY, Json(json!({ "error": error }))).into_response();
+    }
+    None => (payload.topic.clone(), payload.message.clone()),
+  };
+
   if let Some((_, publish_tx)) = tasks.get(&relay_id) {
     let message = PublishMessage {
-      topic: payload.topic.clone(),
-      message: payload.message.clone(),
+      topic,
+      message,
       qos: payload.qos,
       retain: payload.retain,
+      queued_at: std::time::Instant::now(),
     };
 
     match publish_tx.send(message).await {
@@ -228,5 +342,25 @@ async fn handle_publish(
  * Handle status request for health check
  */
 pub async fn handle_status() -> impl IntoResponse {
-  (StatusCode::OK, Json(json!({ "status": "ok" })))
+  (
+    StatusCode::OK,
+    Json(json!({
+      "status": "ok",
+      "circuit_breakers": circuit_breaker_status(),
+      "certificates": certificates::status(),
+      "dedup": dedup_status(),
+      "sparkplug": sparkplug_status(),
+    })),
+  )
+}
+
+/**
+ * Expose the relay metrics in the Prometheus text format
+ */
+pub async fn handle_metrics() -> impl IntoResponse {
+  (
+    StatusCode::OK,
+    [(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+    metrics::render_prometheus(),
+  )
 }
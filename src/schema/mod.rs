@@ -1,4 +1,10 @@
-use crate::services::tagoio::verify_network_token;
+use crate::services::{
+  filters::topic_matches,
+  tagoio::{build_http_client, verify_network_token},
+};
+
+mod secret;
+pub use secret::{resolve_reference, scrub_url, Secret};
 
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
@@ -7,15 +13,86 @@ pub struct RelayConfig {
   pub config: ConfigFile,
   pub profile_id: Option<String>,
   pub network_id: Option<String>,
+  pub http_client: reqwest::Client,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
 pub struct ConfigFile {
-  pub network_token: String,
-  pub authorization_token: String,
-  pub tagoio_url: Option<String>, // Default is "https://api.tago.io"
-  pub downlink_port: Option<u16>, // Default is "3000"
+  pub network_token: Secret,
+  pub authorization_token: Secret,
+  pub tagoio_url: Option<String>,              // Default is "https://api.tago.io"
+  pub downlink_port: Option<u16>,              // Default is "3000"
+  pub downlink_ttl: Option<u64>,               // Default is 300 seconds
+  pub downlink_queue_size: Option<usize>,      // Downlinks kept for the broker before dropping. Default is 1000
+  pub tagoio_tls_ca: Option<String>,           // Extra CA bundle trusted for the TagoIO API
+  pub tagoio_tls_cert: Option<String>,         // Client certificate presented to the TagoIO API
+  pub tagoio_tls_key: Option<String>,          // Client key presented to the TagoIO API
+  pub tagoio_proxy: Option<String>,            // HTTP(S) or SOCKS5 proxy used to reach TagoIO
+  pub tagoio_no_proxy: Option<Vec<String>>,    // Hosts that bypass the proxy
+  pub cert_expiry_warn_days: Option<Vec<i64>>, // Default is [30, 7, 1]
   pub mqtt: Mqtt,
+  #[serde(default)]
+  pub forwarding: Forwarding,
+  #[serde(default)]
+  pub routes: Vec<Route>,
+  #[serde(default)]
+  pub sinks: Vec<Sink>,
+  #[serde(default)]
+  pub archive: Archive,
+}
+
+/**
+ * Rotating on-disk archive of every uplink as received, read by `tago-relay replay`
+ */
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct Archive {
+  pub enabled: Option<bool>,     // Default is false
+  pub path: Option<String>,      // Default is "archive/uplinks.jsonl"
+  pub max_bytes: Option<u64>,    // Default is 104857600 (100 MB)
+  pub max_files: Option<usize>,  // Rotated files kept. Default is 10
+  pub queue_size: Option<usize>, // Uplinks waiting to be written before dropping. Default is 10000
+}
+
+/**
+ * Destination of the records of the topics matching `topic`. The first matching route is used;
+ * other topics go to the relay `tagoio_url` with the relay tokens.
+ */
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct Route {
+  pub topic: String,
+  pub authorization_token: Option<Secret>, // Default is the relay authorization_token
+  pub network_token: Option<Secret>,       // Default is the relay network_token
+  pub tagoio_url: Option<String>,          // TagoIO region. Default is the relay tagoio_url
+  pub device_token: Option<Secret>,        // Send to the device data API with this token instead of the network
+  #[serde(default)]
+  pub sinks: Vec<String>, // Names of the sinks receiving the records. Default is ["tagoio"]
+}
+
+/**
+ * Additional destination of uplink records, selected by name in `routes`.
+ * `tagoio` is the reserved name of the relay's own TagoIO destination.
+ */
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct Sink {
+  pub name: String,
+  #[serde(rename = "type")]
+  pub kind: String, // "tagoio", "webhook" or "file"
+  // tagoio
+  pub tagoio_url: Option<String>,          // Default is the relay tagoio_url
+  pub network_token: Option<Secret>,       // Default is the relay network_token
+  pub authorization_token: Option<Secret>, // Default is the relay authorization_token
+  // webhook
+  pub url: Option<String>,
+  pub method: Option<String>, // Default is "POST"
+  #[serde(default)]
+  pub headers: std::collections::HashMap<String, Secret>,
+  pub body_template: Option<String>, // Default is {"topic": {{topic}}, "relay_id": {{relay_id}}, "records": {{records}}}
+  // file
+  pub path: Option<String>,
+  pub max_bytes: Option<u64>,         // Default is 10485760 (10 MB)
+  pub max_files: Option<usize>,       // Rotated files kept. Default is 5
+  pub forwarding: Option<Forwarding>, // Retries of this sink. Default is the relay forwarding
+  pub queue_size: Option<usize>,      // Batches waiting to be sent before dropping. Default is 1000
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
@@ -24,12 +101,209 @@ pub struct Mqtt {
   pub tls_enabled: bool,
   pub address: String,
   pub port: u16,
-  pub subscribe: Vec<String>,          // Default is ["/tago/#", "/device/+"]
-  pub username: Option<String>,        // Default is "my-username"
-  pub password: Option<String>,        // Default is "my-password"
-  pub broker_tls_ca: Option<String>,   // Default is "certs/ca.crt"
-  pub broker_tls_cert: Option<String>, // Default is "certs/client.crt"
-  pub broker_tls_key: Option<String>,  // Default is "certs/client.key"
+  pub subscribe: Vec<String>,                  // Default is ["/tago/#", "/device/+"]
+  pub username: Option<String>,                // Default is "my-username"
+  pub password: Option<Secret>,                // Default is "my-password"
+  pub broker_tls_ca: Option<String>,           // Default is "certs/ca.crt"
+  pub broker_tls_cert: Option<String>,         // Default is "certs/client.crt"
+  pub broker_tls_key: Option<String>,          // Default is "certs/client.key"
+  pub broker_tls_key_password: Option<Secret>, // Passphrase of an encrypted broker_tls_key
+  pub broker_tls_pkcs12: Option<String>,       // PKCS#12 bundle with the client certificate and key
+  pub broker_tls_pkcs12_password: Option<Secret>,
+  pub tls_insecure_skip_verify: Option<bool>, // Default is false
+  pub tls_server_name: Option<String>,        // Name verified in the broker certificate. Default is `address`
+  pub tls_alpn: Option<Vec<String>>,          // ALPN protocols offered to the broker
+  pub tls_min_version: Option<String>,        // "1.2" or "1.3". Default is "1.2"
+  pub protobuf_descriptor: Option<String>,    // Descriptor set of the "protobuf" subscriptions
+  #[serde(default)]
+  pub filters: MessageFilters,
+  #[serde(default)]
+  pub dedup: Dedup,
+  #[serde(default)]
+  pub rate_limits: Vec<RateLimit>,
+  #[serde(default)]
+  pub subscriptions: Vec<Subscription>,
+  #[serde(default)]
+  pub script_limits: ScriptLimits,
+  #[serde(default)]
+  pub wasm_limits: WasmLimits,
+  #[serde(default)]
+  pub sparkplug: Sparkplug,
+  #[serde(default)]
+  pub lorawan: LoRaWan,
+}
+
+/**
+ * Options for the uplinks matching `topic`. The topic is subscribed to along with `subscribe`.
+ */
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct Subscription {
+  pub topic: String,
+  pub payload_format: Option<String>, // "raw", "json", "cbor", "msgpack" or "protobuf". Default is "raw"
+  pub protobuf_message: Option<String>, // Message type of "protobuf" payloads, e.g. "sensors.Reading"
+  pub deadband: Option<Deadband>,
+  pub aggregate: Option<Aggregate>,
+  #[serde(default)]
+  pub metadata: RecordMetadata,
+  pub script: Option<String>,  // Rhai script building the records of each message
+  pub decoder: Option<String>, // WebAssembly module decoding each message, run before `script`
+}
+
+/**
+ * Limits applied to every run of a subscription script
+ */
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct ScriptLimits {
+  pub timeout_ms: Option<u64>,        // Default is 100
+  pub max_operations: Option<u64>,    // Default is 1000000
+  pub max_string_size: Option<usize>, // Default is 1 MB
+  pub max_array_size: Option<usize>,  // Default is 10000
+  pub max_map_size: Option<usize>,    // Default is 10000
+}
+
+/**
+ * Sparkplug B host mode: decodes node and device messages into TagoIO records
+ */
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct Sparkplug {
+  pub enabled: Option<bool>,         // Default is false
+  pub topic: Option<String>,         // Default is "spBv1.0/#"
+  pub node_serial: Option<String>,   // Default is "{group_id}/{edge_node_id}"
+  pub device_serial: Option<String>, // Default is "{group_id}/{edge_node_id}/{device_id}"
+}
+
+/**
+ * LoRaWAN network-server integration: uplink envelopes become TagoIO records and
+ * `/publish` requests for a device become downlinks
+ */
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct LoRaWan {
+  pub adapter: Option<String>,        // "chirpstack" or "ttn". Default is none
+  pub uplink_topic: Option<String>,   // Default is the uplink topic of the adapter
+  pub application_id: Option<String>, // Application of the downlink topic. For TTN, "app-id@tenant-id"
+}
+
+/**
+ * Limits applied to every run of a subscription decoder
+ */
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct WasmLimits {
+  pub fuel: Option<u64>,               // Instructions budget per message. Default is 10000000
+  pub timeout_ms: Option<u64>,         // Caps the fuel at 100000 per millisecond. Default is 100
+  pub max_memory_bytes: Option<usize>, // Default is 16 MB
+}
+
+/**
+ * Time and metadata added to the records of an uplink
+ */
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct RecordMetadata {
+  pub time: Option<bool>,              // Broker-receive time as the record `time`. Default is true
+  pub timestamp_field: Option<String>, // JSON field with the device timestamp, preferred over the receive time
+  pub retain: Option<bool>,            // Default is true
+  pub dup: Option<bool>,               // Default is true
+  pub packet_id: Option<bool>,         // Default is true
+  pub relay_id: Option<bool>,          // Default is true
+  pub broker: Option<bool>,            // Broker address. Default is true
+}
+
+/**
+ * Tumbling window aggregation: one record per variable and window instead of every sample
+ */
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct Aggregate {
+  pub window_secs: u64,
+  pub function: Option<String>, // "min", "max", "avg", "last" or "count". Default is "avg"
+}
+
+/**
+ * Report-by-exception: numeric variables are only forwarded when they change enough
+ */
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct Deadband {
+  pub absolute: Option<f64>,         // Minimum absolute change
+  pub percent: Option<f64>,          // Minimum change, in percent of the last forwarded value
+  pub max_silence_secs: Option<u64>, // Forward anyway once this long has passed. Default is never
+}
+
+/**
+ * Token bucket limiting the uplinks forwarded for the topics matching `topic`
+ */
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct RateLimit {
+  pub topic: Option<String>,     // Default is "#"
+  pub scope: Option<String>,     // "topic", "device" or "relay". Default is "topic"
+  pub rate: f64,                 // Messages per second
+  pub burst: Option<f64>,        // Default is `rate`, at least 1 message
+  pub action: Option<String>,    // "drop", "sample" or "latest". Default is "drop"
+  pub sample_every: Option<u64>, // With "sample": forward every Nth message over the limit. Default is 10
+}
+
+/**
+ * Skip uplinks already forwarded within a time window
+ */
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct Dedup {
+  pub enabled: Option<bool>,      // Default is false
+  pub window_secs: Option<u64>,   // Default is 60 seconds
+  pub id_field: Option<String>,   // JSON field identifying a message. Default is topic + payload hash
+  pub max_entries: Option<usize>, // Default is 10000 remembered messages
+}
+
+/**
+ * Rules deciding which incoming messages are forwarded to TagoIO
+ */
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct MessageFilters {
+  pub include: Option<Vec<String>>,     // Default is every subscribed topic
+  pub exclude: Option<Vec<String>>,     // Topic filters that are never forwarded
+  pub skip_retained: Option<bool>,      // Default is false
+  pub min_payload_bytes: Option<usize>, // Default is no limit
+  pub max_payload_bytes: Option<usize>, // Default is no limit
+  #[serde(default)]
+  pub json: Vec<JsonPredicate>, // Every predicate must match
+}
+
+/**
+ * Condition on a field of a JSON payload. `field` is a dotted path, e.g. `data.type`
+ */
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct JsonPredicate {
+  pub field: String,
+  pub exists: Option<bool>,
+  pub equals: Option<serde_json::Value>,
+  pub not_equals: Option<serde_json::Value>,
+  pub min: Option<f64>,
+  pub max: Option<f64>,
+}
+
+/**
+ * Policy used when forwarding uplink messages to TagoIO
+ */
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct Forwarding {
+  pub request_timeout_ms: Option<u64>,      // Default is 10000
+  pub connect_timeout_ms: Option<u64>,      // Default is 5000
+  pub max_retries: Option<u32>,             // Default is 5
+  pub backoff_base_ms: Option<u64>,         // Default is 500
+  pub backoff_max_ms: Option<u64>,          // Default is 30000
+  pub backoff_jitter: Option<bool>,         // Default is true
+  pub retry_status_codes: Option<Vec<u16>>, // Default is 429 and any 5xx
+  pub max_concurrency: Option<usize>,       // Default is 50
+  #[serde(default)]
+  pub circuit_breaker: CircuitBreakerPolicy,
+}
+
+/**
+ * Circuit breaker around the TagoIO API
+ */
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct CircuitBreakerPolicy {
+  pub enabled: Option<bool>,           // Default is true
+  pub failure_threshold: Option<u32>,  // Default is 5 consecutive failures
+  pub open_duration_secs: Option<u64>, // Default is 30 seconds
+  pub fallback: Option<String>,        // "buffer" or "drop". Default is "buffer"
+  pub buffer_size: Option<usize>,      // Default is 1000 messages
 }
 
 impl RelayConfig {
@@ -38,16 +312,20 @@ impl RelayConfig {
     let id = "self-hosted".to_string();
     let profile_id = Some(profile_id.unwrap_or_else(|| "self-hosted".to_string()));
 
+    let config = config.with_defaults()?;
+    let http_client = build_http_client(&config)?;
+
     Ok(RelayConfig {
       id,
-      config: config.with_defaults()?,
+      config,
       profile_id,
       network_id: None,
+      http_client,
     })
   }
 
   pub async fn verify(&mut self) -> anyhow::Result<()> {
-    log::info!(target: "network", "Verifying network token for relay: {}", self.id);
+    log::info!(target: "network", relay_id = self.id.as_str(); "Verifying network token for relay: {}", self.id);
     match verify_network_token(self).await {
       Ok(verified_id) => {
         self.network_id = Some(verified_id);
@@ -66,16 +344,235 @@ impl ConfigFile {
     if self.downlink_port.is_none() {
       self.downlink_port = Option::from(3000);
     }
+    if self.downlink_ttl.is_none() {
+      self.downlink_ttl = Some(300);
+    }
     self.mqtt = self.mqtt.with_defaults()?;
+    self.forwarding = self.forwarding.with_defaults()?;
+
+    let mut names = std::collections::HashSet::new();
+    for sink in &self.sinks {
+      if sink.name == "tagoio" || !names.insert(sink.name.as_str()) {
+        anyhow::bail!("sink name \"{}\" is reserved or used twice", sink.name);
+      }
+      match sink.kind.as_str() {
+        "tagoio" => {}
+        "webhook" if sink.url.is_none() => anyhow::bail!("webhook sink \"{}\" needs a url", sink.name),
+        "file" if sink.path.is_none() => anyhow::bail!("file sink \"{}\" needs a path", sink.name),
+        "webhook" => {
+          crate::services::sinks::webhook_method(sink)?;
+          crate::services::sinks::webhook_headers(sink)?;
+        }
+        "file" => {}
+        kind => anyhow::bail!("sink \"{}\" has an unknown type \"{}\"", sink.name, kind),
+      }
+    }
+    for route in &self.routes {
+      for name in &route.sinks {
+        if name != "tagoio" && !names.contains(name.as_str()) {
+          anyhow::bail!("route \"{}\" sends to an unknown sink \"{}\"", route.topic, name);
+        }
+      }
+    }
+    Ok(self)
+  }
+
+  /**
+   * First route whose topic filter matches the topic
+   */
+  pub fn route(&self, topic: &str) -> Option<&Route> {
+    self.routes.iter().find(|route| topic_matches(topic, &route.topic))
+  }
+
+  /**
+   * Read `file:`/`env:` secrets again, so rotated tokens and passwords are used without a restart
+   */
+  pub fn refresh_secrets(&self) {
+    let mut secrets = vec![
+      ("network_token".to_string(), Some(&self.network_token)),
+      ("authorization_token".to_string(), Some(&self.authorization_token)),
+      ("mqtt.password".to_string(), self.mqtt.password.as_ref()),
+      (
+        "mqtt.broker_tls_key_password".to_string(),
+        self.mqtt.broker_tls_key_password.as_ref(),
+      ),
+      (
+        "mqtt.broker_tls_pkcs12_password".to_string(),
+        self.mqtt.broker_tls_pkcs12_password.as_ref(),
+      ),
+    ];
+    for (index, route) in self.routes.iter().enumerate() {
+      secrets.push((
+        format!("routes[{}].authorization_token", index),
+        route.authorization_token.as_ref(),
+      ));
+      secrets.push((format!("routes[{}].network_token", index), route.network_token.as_ref()));
+      secrets.push((format!("routes[{}].device_token", index), route.device_token.as_ref()));
+    }
+    for sink in &self.sinks {
+      secrets.push((
+        format!("sinks.{}.network_token", sink.name),
+        sink.network_token.as_ref(),
+      ));
+      secrets.push((
+        format!("sinks.{}.authorization_token", sink.name),
+        sink.authorization_token.as_ref(),
+      ));
+      for (header, value) in &sink.headers {
+        secrets.push((format!("sinks.{}.headers.{}", sink.name, header), Some(value)));
+      }
+    }
+    for (name, secret) in secrets {
+      match secret.map(Secret::refresh) {
+        Some(Ok(true)) => log::info!(target: "security", "Secret {} changed and was reloaded", name),
+        Some(Err(e)) => log::warn!(target: "security", "Could not reload secret {}: {}", name, e),
+        _ => {}
+      }
+    }
+  }
+}
+
+impl Forwarding {
+  pub fn with_defaults(mut self) -> anyhow::Result<Self> {
+    self.request_timeout_ms.get_or_insert(10_000);
+    self.connect_timeout_ms.get_or_insert(5_000);
+    self.max_retries.get_or_insert(5);
+    self.backoff_base_ms.get_or_insert(500);
+    self.backoff_max_ms.get_or_insert(30_000);
+    self.backoff_jitter.get_or_insert(true);
+    if *self.max_concurrency.get_or_insert(50) == 0 {
+      anyhow::bail!("forwarding.max_concurrency must be at least 1");
+    }
+    self.circuit_breaker = self.circuit_breaker.with_defaults()?;
+    Ok(self)
+  }
+}
+
+impl CircuitBreakerPolicy {
+  pub fn with_defaults(mut self) -> anyhow::Result<Self> {
+    self.enabled.get_or_insert(true);
+    self.failure_threshold.get_or_insert(5);
+    self.open_duration_secs.get_or_insert(30);
+    let fallback = self.fallback.get_or_insert_with(|| "buffer".to_string());
+    if fallback != "buffer" && fallback != "drop" {
+      anyhow::bail!(
+        "circuit_breaker.fallback must be \"buffer\" or \"drop\", got \"{}\"",
+        fallback
+      );
+    }
+    self.buffer_size.get_or_insert(1000);
     Ok(self)
   }
 }
 
 impl Mqtt {
+  /**
+   * Topic filters subscribed to: `subscribe` plus the topic of every subscription
+   * and the Sparkplug and LoRaWAN uplink topics when enabled
+   */
+  pub fn topic_filters(&self) -> Vec<String> {
+    let mut filters = self.subscribe.clone();
+    let sparkplug_topic = self
+      .sparkplug
+      .enabled
+      .unwrap_or(false)
+      .then(|| self.sparkplug.topic.clone().unwrap_or_else(|| "spBv1.0/#".to_string()));
+    let lorawan_topic = crate::services::lorawan::uplink_topic(&self.lorawan);
+    let topics = self.subscriptions.iter().map(|subscription| subscription.topic.clone());
+    for topic in topics.chain(sparkplug_topic).chain(lorawan_topic) {
+      if !filters.contains(&topic) {
+        filters.push(topic);
+      }
+    }
+    filters
+  }
+
+  /**
+   * First subscription whose topic filter matches the topic
+   */
+  pub fn subscription(&self, topic: &str) -> Option<&Subscription> {
+    self
+      .subscriptions
+      .iter()
+      .find(|subscription| topic_matches(topic, &subscription.topic))
+  }
+
   pub fn with_defaults(mut self) -> anyhow::Result<Self> {
     if self.client_id.is_none() {
       self.client_id = Some("tagoio-relay".to_string());
     }
+    if !matches!(self.lorawan.adapter.as_deref(), None | Some("chirpstack" | "ttn")) {
+      anyhow::bail!("lorawan: adapter must be \"chirpstack\" or \"ttn\"");
+    }
+    for subscription in &self.subscriptions {
+      if !matches!(
+        subscription.payload_format.as_deref(),
+        None | Some("raw" | "json" | "cbor" | "msgpack" | "protobuf")
+      ) {
+        anyhow::bail!(
+          "subscriptions: payload_format of {} must be \"raw\", \"json\", \"cbor\", \"msgpack\" or \"protobuf\"",
+          subscription.topic
+        );
+      }
+      if subscription.payload_format.as_deref() == Some("protobuf") {
+        let (Some(descriptor), Some(message)) = (&self.protobuf_descriptor, &subscription.protobuf_message) else {
+          anyhow::bail!(
+            "subscriptions: payload_format \"protobuf\" of {} needs protobuf_message and mqtt.protobuf_descriptor",
+            subscription.topic
+          );
+        };
+        crate::services::codecs::protobuf_message(descriptor, message)?;
+      }
+      if let Some(script) = &subscription.script {
+        // Compile now so a broken script stops the relay at startup
+        crate::services::scripting::compile(script)?;
+      }
+      if let Some(decoder) = &subscription.decoder {
+        crate::services::wasm_decoder::compile(decoder)?;
+      }
+      if let Some(deadband) = &subscription.deadband {
+        for (name, threshold) in [("absolute", deadband.absolute), ("percent", deadband.percent)] {
+          if threshold.is_some_and(|threshold| !threshold.is_finite() || threshold < 0.0) {
+            anyhow::bail!(
+              "subscriptions: deadband {} of {} must be a finite number of at least 0",
+              name,
+              subscription.topic
+            );
+          }
+        }
+      }
+      if let Some(aggregate) = &subscription.aggregate {
+        if aggregate.window_secs == 0 {
+          anyhow::bail!(
+            "subscriptions: aggregate window_secs of {} must be greater than 0",
+            subscription.topic
+          );
+        }
+        if !matches!(
+          aggregate.function.as_deref(),
+          None | Some("min" | "max" | "avg" | "last" | "count")
+        ) {
+          anyhow::bail!(
+            "subscriptions: aggregate function of {} must be \"min\", \"max\", \"avg\", \"last\" or \"count\"",
+            subscription.topic
+          );
+        }
+      }
+    }
+    for rule in &self.rate_limits {
+      if !rule.rate.is_finite() || rule.rate <= 0.0 {
+        anyhow::bail!("rate_limits: rate must be a finite number greater than 0");
+      }
+      if rule.burst.is_some_and(|burst| !burst.is_finite()) {
+        anyhow::bail!("rate_limits: burst must be a finite number");
+      }
+      if !matches!(rule.scope.as_deref(), None | Some("topic" | "device" | "relay")) {
+        anyhow::bail!("rate_limits: scope must be \"topic\", \"device\" or \"relay\"");
+      }
+      if !matches!(rule.action.as_deref(), None | Some("drop" | "sample" | "latest")) {
+        anyhow::bail!("rate_limits: action must be \"drop\", \"sample\" or \"latest\"");
+      }
+    }
     Ok(self)
   }
 }
@@ -97,10 +594,11 @@ mod tests {
   #[test]
   fn test_relay_config_new_with_defaults() {
     let config = ConfigFile {
-      network_token: "network_token".to_string(),
-      authorization_token: "authorization_token".to_string(),
+      network_token: "network_token".into(),
+      authorization_token: "authorization_token".into(),
       tagoio_url: None,
       downlink_port: None,
+      downlink_ttl: None,
       mqtt: Mqtt {
         client_id: None,
         tls_enabled: false,
@@ -112,7 +610,9 @@ mod tests {
         broker_tls_ca: None,
         broker_tls_cert: None,
         broker_tls_key: None,
+        ..Default::default()
       },
+      ..Default::default()
     };
 
     let relay_config = RelayConfig::new_with_defaults(None, config).unwrap();
@@ -132,10 +632,11 @@ mod tests {
   #[test]
   fn test_config_file_with_defaults() {
     let config = ConfigFile {
-      network_token: "network_token".to_string(),
-      authorization_token: "authorization_token".to_string(),
+      network_token: "network_token".into(),
+      authorization_token: "authorization_token".into(),
       tagoio_url: None,
       downlink_port: None,
+      downlink_ttl: None,
       mqtt: Mqtt {
         client_id: None,
         tls_enabled: false,
@@ -147,14 +648,35 @@ mod tests {
         broker_tls_ca: None,
         broker_tls_cert: None,
         broker_tls_key: None,
+        ..Default::default()
       },
+      ..Default::default()
     };
 
     let config_with_defaults = config.with_defaults().unwrap();
 
     assert_eq!(config_with_defaults.tagoio_url.unwrap(), "https://api.tago.io");
     assert_eq!(config_with_defaults.downlink_port.unwrap(), 3000);
+    assert_eq!(config_with_defaults.downlink_ttl.unwrap(), 300);
+    assert_eq!(config_with_defaults.forwarding.max_retries.unwrap(), 5);
+    assert_eq!(config_with_defaults.forwarding.max_concurrency.unwrap(), 50);
+    assert!(config_with_defaults.forwarding.retry_status_codes.is_none());
     assert_eq!(config_with_defaults.mqtt.client_id.unwrap(), "tagoio-relay");
+
+    let forwarding = Forwarding {
+      max_concurrency: Some(0),
+      ..Default::default()
+    };
+    assert!(forwarding.with_defaults().is_err());
+
+    let forwarding = Forwarding {
+      circuit_breaker: CircuitBreakerPolicy {
+        fallback: Some("dorp".to_string()),
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+    assert!(forwarding.with_defaults().is_err());
   }
 
   #[test]
@@ -170,6 +692,7 @@ mod tests {
       broker_tls_ca: None,
       broker_tls_cert: None,
       broker_tls_key: None,
+      ..Default::default()
     };
 
     let mqtt_with_defaults = mqtt.with_defaults().unwrap();
@@ -177,6 +700,26 @@ mod tests {
     assert_eq!(mqtt_with_defaults.client_id.unwrap(), "tagoio-relay");
   }
 
+  #[test]
+  fn test_mqtt_rejects_invalid_deadband() {
+    let with_deadband = |absolute: f64| Mqtt {
+      subscriptions: vec![Subscription {
+        topic: "sensors/#".to_string(),
+        deadband: Some(Deadband {
+          absolute: Some(absolute),
+          ..Default::default()
+        }),
+        ..Default::default()
+      }],
+      ..Default::default()
+    };
+
+    assert!(with_deadband(0.5).with_defaults().is_ok());
+    for invalid in [-1.0, f64::NAN, f64::INFINITY] {
+      assert!(with_deadband(invalid).with_defaults().is_err());
+    }
+  }
+
   // #[test]
   // fn test_is_valid_address() {
   //   let mqtt = MQTT {
@@ -0,0 +1,184 @@
+use std::{
+  fmt,
+  sync::{Arc, RwLock},
+};
+
+const REDACTED: &str = "***";
+
+/**
+ * Query parameters whose values are scrubbed from URLs before they are logged
+ */
+const SENSITIVE_PARAMS: [&str; 3] = ["authorization_token", "token", "password"];
+
+/**
+ * A token or password read from the configuration.
+ * Prints as `***` in Debug/Display/Serialize; use `expose` where the real value is needed.
+ *
+ * The configured value may reference another source instead of holding the secret:
+ * `file:/run/secrets/name` reads a file (e.g. a Docker or Kubernetes secret) and
+ * `env:NAME` reads an environment variable. Referenced secrets are read again by `refresh`.
+ */
+#[derive(Clone, Default)]
+pub struct Secret {
+  reference: Option<String>,
+  value: Arc<RwLock<String>>,
+}
+
+impl Secret {
+  pub fn expose(&self) -> String {
+    self.value.read().unwrap().clone()
+  }
+
+  /**
+   * Read a `file:`/`env:` reference again. Returns true when the secret changed.
+   */
+  pub fn refresh(&self) -> Result<bool, String> {
+    let Some(reference) = &self.reference else {
+      return Ok(false);
+    };
+    let resolved = resolve_reference(reference)?.unwrap_or_default();
+    let mut value = self.value.write().unwrap();
+    if *value == resolved {
+      return Ok(false);
+    }
+    *value = resolved;
+    Ok(true)
+  }
+}
+
+impl PartialEq for Secret {
+  fn eq(&self, other: &Self) -> bool {
+    self.expose() == other.expose()
+  }
+}
+
+impl From<String> for Secret {
+  fn from(value: String) -> Self {
+    Secret {
+      reference: None,
+      value: Arc::new(RwLock::new(value)),
+    }
+  }
+}
+
+impl From<&str> for Secret {
+  fn from(value: &str) -> Self {
+    Secret::from(value.to_string())
+  }
+}
+
+impl fmt::Debug for Secret {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(REDACTED)
+  }
+}
+
+impl fmt::Display for Secret {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(REDACTED)
+  }
+}
+
+impl serde::Serialize for Secret {
+  fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(REDACTED)
+  }
+}
+
+impl<'de> serde::Deserialize<'de> for Secret {
+  fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let configured = String::deserialize(deserializer)?;
+    match resolve_reference(&configured).map_err(serde::de::Error::custom)? {
+      Some(value) => Ok(Secret {
+        reference: Some(configured),
+        value: Arc::new(RwLock::new(value)),
+      }),
+      None => Ok(Secret::from(configured)),
+    }
+  }
+}
+
+/**
+ * Resolve a `file:<path>` or `env:<NAME>` reference. Returns `None` for plain values.
+ * Trailing newlines are removed from files, as most secret files end with one.
+ */
+pub fn resolve_reference(value: &str) -> Result<Option<String>, String> {
+  if let Some(path) = value.strip_prefix("file:") {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("Failed to read secret file {}: {}", path, e))?;
+    return Ok(Some(content.trim_end_matches(['\n', '\r']).to_string()));
+  }
+  if let Some(name) = value.strip_prefix("env:") {
+    let content = std::env::var(name).map_err(|_| format!("Environment variable {} is not set", name))?;
+    return Ok(Some(content));
+  }
+  Ok(None)
+}
+
+/**
+ * Replace the value of token/password query parameters in any URL found in `text`
+ */
+pub fn scrub_url(text: &str) -> String {
+  let mut scrubbed = text.to_string();
+  for param in SENSITIVE_PARAMS {
+    for separator in ['?', '&'] {
+      let needle = format!("{}{}=", separator, param);
+      let mut from = 0;
+      while let Some(found) = scrubbed[from..].find(&needle) {
+        let start = from + found + needle.len();
+        let end = scrubbed[start..]
+          .find(|c: char| matches!(c, '&' | '#' | ')' | '"' | '\'') || c.is_whitespace())
+          .map_or(scrubbed.len(), |end| start + end);
+        scrubbed.replace_range(start..end, REDACTED);
+        from = start + REDACTED.len();
+      }
+    }
+  }
+  scrubbed
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_secret_is_redacted() {
+    let secret = Secret::from("my-token");
+    assert_eq!(secret.expose(), "my-token");
+    assert_eq!(format!("{:?}", secret), "***");
+    assert_eq!(secret.to_string(), "***");
+    assert_eq!(serde_json::to_string(&secret).unwrap(), "\"***\"");
+
+    let secret: Secret = serde_json::from_str("\"my-token\"").unwrap();
+    assert_eq!(secret.expose(), "my-token");
+  }
+
+  #[test]
+  fn test_secret_file_reference() {
+    let path = std::env::temp_dir().join(format!("tagoio-relay-secret-{}", std::process::id()));
+    std::fs::write(&path, "first-token\n").unwrap();
+
+    let secret: Secret = serde_json::from_value(serde_json::json!(format!("file:{}", path.display()))).unwrap();
+    assert_eq!(secret.expose(), "first-token");
+    assert!(!secret.refresh().unwrap());
+
+    std::fs::write(&path, "second-token").unwrap();
+    assert!(secret.refresh().unwrap());
+    assert_eq!(secret.expose(), "second-token");
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(secret.refresh().is_err());
+    assert!(serde_json::from_str::<Secret>("\"env:TAGOIO_RELAY_TEST_UNSET_SECRET\"").is_err());
+  }
+
+  #[test]
+  fn test_scrub_url() {
+    let message =
+      "error sending request for url (https://api.tago.io/integration/network/data?authorization_token=abc-123&x=1)";
+    assert_eq!(
+      scrub_url(message),
+      "error sending request for url (https://api.tago.io/integration/network/data?authorization_token=***&x=1)"
+    );
+    assert_eq!(scrub_url("https://host/?a=1&token=abc"), "https://host/?a=1&token=***");
+    assert_eq!(scrub_url("https://host/info"), "https://host/info");
+  }
+}
@@ -0,0 +1,302 @@
+use crate::{
+  schema::{Aggregate, RelayConfig},
+  services::{
+    payload::{format_time, now_ms},
+    tagoio::{post_aggregation, send_records},
+  },
+};
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::{
+  collections::{BTreeMap, HashMap},
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+/**
+ * How often closed windows are looked for
+ */
+const FLUSH_INTERVAL_MS: u64 = 1000;
+
+/**
+ * Samples of one topic and variable within a window
+ */
+struct Window {
+  start_ms: i64,
+  end_ms: i64,
+  function: String,
+  metadata: Value,
+  min: f64,
+  max: f64,
+  sum: f64,
+  last: f64,
+  count: u64,
+}
+
+/**
+ * Open tumbling windows of a relay, by topic and variable
+ */
+pub struct Aggregator {
+  windows: Mutex<HashMap<(String, String), Window>>,
+}
+
+static AGGREGATORS: Lazy<Mutex<HashMap<String, Arc<Aggregator>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/**
+ * Get the aggregator of a relay, creating it on first use
+ */
+pub fn aggregator(relay_cfg: &RelayConfig) -> Arc<Aggregator> {
+  AGGREGATORS
+    .lock()
+    .unwrap()
+    .entry(relay_cfg.id.clone())
+    .or_insert_with(|| Arc::new(Aggregator::new()))
+    .clone()
+}
+
+/**
+ * Start the task sending the windows closed by time, through the deadband like any other records.
+ * `None` when no subscription of the relay aggregates. The caller stops it with the relay.
+ */
+pub fn spawn_flush(relay_cfg: Arc<RelayConfig>) -> Option<tokio::task::JoinHandle<()>> {
+  let aggregates = relay_cfg
+    .config
+    .mqtt
+    .subscriptions
+    .iter()
+    .any(|subscription| subscription.aggregate.is_some());
+  if !aggregates {
+    return None;
+  }
+
+  let aggregator = aggregator(&relay_cfg);
+  Some(tokio::spawn(async move {
+    loop {
+      tokio::time::sleep(Duration::from_millis(FLUSH_INTERVAL_MS)).await;
+      flush_closed(&relay_cfg, &aggregator, now_ms()).await;
+    }
+  }))
+}
+
+/**
+ * Send the windows that ended before `now_ms`
+ */
+pub async fn flush_closed(relay_cfg: &RelayConfig, aggregator: &Aggregator, now_ms: i64) {
+  for (topic, records) in aggregator.flush(now_ms) {
+    let records = post_aggregation(relay_cfg, &topic, records);
+    if let Err(e) = send_records(relay_cfg, &topic, records).await {
+      log::error!(target: "mqtt", relay_id = relay_cfg.id.as_str(), topic = topic.as_str(); "Failed to forward aggregated data to TagoIO: {}", e);
+    }
+  }
+}
+
+impl Window {
+  fn new(start_ms: i64, aggregate: &Aggregate, metadata: Value) -> Self {
+    Window {
+      start_ms,
+      end_ms: start_ms + aggregate.window_secs as i64 * 1000,
+      function: aggregate.function.clone().unwrap_or_else(|| "avg".to_string()),
+      metadata,
+      min: f64::MAX,
+      max: f64::MIN,
+      sum: 0.0,
+      last: 0.0,
+      count: 0,
+    }
+  }
+
+  fn push(&mut self, value: f64) {
+    self.min = self.min.min(value);
+    self.max = self.max.max(value);
+    self.sum += value;
+    self.last = value;
+    self.count += 1;
+  }
+
+  fn into_record(self, variable: &str) -> Value {
+    let avg = self.sum / self.count as f64;
+    let value = match self.function.as_str() {
+      "min" => json!(self.min),
+      "max" => json!(self.max),
+      "last" => json!(self.last),
+      "count" => json!(self.count),
+      _ => json!(avg),
+    };
+
+    let mut metadata = self.metadata;
+    if let Value::Object(fields) = &mut metadata {
+      fields.insert("window_start".to_string(), json!(format_time(self.start_ms)));
+      fields.insert("window_end".to_string(), json!(format_time(self.end_ms)));
+      fields.insert("min".to_string(), json!(self.min));
+      fields.insert("max".to_string(), json!(self.max));
+      fields.insert("avg".to_string(), json!(avg));
+      fields.insert("last".to_string(), json!(self.last));
+      fields.insert("count".to_string(), json!(self.count));
+    }
+    json!({
+      "variable": variable,
+      "value": value,
+      "time": format_time(self.start_ms),
+      "metadata": metadata,
+    })
+  }
+}
+
+impl Aggregator {
+  pub fn new() -> Self {
+    Aggregator {
+      windows: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /**
+   * Add the numeric records to the window of their receive time and return the records to send now:
+   * non-numeric records, and windows closed by a sample of the next window.
+   */
+  pub fn add(&self, received_at: i64, topic: &str, aggregate: &Aggregate, records: Vec<Value>) -> Vec<Value> {
+    let window_ms = aggregate.window_secs.max(1) as i64 * 1000;
+    let start_ms = received_at - received_at.rem_euclid(window_ms);
+    let mut windows = self.windows.lock().unwrap();
+
+    let mut ready = Vec::new();
+    for record in records {
+      let (Some(variable), Some(value)) = (record["variable"].as_str(), record["value"].as_f64()) else {
+        ready.push(record);
+        continue;
+      };
+
+      let key = (topic.to_string(), variable.to_string());
+      if windows.get(&key).is_some_and(|window| window.start_ms != start_ms) {
+        let closed = windows.remove(&key).unwrap();
+        ready.push(closed.into_record(variable));
+      }
+      windows
+        .entry(key)
+        .or_insert_with(|| Window::new(start_ms, aggregate, record["metadata"].clone()))
+        .push(value);
+    }
+    ready
+  }
+
+  /**
+   * Records of every window that ended before `now_ms`, by topic
+   */
+  pub fn flush(&self, now_ms: i64) -> BTreeMap<String, Vec<Value>> {
+    let mut windows = self.windows.lock().unwrap();
+    let closed: Vec<(String, String)> = windows
+      .iter()
+      .filter(|(_, window)| window.end_ms <= now_ms)
+      .map(|(key, _)| key.clone())
+      .collect();
+
+    let mut records: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for (topic, variable) in closed {
+      if let Some(window) = windows.remove(&(topic.clone(), variable.clone())) {
+        records.entry(topic).or_default().push(window.into_record(&variable));
+      }
+    }
+    records
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn record(variable: &str, value: Value) -> Value {
+    json!({ "variable": variable, "value": value, "metadata": { "topic": "sensors/1" } })
+  }
+
+  #[test]
+  fn test_tumbling_window() {
+    let aggregator = Aggregator::new();
+    let aggregate = Aggregate {
+      window_secs: 10,
+      function: Some("max".to_string()),
+    };
+
+    for (at, value) in [(1_000, 3.0), (4_000, 7.0), (9_000, 5.0)] {
+      let ready = aggregator.add(at, "sensors/1", &aggregate, vec![record("current", json!(value))]);
+      assert!(ready.is_empty());
+    }
+
+    // Non-numeric variables are not aggregated
+    let ready = aggregator.add(9_500, "sensors/1", &aggregate, vec![record("status", json!("ok"))]);
+    assert_eq!(ready.len(), 1);
+
+    assert!(aggregator.flush(9_999).is_empty());
+    let records = &aggregator.flush(10_000)["sensors/1"];
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["variable"], "current");
+    assert_eq!(records[0]["value"], 7.0);
+    assert_eq!(records[0]["metadata"]["topic"], "sensors/1");
+    assert_eq!(records[0]["metadata"]["count"], 3);
+    assert_eq!(records[0]["metadata"]["avg"], 5.0);
+    assert_eq!(records[0]["metadata"]["window_start"], "1970-01-01T00:00:00Z");
+    assert_eq!(records[0]["metadata"]["window_end"], "1970-01-01T00:00:10Z");
+  }
+
+  #[test]
+  fn test_next_window_closes_previous() {
+    let aggregator = Aggregator::new();
+    let aggregate = Aggregate {
+      window_secs: 1,
+      function: None,
+    };
+
+    aggregator.add(100, "sensors/1", &aggregate, vec![record("current", json!(2))]);
+    let ready = aggregator.add(1_100, "sensors/1", &aggregate, vec![record("current", json!(4))]);
+    assert_eq!(ready.len(), 1);
+    assert_eq!(ready[0]["value"], 2.0);
+  }
+
+  #[tokio::test]
+  async fn test_closed_windows_go_through_deadband() {
+    use crate::schema::{ConfigFile, Deadband, Mqtt, Subscription};
+
+    let mut server = mockito::Server::new_async().await;
+    let tagoio = server
+      .mock("POST", "/integration/network/data")
+      .match_query(mockito::Matcher::Any)
+      .with_status(200)
+      .expect(1)
+      .create_async()
+      .await;
+    let relay_cfg = RelayConfig {
+      id: "test_aggregation_deadband".to_string(),
+      config: ConfigFile {
+        network_token: "network_token".into(),
+        authorization_token: "authorization_token".into(),
+        tagoio_url: Some(server.url()),
+        mqtt: Mqtt {
+          subscriptions: vec![Subscription {
+            topic: "sensors/#".to_string(),
+            aggregate: Some(Aggregate {
+              window_secs: 10,
+              function: None,
+            }),
+            deadband: Some(Deadband {
+              absolute: Some(1.0),
+              ..Default::default()
+            }),
+            ..Default::default()
+          }],
+          ..Default::default()
+        },
+        ..Default::default()
+      },
+      profile_id: None,
+      network_id: None,
+      http_client: reqwest::Client::new(),
+    };
+
+    let aggregator = Aggregator::new();
+    let aggregate = relay_cfg.config.mqtt.subscriptions[0].aggregate.clone().unwrap();
+    aggregator.add(1_000, "sensors/1", &aggregate, vec![record("current", json!(5))]);
+    flush_closed(&relay_cfg, &aggregator, 10_000).await;
+    // Same average in the next window, within the deadband
+    aggregator.add(11_000, "sensors/1", &aggregate, vec![record("current", json!(5.5))]);
+    flush_closed(&relay_cfg, &aggregator, 20_000).await;
+    tagoio.assert_async().await;
+  }
+}
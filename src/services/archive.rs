@@ -0,0 +1,421 @@
+use crate::{
+  schema::RelayConfig,
+  services::{
+    aggregation,
+    filters::topic_matches,
+    metrics, payload,
+    sinks::{self, RotatingFile},
+    tagoio::{drain_circuit_breakers, error_chain, replayed_records, send_records},
+  },
+};
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use once_cell::sync::Lazy;
+use rumqttc::Publish;
+use std::{
+  collections::HashMap,
+  fs::File,
+  io::{BufRead, BufReader},
+  path::Path,
+  sync::{
+    mpsc::{self, TrySendError},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
+
+/**
+ * Uplink as written to the archive, one JSON line each
+ */
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct ArchivedUplink {
+  pub received_at: String,
+  pub topic: String,
+  pub qos: u8,
+  pub retain: bool,
+  pub payload: String, // base64 of the raw bytes
+}
+
+impl ArchivedUplink {
+  fn new(publish: &Publish, received_at: i64) -> Self {
+    ArchivedUplink {
+      received_at: payload::format_time(received_at),
+      topic: publish.topic.clone(),
+      qos: publish.qos as u8,
+      retain: publish.retain,
+      payload: BASE64.encode(&publish.payload),
+    }
+  }
+
+  fn received_at_ms(&self) -> anyhow::Result<i64> {
+    Ok(self.received_at.parse::<jiff::Timestamp>()?.as_millisecond())
+  }
+
+  fn publish(&self) -> anyhow::Result<Publish> {
+    let qos = rumqttc::qos(self.qos).map_err(|e| anyhow::anyhow!("{}", e))?;
+    let mut publish = Publish::new(&self.topic, qos, BASE64.decode(&self.payload)?);
+    publish.retain = self.retain;
+    Ok(publish)
+  }
+}
+
+/**
+ * Writes uplinks on a background thread, so a slow disk does not hold up the broker connection.
+ * Uplinks that do not fit in its queue are dropped and counted.
+ */
+pub struct Archive {
+  relay_id: String,
+  sender: mpsc::SyncSender<String>,
+}
+
+static ARCHIVES: Lazy<Mutex<HashMap<String, Arc<Archive>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn archive_file(relay_cfg: &RelayConfig) -> RotatingFile {
+  let archive = &relay_cfg.config.archive;
+  RotatingFile::new(
+    archive.path.as_deref().unwrap_or("archive/uplinks.jsonl"),
+    archive.max_bytes.unwrap_or(100 * 1024 * 1024),
+    archive.max_files.unwrap_or(10),
+  )
+}
+
+/**
+ * Get the archive of a relay, or `None` when archiving is disabled
+ */
+pub fn archive(relay_cfg: &RelayConfig) -> Option<Arc<Archive>> {
+  if !relay_cfg.config.archive.enabled.unwrap_or(false) {
+    return None;
+  }
+  let archive = ARCHIVES
+    .lock()
+    .unwrap()
+    .entry(relay_cfg.id.clone())
+    .or_insert_with(|| {
+      let queue_size = relay_cfg.config.archive.queue_size.unwrap_or(10_000).max(1);
+      let (sender, receiver) = mpsc::sync_channel::<String>(queue_size);
+      let file = archive_file(relay_cfg);
+      let relay_id = relay_cfg.id.clone();
+      std::thread::spawn(move || {
+        while let Ok(line) = receiver.recv() {
+          let mut lines = vec![line];
+          lines.extend(receiver.try_iter());
+          if let Err(e) = file.append(&lines) {
+            log::error!(target: "error", relay_id = relay_id.as_str(), error:% = e; "Failed to archive {} uplink(s): {}", lines.len(), e);
+            metrics::increment_counter("archive_errors_total", &[("relay_id", &relay_id)]);
+          }
+        }
+      });
+      Arc::new(Archive {
+        relay_id: relay_cfg.id.clone(),
+        sender,
+      })
+    })
+    .clone();
+  Some(archive)
+}
+
+impl Archive {
+  pub fn record(&self, publish: &Publish, received_at: i64) {
+    let line = serde_json::to_string(&ArchivedUplink::new(publish, received_at)).unwrap_or_default();
+    if let Err(TrySendError::Full(_)) = self.sender.try_send(line) {
+      log::warn!(target: "error", relay_id = self.relay_id.as_str(), topic = publish.topic.as_str(); "Archive is behind: uplink on topic {} not archived", publish.topic);
+      metrics::increment_counter("archive_dropped_total", &[("relay_id", &self.relay_id)]);
+    }
+  }
+}
+
+/**
+ * Which archived uplinks to send again, and how fast
+ */
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+  pub from: Option<jiff::Timestamp>, // Inclusive
+  pub to: Option<jiff::Timestamp>,   // Exclusive
+  pub topics: Vec<String>,           // Topic filters. Default is every topic
+  pub rate: f64,                     // Messages per second
+  pub dry_run: bool,
+}
+
+impl ReplayOptions {
+  fn matches(&self, uplink: &ArchivedUplink, received_at: i64) -> bool {
+    self.from.is_none_or(|from| received_at >= from.as_millisecond())
+      && self.to.is_none_or(|to| received_at < to.as_millisecond())
+      && (self.topics.is_empty() || self.topics.iter().any(|filter| topic_matches(&uplink.topic, filter)))
+  }
+}
+
+/**
+ * Archived uplinks matching the options, oldest first, with their receive time.
+ * The files are read as the uplinks are taken.
+ */
+fn matching_uplinks<'a>(
+  file: &RotatingFile,
+  options: &'a ReplayOptions,
+) -> impl Iterator<Item = anyhow::Result<(ArchivedUplink, i64)>> + 'a {
+  file.files().into_iter().rev().flat_map(move |path| {
+    let (reader, error) = match File::open(&path) {
+      Ok(opened) => (Some(BufReader::new(opened)), None),
+      Err(e) => (
+        None,
+        Some(Err(e).with_context(|| format!("Failed to open {}", path.display()))),
+      ),
+    };
+    let lines = reader.into_iter().flat_map(|reader| reader.lines().enumerate());
+    error
+      .into_iter()
+      .chain(lines.filter_map(move |(index, line)| matching_line(options, &path, index, line)))
+  })
+}
+
+fn matching_line(
+  options: &ReplayOptions,
+  path: &Path,
+  index: usize,
+  line: std::io::Result<String>,
+) -> Option<anyhow::Result<(ArchivedUplink, i64)>> {
+  let line = match line {
+    Ok(line) => line,
+    Err(e) => return Some(Err(e).with_context(|| format!("Failed to read {}", path.display()))),
+  };
+  let parsed = serde_json::from_str::<ArchivedUplink>(&line)
+    .map_err(anyhow::Error::from)
+    .and_then(|uplink| Ok((uplink.received_at_ms()?, uplink)));
+  match parsed {
+    Ok((received_at, uplink)) if options.matches(&uplink, received_at) => Some(Ok((uplink, received_at))),
+    Ok(_) => None,
+    Err(e) => {
+      log::warn!(target: "info", "Skipping line {} of {}: {}", index + 1, path.display(), e);
+      None
+    }
+  }
+}
+
+/**
+ * Send archived uplinks through the current filters, decoders, scripts and routes again, with
+ * their original receive time. Aggregation windows close by that time too, and the ones still open
+ * are sent at the end, as are the messages held by the circuit breakers and the queues of the sinks.
+ * A dry run logs the records before aggregation instead of sending them.
+ * Returns the number of uplinks replayed.
+ */
+pub async fn replay(relay_cfg: &RelayConfig, options: &ReplayOptions) -> anyhow::Result<usize> {
+  log::info!(target: "info", relay_id = relay_cfg.id.as_str(); "Replaying archived uplinks");
+
+  let aggregator = aggregation::aggregator(relay_cfg);
+  let period = Duration::try_from_secs_f64(1.0 / options.rate).unwrap_or(Duration::MAX);
+  let mut ticker = tokio::time::interval(period.max(Duration::from_nanos(1)));
+  let mut replayed = 0;
+  for uplink in matching_uplinks(&archive_file(relay_cfg), options) {
+    let (uplink, received_at) = uplink?;
+    let publish = match uplink.publish() {
+      Ok(publish) => publish,
+      Err(e) => {
+        log::warn!(target: "info", relay_id = relay_cfg.id.as_str(), topic = uplink.topic.as_str(); "Skipping archived uplink on topic {}: {}", uplink.topic, e);
+        continue;
+      }
+    };
+
+    if options.dry_run {
+      let Some(records) = replayed_records(relay_cfg, &publish, received_at, false).await else {
+        continue;
+      };
+      log::info!(target: "info", relay_id = relay_cfg.id.as_str(), topic = publish.topic.as_str(); "[Dry run] {} {}: {}", uplink.received_at, publish.topic, serde_json::Value::Array(records));
+    } else {
+      // Windows that ended before this uplink was received are sent first, as they were live
+      aggregation::flush_closed(relay_cfg, &aggregator, received_at).await;
+      let Some(records) = replayed_records(relay_cfg, &publish, received_at, true).await else {
+        continue;
+      };
+      ticker.tick().await;
+      if let Err(e) = send_records(relay_cfg, &publish.topic, records).await {
+        let error = error_chain(e.as_ref());
+        log::error!(target: "error", relay_id = relay_cfg.id.as_str(), topic = publish.topic.as_str(), error = error.as_str(); "Failed to replay uplink on topic {}: {}", publish.topic, error);
+        continue;
+      }
+    }
+    replayed += 1;
+  }
+
+  if !options.dry_run {
+    aggregation::flush_closed(relay_cfg, &aggregator, i64::MAX).await;
+    sinks::close(relay_cfg).await;
+    let unsent = drain_circuit_breakers(relay_cfg).await;
+    if unsent > 0 {
+      anyhow::bail!(
+        "{} replayed message(s) held by an open circuit breaker could not be sent",
+        unsent
+      );
+    }
+  }
+  Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::schema::{Archive as ArchiveConfig, ConfigFile, Mqtt};
+  use mockito::Matcher;
+  use rumqttc::QoS;
+
+  fn relay_config(path: &str, tagoio_url: String) -> RelayConfig {
+    RelayConfig {
+      id: "archive_test_id".to_string(),
+      config: ConfigFile {
+        network_token: "network_token".into(),
+        authorization_token: "authorization_token".into(),
+        tagoio_url: Some(tagoio_url),
+        mqtt: Mqtt {
+          address: "localhost".to_string(),
+          port: 1883,
+          ..Default::default()
+        },
+        archive: ArchiveConfig {
+          enabled: Some(true),
+          path: Some(path.to_string()),
+          ..Default::default()
+        },
+        ..Default::default()
+      },
+      profile_id: None,
+      network_id: None,
+      http_client: reqwest::Client::new(),
+    }
+  }
+
+  fn every_uplink() -> ReplayOptions {
+    ReplayOptions {
+      from: None,
+      to: None,
+      topics: Vec::new(),
+      rate: 1000.0,
+      dry_run: false,
+    }
+  }
+
+  #[test]
+  fn test_archived_uplink_round_trip() {
+    let mut publish = Publish::new("sensors/1", QoS::AtLeastOnce, vec![0x01, 0xFF]);
+    publish.retain = true;
+    let uplink = ArchivedUplink::new(&publish, 1_700_000_000_123);
+    assert_eq!(uplink.received_at, "2023-11-14T22:13:20.123Z");
+    assert_eq!(uplink.payload, "Af8=");
+    assert_eq!(uplink.received_at_ms().unwrap(), 1_700_000_000_123);
+    assert_eq!(uplink.publish().unwrap(), publish);
+  }
+
+  #[tokio::test]
+  async fn test_replay_time_range_and_topics() {
+    let mut server = mockito::Server::new_async().await;
+    let dir = std::env::temp_dir().join(format!("tagoio-relay-replay-{}", std::process::id()));
+    let path = dir.join("uplinks.jsonl");
+    let relay_cfg = relay_config(path.to_str().unwrap(), server.url());
+
+    let file = archive_file(&relay_cfg);
+    let lines: Vec<String> = [
+      ("sensors/1", "10", 1_000),
+      ("sensors/2", "20", 2_000),
+      ("alarms/1", "on", 3_000),
+      ("sensors/1", "40", 4_000),
+    ]
+    .iter()
+    .map(|(topic, payload, received_at)| {
+      let publish = Publish::new(*topic, QoS::AtMostOnce, *payload);
+      serde_json::to_string(&ArchivedUplink::new(&publish, *received_at)).unwrap()
+    })
+    .collect();
+    file.append(&lines).unwrap();
+
+    let options = ReplayOptions {
+      from: Some(jiff::Timestamp::from_millisecond(2_000).unwrap()),
+      to: Some(jiff::Timestamp::from_millisecond(4_000).unwrap()),
+      ..every_uplink()
+    };
+    let topics: Vec<String> = matching_uplinks(&file, &options)
+      .map(|uplink| uplink.unwrap().0.topic)
+      .collect();
+    assert_eq!(topics, vec!["sensors/2", "alarms/1"]);
+
+    let tagoio = server
+      .mock("POST", "/integration/network/data")
+      .match_query(Matcher::Any)
+      .match_body(Matcher::Regex(r#""time":"1970-01-01T00:00:04Z""#.to_string()))
+      .with_status(200)
+      .create_async()
+      .await;
+    let options = ReplayOptions {
+      topics: vec!["sensors/1".to_string()],
+      from: Some(jiff::Timestamp::from_millisecond(3_000).unwrap()),
+      ..every_uplink()
+    };
+    assert_eq!(replay(&relay_cfg, &options).await.unwrap(), 1);
+    tagoio.assert_async().await;
+
+    let dry_run = ReplayOptions {
+      dry_run: true,
+      ..every_uplink()
+    };
+    assert_eq!(replay(&relay_cfg, &dry_run).await.unwrap(), 4);
+    tagoio.assert_async().await;
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_replay_aggregates_by_receive_time() {
+    use crate::schema::{Aggregate, Subscription};
+
+    let mut server = mockito::Server::new_async().await;
+    let dir = std::env::temp_dir().join(format!("tagoio-relay-replay-aggregate-{}", std::process::id()));
+    let path = dir.join("uplinks.jsonl");
+    let mut relay_cfg = relay_config(path.to_str().unwrap(), server.url());
+    relay_cfg.id = "archive_aggregate_test_id".to_string();
+    relay_cfg.config.mqtt.subscriptions = vec![Subscription {
+      topic: "sensors/#".to_string(),
+      payload_format: Some("json".to_string()),
+      aggregate: Some(Aggregate {
+        window_secs: 10,
+        function: None,
+      }),
+      ..Default::default()
+    }];
+
+    let lines: Vec<String> = [
+      (r#"{"current":5}"#, 1_000),
+      (r#"{"current":7}"#, 4_000),
+      (r#"{"current":9}"#, 12_000),
+    ]
+    .iter()
+    .map(|(payload, received_at)| {
+      let publish = Publish::new("sensors/1", QoS::AtMostOnce, *payload);
+      serde_json::to_string(&ArchivedUplink::new(&publish, *received_at)).unwrap()
+    })
+    .collect();
+    archive_file(&relay_cfg).append(&lines).unwrap();
+
+    // Dry runs leave the windows alone
+    let dry_run = ReplayOptions {
+      dry_run: true,
+      ..every_uplink()
+    };
+    assert_eq!(replay(&relay_cfg, &dry_run).await.unwrap(), 3);
+    assert!(aggregation::aggregator(&relay_cfg).flush(i64::MAX).is_empty());
+
+    let mut windows = Vec::new();
+    for window_start in ["1970-01-01T00:00:00Z", "1970-01-01T00:00:10Z"] {
+      let window = server
+        .mock("POST", "/integration/network/data")
+        .match_query(Matcher::Any)
+        .match_body(Matcher::Regex(format!(r#""window_start":"{}""#, window_start)))
+        .with_status(200)
+        .expect(1)
+        .create_async()
+        .await;
+      windows.push(window);
+    }
+
+    // The last window is still open when the archive ends, and is sent too
+    assert_eq!(replay(&relay_cfg, &every_uplink()).await.unwrap(), 3);
+    for window in windows {
+      window.assert_async().await;
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
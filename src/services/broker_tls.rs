@@ -0,0 +1,497 @@
+use crate::schema::{resolve_reference, Mqtt, Secret};
+use anyhow::{Context, Result};
+use openssl::{pkcs12::Pkcs12, pkey::PKey};
+use rumqttc::tokio_rustls::rustls::{
+  self,
+  client::{
+    danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    WebPkiServerVerifier,
+  },
+  crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+  pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+  ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use std::sync::Arc;
+
+type ClientAuth = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+/**
+ * Certificates and keys loaded from the `broker_tls_*` settings
+ */
+#[derive(Default)]
+pub struct BrokerCredentials {
+  pub ca: Option<Vec<CertificateDer<'static>>>,
+  pub client_auth: Option<ClientAuth>,
+}
+
+/**
+ * Read a PEM setting: either a file path or the PEM content itself (e.g. from an environment variable)
+ */
+fn read_pem_setting(name: &str, value: &str) -> Result<Vec<u8>> {
+  // `env:NAME` holds inline PEM in an environment variable, `file:` is an explicit path
+  let value = match value.strip_prefix("file:") {
+    Some(path) => path.to_string(),
+    None if value.starts_with("env:") => resolve_reference(value)
+      .map_err(anyhow::Error::msg)
+      .with_context(|| format!("Failed to read {}", name))?
+      .unwrap_or_default(),
+    None => value.to_string(),
+  };
+  let value = value.as_str();
+  let trimmed = value.trim_start();
+  if trimmed.starts_with("-----BEGIN") {
+    // Environment variables often carry escaped newlines
+    let pem = if trimmed.contains('\n') {
+      trimmed.to_string()
+    } else {
+      trimmed.replace("\\n", "\n")
+    };
+    return Ok(pem.into_bytes());
+  }
+
+  std::fs::read(value).with_context(|| format!("Failed to read {} file {}", name, value))
+}
+
+fn parse_certificates(name: &str, pem: &[u8]) -> Result<Vec<CertificateDer<'static>>> {
+  let certs = CertificateDer::pem_slice_iter(pem)
+    .collect::<Result<Vec<_>, _>>()
+    .with_context(|| format!("Invalid certificate in {}", name))?;
+  if certs.is_empty() {
+    anyhow::bail!("No certificate found in {}", name);
+  }
+  Ok(certs)
+}
+
+fn parse_private_key(pem: &[u8], password: Option<&str>) -> Result<PrivateKeyDer<'static>> {
+  let encrypted = String::from_utf8_lossy(pem).contains("ENCRYPTED");
+  if !encrypted {
+    return PrivateKeyDer::from_pem_slice(pem).context("Invalid broker_tls_key");
+  }
+
+  let password = password.context("broker_tls_key is encrypted but broker_tls_key_password is not set")?;
+  let key = PKey::private_key_from_pem_passphrase(pem, password.as_bytes())
+    .context("Failed to decrypt broker_tls_key: wrong broker_tls_key_password or unsupported key format")?;
+  Ok(PrivateKeyDer::Pkcs8(key.private_key_to_pkcs8()?.into()))
+}
+
+fn parse_pkcs12(path: &str, password: &str) -> Result<ClientAuth> {
+  let der = std::fs::read(path).with_context(|| format!("Failed to read broker_tls_pkcs12 file {}", path))?;
+  let parsed = Pkcs12::from_der(&der)
+    .and_then(|pkcs12| pkcs12.parse2(password))
+    .with_context(|| {
+      format!(
+        "Failed to open broker_tls_pkcs12 {}: wrong password or invalid bundle",
+        path
+      )
+    })?;
+
+  let cert = parsed.cert.context("broker_tls_pkcs12 has no certificate")?;
+  let key = parsed.pkey.context("broker_tls_pkcs12 has no private key")?;
+
+  // Leaf certificate first, followed by the intermediates shipped in the bundle
+  let mut chain = vec![CertificateDer::from(cert.to_der()?)];
+  for intermediate in parsed.ca.into_iter().flatten() {
+    chain.push(CertificateDer::from(intermediate.to_der()?));
+  }
+
+  Ok((chain, PrivateKeyDer::Pkcs8(key.private_key_to_pkcs8()?.into())))
+}
+
+/**
+ * Load the broker CA and client identity. Any configured file that cannot be read or parsed is an error,
+ * instead of silently connecting with weaker settings.
+ */
+pub fn load_broker_credentials(mqtt: &Mqtt) -> Result<BrokerCredentials> {
+  let ca = match &mqtt.broker_tls_ca {
+    Some(ca) => Some(parse_certificates(
+      "broker_tls_ca",
+      &read_pem_setting("broker_tls_ca", ca)?,
+    )?),
+    None => None,
+  };
+
+  let client_auth = match (&mqtt.broker_tls_pkcs12, &mqtt.broker_tls_cert, &mqtt.broker_tls_key) {
+    (Some(_), Some(_), _) | (Some(_), _, Some(_)) => {
+      anyhow::bail!("broker_tls_pkcs12 cannot be combined with broker_tls_cert/broker_tls_key")
+    }
+    (Some(pkcs12), None, None) => {
+      let password = mqtt
+        .broker_tls_pkcs12_password
+        .as_ref()
+        .map(Secret::expose)
+        .unwrap_or_default();
+      Some(parse_pkcs12(pkcs12, &password)?)
+    }
+    (None, Some(cert), Some(key)) => {
+      let chain = parse_certificates("broker_tls_cert", &read_pem_setting("broker_tls_cert", cert)?)?;
+      let key = parse_private_key(
+        &read_pem_setting("broker_tls_key", key)?,
+        mqtt.broker_tls_key_password.as_ref().map(Secret::expose).as_deref(),
+      )?;
+      Some((chain, key))
+    }
+    (None, Some(_), None) => anyhow::bail!("broker_tls_cert is set but broker_tls_key is missing"),
+    (None, None, Some(_)) => anyhow::bail!("broker_tls_key is set but broker_tls_cert is missing"),
+    (None, None, None) => None,
+  };
+
+  Ok(BrokerCredentials { ca, client_auth })
+}
+
+/**
+ * Name verified in the broker certificate, when it is not the broker address. The TLS ClientHello still
+ * carries the address as SNI (none for an IP address): rumqttc connects and names the server from the same address.
+ */
+pub fn tls_server_name(mqtt: &Mqtt) -> Result<Option<ServerName<'static>>> {
+  mqtt
+    .tls_server_name
+    .as_ref()
+    .map(|name| ServerName::try_from(name.clone()).with_context(|| format!("Invalid tls_server_name \"{}\"", name)))
+    .transpose()
+}
+
+/**
+ * Build the rustls configuration used to connect to the MQTT broker.
+ * Without a CA the platform root certificates are used.
+ */
+pub fn build_client_config(mqtt: &Mqtt, credentials: BrokerCredentials) -> Result<ClientConfig> {
+  let versions: &[&'static rustls::SupportedProtocolVersion] = match mqtt.tls_min_version.as_deref() {
+    None | Some("1.2") => rustls::DEFAULT_VERSIONS,
+    Some("1.3") => &[&rustls::version::TLS13],
+    Some(other) => anyhow::bail!("Unsupported tls_min_version \"{}\", expected \"1.2\" or \"1.3\"", other),
+  };
+  let builder = ClientConfig::builder_with_protocol_versions(versions);
+  let provider = builder.crypto_provider().clone();
+
+  let mut root_cert_store = RootCertStore::empty();
+  if let Some(ca) = credentials.ca {
+    for cert in ca {
+      root_cert_store.add(cert).context("Invalid broker CA certificate")?;
+    }
+  } else {
+    root_cert_store
+      .add_parsable_certificates(rustls_native_certs::load_native_certs().expect("could not load platform certs"));
+  }
+
+  let builder = if mqtt.tls_insecure_skip_verify.unwrap_or(false) {
+    builder
+      .dangerous()
+      .with_custom_certificate_verifier(Arc::new(SkipServerVerification { provider }))
+  } else if let Some(server_name) = tls_server_name(mqtt)? {
+    let inner = WebPkiServerVerifier::builder_with_provider(Arc::new(root_cert_store), provider)
+      .build()
+      .context("Invalid broker CA certificate")?;
+    builder
+      .dangerous()
+      .with_custom_certificate_verifier(Arc::new(ServerNameVerification { server_name, inner }))
+  } else {
+    builder.with_root_certificates(Arc::new(root_cert_store))
+  };
+
+  let mut client_config = if let Some((chain, key)) = credentials.client_auth {
+    builder
+      .with_client_auth_cert(chain, key)
+      .context("Broker client certificate does not match its key")?
+  } else {
+    builder.with_no_client_auth()
+  };
+
+  if let Some(alpn) = &mqtt.tls_alpn {
+    client_config.alpn_protocols = alpn.iter().map(|protocol| protocol.as_bytes().to_vec()).collect();
+  }
+
+  Ok(client_config)
+}
+
+/**
+ * Verifies the broker certificate against `tls_server_name` instead of the broker address
+ */
+#[derive(Debug)]
+struct ServerNameVerification {
+  server_name: ServerName<'static>,
+  inner: Arc<WebPkiServerVerifier>,
+}
+
+impl ServerCertVerifier for ServerNameVerification {
+  fn verify_server_cert(
+    &self,
+    end_entity: &CertificateDer<'_>,
+    intermediates: &[CertificateDer<'_>],
+    _server_name: &ServerName<'_>,
+    ocsp_response: &[u8],
+    now: UnixTime,
+  ) -> Result<ServerCertVerified, rustls::Error> {
+    self
+      .inner
+      .verify_server_cert(end_entity, intermediates, &self.server_name, ocsp_response, now)
+  }
+
+  fn verify_tls12_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    self.inner.verify_tls12_signature(message, cert, dss)
+  }
+
+  fn verify_tls13_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    self.inner.verify_tls13_signature(message, cert, dss)
+  }
+
+  fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+    self.inner.supported_verify_schemes()
+  }
+}
+
+/**
+ * Accepts any broker certificate. Handshake signatures are still checked.
+ */
+#[derive(Debug)]
+struct SkipServerVerification {
+  provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for SkipServerVerification {
+  fn verify_server_cert(
+    &self,
+    _end_entity: &CertificateDer<'_>,
+    _intermediates: &[CertificateDer<'_>],
+    _server_name: &ServerName<'_>,
+    _ocsp_response: &[u8],
+    _now: UnixTime,
+  ) -> Result<ServerCertVerified, rustls::Error> {
+    Ok(ServerCertVerified::assertion())
+  }
+
+  fn verify_tls12_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+  }
+
+  fn verify_tls13_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+  }
+
+  fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+    self.provider.signature_verification_algorithms.supported_schemes()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::services::certificates::self_signed;
+  use openssl::{
+    hash::MessageDigest,
+    stack::Stack,
+    symm::Cipher,
+    x509::{extension::SubjectAlternativeName, X509},
+  };
+  use rumqttc::{
+    tokio_rustls::{rustls::ServerConfig, TlsAcceptor},
+    AsyncClient, MqttOptions, Transport,
+  };
+  use std::time::Duration;
+  use tokio::net::TcpListener;
+
+  fn temp_file(name: &str, contents: &[u8]) -> String {
+    let path = std::env::temp_dir().join(format!("tagoio-relay-{}-{}", std::process::id(), name));
+    std::fs::write(&path, contents).unwrap();
+    path.display().to_string()
+  }
+
+  #[test]
+  fn test_build_client_config_options() {
+    let mqtt = Mqtt {
+      address: "10.0.0.5".to_string(),
+      tls_insecure_skip_verify: Some(true),
+      tls_alpn: Some(vec!["mqtt".to_string()]),
+      tls_min_version: Some("1.3".to_string()),
+      ..Default::default()
+    };
+
+    let client_config = build_client_config(&mqtt, BrokerCredentials::default()).unwrap();
+    assert_eq!(client_config.alpn_protocols, vec![b"mqtt".to_vec()]);
+  }
+
+  #[test]
+  fn test_build_client_config_rejects_invalid_options() {
+    let mqtt = Mqtt {
+      tls_min_version: Some("1.0".to_string()),
+      ..Default::default()
+    };
+    assert!(build_client_config(&mqtt, BrokerCredentials::default()).is_err());
+
+    let mqtt = Mqtt {
+      tls_server_name: Some("not a valid name!".to_string()),
+      ..Default::default()
+    };
+    assert!(tls_server_name(&mqtt).is_err());
+  }
+
+  #[test]
+  fn test_load_inline_pem_and_encrypted_key() {
+    let (cert, key) = self_signed(0, 30);
+    let cert_pem = String::from_utf8(cert.to_pem().unwrap()).unwrap();
+    let key_pem = key
+      .private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), b"secret")
+      .unwrap();
+
+    let key_path = temp_file("key.pem", &key_pem);
+
+    let mqtt = Mqtt {
+      // Inline PEM with escaped newlines, as set through an environment variable
+      broker_tls_ca: Some(cert_pem.replace('\n', "\\n")),
+      broker_tls_cert: Some(format!("{}{}", cert_pem, cert_pem)),
+      broker_tls_key: Some(key_path.clone()),
+      broker_tls_key_password: Some("secret".into()),
+      ..Default::default()
+    };
+
+    let credentials = load_broker_credentials(&mqtt).unwrap();
+    assert_eq!(credentials.ca.as_ref().unwrap().len(), 1);
+    assert_eq!(credentials.client_auth.as_ref().unwrap().0.len(), 2);
+    assert!(build_client_config(&mqtt, credentials).is_ok());
+
+    let mqtt = Mqtt {
+      broker_tls_key_password: Some("wrong".into()),
+      ..mqtt
+    };
+    assert!(load_broker_credentials(&mqtt).is_err());
+    std::fs::remove_file(key_path).unwrap();
+  }
+
+  #[test]
+  fn test_load_pkcs12() {
+    let (cert, key) = self_signed(0, 30);
+    let (intermediate, _) = self_signed(0, 30);
+    let mut ca = Stack::new().unwrap();
+    ca.push(intermediate).unwrap();
+    let pkcs12 = Pkcs12::builder()
+      .name("relay")
+      .pkey(&key)
+      .cert(&cert)
+      .ca(ca)
+      .build2("secret")
+      .unwrap();
+
+    let pkcs12_path = temp_file("client.p12", &pkcs12.to_der().unwrap());
+
+    let mqtt = Mqtt {
+      broker_tls_pkcs12: Some(pkcs12_path.clone()),
+      broker_tls_pkcs12_password: Some("secret".into()),
+      ..Default::default()
+    };
+    let credentials = load_broker_credentials(&mqtt).unwrap();
+    assert_eq!(credentials.client_auth.unwrap().0.len(), 2);
+    std::fs::remove_file(pkcs12_path).unwrap();
+  }
+
+  #[test]
+  fn test_load_broker_credentials_errors() {
+    let missing_file = Mqtt {
+      broker_tls_ca: Some("/nonexistent/ca.crt".to_string()),
+      ..Default::default()
+    };
+    assert!(load_broker_credentials(&missing_file).is_err());
+
+    let key_without_cert = Mqtt {
+      broker_tls_key: Some("/nonexistent/client.key".to_string()),
+      ..Default::default()
+    };
+    assert!(load_broker_credentials(&key_without_cert).is_err());
+  }
+
+  /**
+   * Certificate for `name`, signed with the key of `self_signed`, in PEM
+   */
+  fn certificate_for(name: &str) -> (String, PrivateKeyDer<'static>) {
+    let (cert, key) = self_signed(0, 30);
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(cert.subject_name()).unwrap();
+    builder.set_issuer_name(cert.subject_name()).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(cert.not_before()).unwrap();
+    builder.set_not_after(cert.not_after()).unwrap();
+    let san = SubjectAlternativeName::new()
+      .dns(name)
+      .build(&builder.x509v3_context(None, None))
+      .unwrap();
+    builder.append_extension(san).unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+    let pem = String::from_utf8(builder.build().to_pem().unwrap()).unwrap();
+    let key = PrivateKeyDer::Pkcs8(key.private_key_to_pkcs8().unwrap().into());
+    (pem, key)
+  }
+
+  /**
+   * Connect to a local TLS broker presenting a certificate for broker.example.com.
+   * Returns the SNI the broker received, or `None` when the handshake failed.
+   */
+  async fn handshake(address: &str, server_name: Option<&str>) -> Option<Option<String>> {
+    let (pem, key) = certificate_for("broker.example.com");
+    let server_config = ServerConfig::builder()
+      .with_no_client_auth()
+      .with_single_cert(parse_certificates("cert", pem.as_bytes()).unwrap(), key)
+      .unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let mqtt = Mqtt {
+      address: address.to_string(),
+      port,
+      tls_enabled: true,
+      tls_server_name: server_name.map(str::to_string),
+      broker_tls_ca: Some(pem),
+      ..Default::default()
+    };
+    let client_config = build_client_config(&mqtt, load_broker_credentials(&mqtt).unwrap()).unwrap();
+    let mut options = MqttOptions::new("test_sni", address, port);
+    options.set_transport(Transport::tls_with_config(client_config.into()));
+    let (_client, mut eventloop) = AsyncClient::new(options, 10);
+    tokio::spawn(async move {
+      let _ = eventloop.poll().await;
+    });
+
+    let (tcp, _) = listener.accept().await.unwrap();
+    let accepted = tokio::time::timeout(
+      Duration::from_secs(5),
+      TlsAcceptor::from(Arc::new(server_config)).accept(tcp),
+    )
+    .await
+    .unwrap();
+    accepted
+      .ok()
+      .map(|tls| tls.get_ref().1.server_name().map(str::to_string))
+  }
+
+  #[tokio::test]
+  async fn test_tls_server_name_is_verified_and_sni_is_the_address() {
+    // The certificate is verified against tls_server_name, while the SNI stays the broker address
+    assert_eq!(
+      handshake("localhost", Some("broker.example.com")).await,
+      Some(Some("localhost".to_string()))
+    );
+    // An IP address is never sent as SNI
+    assert_eq!(handshake("127.0.0.1", Some("broker.example.com")).await, Some(None));
+    // Without it, the certificate has to name the address
+    assert_eq!(handshake("localhost", None).await, None);
+  }
+}
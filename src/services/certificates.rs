@@ -0,0 +1,222 @@
+use crate::services::metrics;
+use once_cell::sync::Lazy;
+use openssl::{asn1::Asn1Time, x509::X509Ref};
+use std::{
+  collections::BTreeMap,
+  sync::Mutex,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+pub const DEFAULT_WARN_DAYS: [i64; 3] = [30, 7, 1];
+
+struct TrackedCertificate {
+  subject: String,
+  not_after: String,
+  expires_at: i64,
+  days_left: i64,
+  /// Lowest threshold already reported, so each warning is logged once
+  warned_at: Option<i64>,
+}
+
+static CERTIFICATES: Lazy<Mutex<BTreeMap<String, TrackedCertificate>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/**
+ * Unix timestamp of a certificate's notAfter
+ */
+fn unix_time(time: &openssl::asn1::Asn1TimeRef) -> Result<i64, openssl::error::ErrorStack> {
+  let diff = Asn1Time::from_unix(0)?.diff(time)?;
+  Ok(diff.days as i64 * 86_400 + diff.secs as i64)
+}
+
+/**
+ * Whole days left until `expires_at`, negative once expired
+ */
+fn days_left(expires_at: i64) -> i64 {
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs() as i64)
+    .unwrap_or_default();
+  (expires_at - now).div_euclid(86_400)
+}
+
+/**
+ * Record a loaded certificate under a label such as `api/server` or `<relay-id>/broker_ca`
+ */
+pub fn track(source: &str, cert: &X509Ref) {
+  let expires_at = match unix_time(cert.not_after()) {
+    Ok(expires_at) => expires_at,
+    Err(e) => {
+      log::warn!(target: "security", "Could not read the expiry date of certificate {}: {}", source, e);
+      return;
+    }
+  };
+
+  let subject = cert
+    .subject_name()
+    .entries()
+    .map(|entry| {
+      let value = entry.data().as_utf8().map(|v| v.to_string()).unwrap_or_default();
+      format!("{}={}", entry.object().nid().short_name().unwrap_or("?"), value)
+    })
+    .collect::<Vec<_>>()
+    .join(", ");
+
+  let days_left = days_left(expires_at);
+  metrics::set_gauge("certificate_expiry_days", &[("certificate", source)], days_left as f64);
+
+  let mut certificates = CERTIFICATES.lock().unwrap();
+  let warned_at = certificates.get(source).and_then(|tracked| tracked.warned_at);
+  certificates.insert(
+    source.to_string(),
+    TrackedCertificate {
+      subject,
+      not_after: cert.not_after().to_string(),
+      expires_at,
+      days_left,
+      warned_at,
+    },
+  );
+}
+
+/**
+ * Record a DER encoded certificate (as loaded by rustls)
+ */
+pub fn track_der(source: &str, der: &[u8]) {
+  match openssl::x509::X509::from_der(der) {
+    Ok(cert) => track(source, &cert),
+    Err(e) => log::warn!(target: "security", "Could not parse certificate {}: {}", source, e),
+  }
+}
+
+/**
+ * Refresh days-to-expiry and log a warning each time a certificate crosses one of the thresholds
+ */
+pub fn check_expiry(warn_days: &[i64]) {
+  let mut certificates = CERTIFICATES.lock().unwrap();
+  for (source, tracked) in certificates.iter_mut() {
+    tracked.days_left = days_left(tracked.expires_at);
+    metrics::set_gauge(
+      "certificate_expiry_days",
+      &[("certificate", source)],
+      tracked.days_left as f64,
+    );
+
+    if tracked.days_left < 0 {
+      if tracked.warned_at != Some(-1) {
+        log::error!(target: "security", "Certificate {} ({}) EXPIRED on {}", source, tracked.subject, tracked.not_after);
+        tracked.warned_at = Some(-1);
+      }
+      continue;
+    }
+
+    let crossed = warn_days
+      .iter()
+      .copied()
+      .filter(|threshold| tracked.days_left <= *threshold)
+      .min();
+    if let Some(threshold) = crossed {
+      if tracked.warned_at.is_none_or(|warned_at| threshold < warned_at) {
+        log::warn!(target: "security", "Certificate {} ({}) expires in {} day(s), on {}", source, tracked.subject, tracked.days_left, tracked.not_after);
+        tracked.warned_at = Some(threshold);
+      }
+    }
+  }
+}
+
+/**
+ * Labels of every tracked certificate that is already expired
+ */
+pub fn expired() -> Vec<String> {
+  CERTIFICATES
+    .lock()
+    .unwrap()
+    .iter()
+    .filter(|(_, tracked)| tracked.days_left < 0)
+    .map(|(source, tracked)| format!("{} ({}, expired on {})", source, tracked.subject, tracked.not_after))
+    .collect()
+}
+
+/**
+ * Certificates and their days-to-expiry, reported on `/status`
+ */
+pub fn status() -> serde_json::Value {
+  let certificates = CERTIFICATES.lock().unwrap();
+  let status: serde_json::Map<String, serde_json::Value> = certificates
+    .iter()
+    .map(|(source, tracked)| {
+      (
+        source.clone(),
+        serde_json::json!({
+          "subject": tracked.subject,
+          "not_after": tracked.not_after,
+          "days_left": tracked.days_left,
+        }),
+      )
+    })
+    .collect();
+  serde_json::Value::Object(status)
+}
+
+/**
+ * Self-signed test certificate and its key, valid from `not_before` to one hour past `not_after` days from now
+ */
+#[cfg(test)]
+pub(crate) fn self_signed(
+  not_before: i64,
+  not_after: i64,
+) -> (openssl::x509::X509, openssl::pkey::PKey<openssl::pkey::Private>) {
+  use openssl::{
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::PKey,
+    x509::{X509NameBuilder, X509},
+  };
+
+  let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+  let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+  let mut name = X509NameBuilder::new().unwrap();
+  name.append_entry_by_text("CN", "relay-test").unwrap();
+  let name = name.build();
+
+  let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+  let mut builder = X509::builder().unwrap();
+  builder.set_version(2).unwrap();
+  builder.set_subject_name(&name).unwrap();
+  builder.set_issuer_name(&name).unwrap();
+  builder.set_pubkey(&key).unwrap();
+  builder
+    .set_not_before(&Asn1Time::from_unix(now + not_before * 86_400).unwrap())
+    .unwrap();
+  builder
+    .set_not_after(&Asn1Time::from_unix(now + not_after * 86_400 + 3_600).unwrap())
+    .unwrap();
+  builder.sign(&key, MessageDigest::sha256()).unwrap();
+  (builder.build(), key)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use openssl::x509::X509;
+
+  fn certificate(not_before: i64, not_after: i64) -> X509 {
+    self_signed(not_before, not_after).0
+  }
+
+  #[test]
+  fn test_track_and_expiry() {
+    track("test/valid", &certificate(-1, 10));
+    track("test/expired", &certificate(-10, -2));
+    check_expiry(&DEFAULT_WARN_DAYS);
+
+    let status = status();
+    assert_eq!(status["test/valid"]["days_left"], 10);
+    assert_eq!(status["test/expired"]["days_left"], -2);
+    assert!(expired().iter().any(|e| e.starts_with("test/expired")));
+    assert!(!expired().iter().any(|e| e.starts_with("test/valid")));
+
+    let certificates = CERTIFICATES.lock().unwrap();
+    assert_eq!(certificates["test/valid"].warned_at, Some(30));
+  }
+}
@@ -0,0 +1,198 @@
+use crate::services::payload::FileCache;
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor, SerializeOptions};
+use serde_json::{Number, Value};
+
+static DESCRIPTORS: Lazy<FileCache<DescriptorPool>> = Lazy::new(FileCache::default);
+
+fn integer_value(integer: i128) -> Value {
+  if let Ok(integer) = i64::try_from(integer) {
+    return Value::from(integer);
+  }
+  if let Ok(integer) = u64::try_from(integer) {
+    return Value::from(integer);
+  }
+  Value::from(integer.to_string())
+}
+
+fn float_value(float: f64) -> Value {
+  Number::from_f64(float).map_or(Value::Null, Value::Number)
+}
+
+/**
+ * Map keys that are not strings, e.g. integer keys, become their JSON text
+ */
+fn map_key(key: Value) -> String {
+  match key {
+    Value::String(key) => key,
+    key => key.to_string(),
+  }
+}
+
+fn cbor_to_json(value: ciborium::Value) -> Value {
+  use ciborium::Value as Cbor;
+  match value {
+    Cbor::Integer(integer) => integer_value(integer.into()),
+    Cbor::Float(float) => float_value(float),
+    Cbor::Text(text) => Value::String(text),
+    Cbor::Bytes(bytes) => Value::String(hex::encode_upper(bytes)),
+    Cbor::Bool(boolean) => Value::Bool(boolean),
+    Cbor::Null => Value::Null,
+    Cbor::Tag(_, value) => cbor_to_json(*value),
+    Cbor::Array(items) => Value::Array(items.into_iter().map(cbor_to_json).collect()),
+    Cbor::Map(entries) => Value::Object(
+      entries
+        .into_iter()
+        .map(|(key, value)| (map_key(cbor_to_json(key)), cbor_to_json(value)))
+        .collect(),
+    ),
+    _ => Value::Null,
+  }
+}
+
+fn msgpack_to_json(value: rmpv::Value) -> Value {
+  use rmpv::Value as MsgPack;
+  match value {
+    MsgPack::Nil => Value::Null,
+    MsgPack::Boolean(boolean) => Value::Bool(boolean),
+    MsgPack::Integer(integer) => integer
+      .as_i64()
+      .map(Value::from)
+      .or_else(|| integer.as_u64().map(Value::from))
+      .unwrap_or_default(),
+    MsgPack::F32(float) => float_value(float.into()),
+    MsgPack::F64(float) => float_value(float),
+    MsgPack::String(text) => match text.into_str() {
+      Some(text) => Value::String(text),
+      None => Value::Null,
+    },
+    MsgPack::Binary(bytes) | MsgPack::Ext(_, bytes) => Value::String(hex::encode_upper(bytes)),
+    MsgPack::Array(items) => Value::Array(items.into_iter().map(msgpack_to_json).collect()),
+    MsgPack::Map(entries) => Value::Object(
+      entries
+        .into_iter()
+        .map(|(key, value)| (map_key(msgpack_to_json(key)), msgpack_to_json(value)))
+        .collect(),
+    ),
+  }
+}
+
+/**
+ * Decode a single CBOR item. Byte strings become uppercase hex and tags are dropped.
+ */
+pub fn decode_cbor(payload: &[u8]) -> anyhow::Result<Value> {
+  let mut reader = payload;
+  let value: ciborium::Value = ciborium::from_reader(&mut reader).context("invalid CBOR")?;
+  if !reader.is_empty() {
+    anyhow::bail!("{} bytes left after the CBOR item", reader.len());
+  }
+  Ok(cbor_to_json(value))
+}
+
+/**
+ * Decode a single MessagePack value. Binary and extension values become uppercase hex.
+ */
+pub fn decode_msgpack(payload: &[u8]) -> anyhow::Result<Value> {
+  let mut reader = payload;
+  let value = rmpv::decode::read_value(&mut reader).context("invalid MessagePack")?;
+  if !reader.is_empty() {
+    anyhow::bail!("{} bytes left after the MessagePack value", reader.len());
+  }
+  Ok(msgpack_to_json(value))
+}
+
+/**
+ * Message type `name` of a descriptor set file (`protoc --include_imports --descriptor_set_out`).
+ * The file is read again when it changes.
+ */
+pub fn protobuf_message(path: &str, name: &str) -> anyhow::Result<MessageDescriptor> {
+  let pool = DESCRIPTORS.get_or_load(path, || {
+    let bytes = std::fs::read(path).with_context(|| format!("Failed to read descriptor set {}", path))?;
+    DescriptorPool::decode(bytes.as_slice()).with_context(|| format!("Failed to load descriptor set {}", path))
+  })?;
+
+  pool
+    .get_message_by_name(name)
+    .with_context(|| format!("Message type {} not found in descriptor set {}", name, path))
+}
+
+/**
+ * Decode a Protobuf message. Fields keep their `.proto` names and default values are kept,
+ * so a reading of 0 is still sent.
+ */
+pub fn decode_protobuf(message: MessageDescriptor, payload: &[u8]) -> anyhow::Result<Value> {
+  let message = DynamicMessage::decode(message, payload).context("invalid Protobuf message")?;
+  let options = SerializeOptions::new()
+    .stringify_64_bit_integers(false)
+    .use_proto_field_name(true)
+    .skip_default_fields(false);
+  message
+    .serialize_with_options(serde_json::value::Serializer, &options)
+    .context("Protobuf message cannot be represented as JSON")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use prost::Message;
+  use prost_types::{
+    field_descriptor_proto::{Label, Type},
+    DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+  };
+  use serde_json::json;
+
+  #[test]
+  fn test_decode_cbor_and_msgpack() {
+    // {"t": 21.5, 1: h'CAFE', "ok": true}
+    let cbor = hex::decode("A36174F94D600142CAFE626F6BF5").unwrap();
+    assert_eq!(decode_cbor(&cbor).unwrap(), json!({"t": 21.5, "1": "CAFE", "ok": true}));
+    assert!(decode_cbor(&[0xA3, 0x61]).is_err());
+
+    // {"t": 215, "tags": ["a"]}
+    let msgpack = hex::decode("82A174CCD7A47461677391A161").unwrap();
+    assert_eq!(decode_msgpack(&msgpack).unwrap(), json!({"t": 215, "tags": ["a"]}));
+    assert!(decode_msgpack(&[0x82, 0xA1]).is_err());
+  }
+
+  #[test]
+  fn test_decode_protobuf() {
+    let field = |name: &str, number: i32, kind: Type| FieldDescriptorProto {
+      name: Some(name.to_string()),
+      number: Some(number),
+      label: Some(Label::Optional as i32),
+      r#type: Some(kind as i32),
+      ..Default::default()
+    };
+    let descriptors = FileDescriptorSet {
+      file: vec![FileDescriptorProto {
+        name: Some("reading.proto".to_string()),
+        package: Some("sensors".to_string()),
+        message_type: vec![DescriptorProto {
+          name: Some("Reading".to_string()),
+          field: vec![
+            field("temperature", 1, Type::Double),
+            field("battery_level", 2, Type::Uint32),
+          ],
+          ..Default::default()
+        }],
+        syntax: Some("proto3".to_string()),
+        ..Default::default()
+      }],
+    };
+    let path = std::env::temp_dir().join(format!("tagoio-relay-descriptors-{}.pb", std::process::id()));
+    std::fs::write(&path, descriptors.encode_to_vec()).unwrap();
+    let path = path.to_str().unwrap();
+
+    let message = protobuf_message(path, "sensors.Reading").unwrap();
+    // temperature = 21.5, battery_level unset
+    let payload = hex::decode("090000000000803540").unwrap();
+    assert_eq!(
+      decode_protobuf(message.clone(), &payload).unwrap(),
+      json!({"temperature": 21.5, "battery_level": 0})
+    );
+    assert!(decode_protobuf(message, &[0x09, 0x00]).is_err());
+    assert!(protobuf_message(path, "sensors.Missing").is_err());
+    std::fs::remove_file(path).unwrap();
+  }
+}
@@ -0,0 +1,194 @@
+use crate::{
+  schema::{Deadband, RelayConfig},
+  services::metrics,
+};
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+/**
+ * Last forwarded value of every topic and variable of a relay
+ */
+pub struct ReportByException {
+  relay_id: String,
+  last_forwarded: Mutex<HashMap<(String, String), (f64, Instant)>>,
+}
+
+static REPORTS: Lazy<Mutex<HashMap<String, Arc<ReportByException>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/**
+ * Get the report-by-exception state of a relay, creating it on first use
+ */
+pub fn report_by_exception(relay_cfg: &RelayConfig) -> Arc<ReportByException> {
+  REPORTS
+    .lock()
+    .unwrap()
+    .entry(relay_cfg.id.clone())
+    .or_insert_with(|| Arc::new(ReportByException::new(&relay_cfg.id)))
+    .clone()
+}
+
+fn exceeds_deadband(deadband: &Deadband, last: f64, value: f64) -> bool {
+  let change = (value - last).abs();
+  let absolute = deadband.absolute.is_some_and(|absolute| change > absolute);
+  let percent = deadband.percent.is_some_and(|percent| {
+    if last == 0.0 {
+      change > 0.0
+    } else {
+      change / last.abs() * 100.0 > percent
+    }
+  });
+  // Without any threshold, every change is reported
+  absolute || percent || (deadband.absolute.is_none() && deadband.percent.is_none() && change > 0.0)
+}
+
+impl ReportByException {
+  pub fn new(relay_id: &str) -> Self {
+    ReportByException {
+      relay_id: relay_id.to_string(),
+      last_forwarded: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /**
+   * Keep the records worth forwarding. Numeric variables pass when they moved past the
+   * deadband or stayed silent for `max_silence_secs`; other variables always pass.
+   * The baseline only moves once the records are committed, after they were sent.
+   */
+  pub fn filter(&self, topic: &str, deadband: &Deadband, records: Vec<Value>) -> Vec<Value> {
+    let now = Instant::now();
+    let max_silence = deadband.max_silence_secs.map(Duration::from_secs);
+    let last_forwarded = self.last_forwarded.lock().unwrap();
+    // Values kept earlier in this batch
+    let mut kept: HashMap<(String, String), f64> = HashMap::new();
+
+    let before = records.len();
+    let records: Vec<Value> = records
+      .into_iter()
+      .filter(|record| {
+        let (Some(variable), Some(value)) = (record["variable"].as_str(), record["value"].as_f64()) else {
+          return true;
+        };
+        let key = (topic.to_string(), variable.to_string());
+        let forward = match (kept.get(&key), last_forwarded.get(&key)) {
+          (Some(last), _) => exceeds_deadband(deadband, *last, value),
+          (None, None) => true,
+          (None, Some((last, forwarded_at))) => {
+            exceeds_deadband(deadband, *last, value)
+              || max_silence.is_some_and(|max_silence| now.duration_since(*forwarded_at) >= max_silence)
+          }
+        };
+        if forward {
+          kept.insert(key, value);
+        }
+        forward
+      })
+      .collect();
+
+    for _ in records.len()..before {
+      metrics::increment_counter("records_suppressed_total", &[("relay_id", &self.relay_id)]);
+    }
+    records
+  }
+
+  /**
+   * Make the numeric values of sent records the new baseline of their variables
+   */
+  pub fn commit(&self, topic: &str, records: &[Value]) {
+    let now = Instant::now();
+    let mut last_forwarded = self.last_forwarded.lock().unwrap();
+    for record in records {
+      if let (Some(variable), Some(value)) = (record["variable"].as_str(), record["value"].as_f64()) {
+        last_forwarded.insert((topic.to_string(), variable.to_string()), (value, now));
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn record(variable: &str, value: Value) -> Value {
+    json!({ "variable": variable, "value": value })
+  }
+
+  #[test]
+  fn test_deadband_filter() {
+    let report = ReportByException::new("test_deadband");
+    let deadband = Deadband {
+      absolute: Some(0.5),
+      percent: Some(10.0),
+      ..Default::default()
+    };
+
+    let forwarded = |value: f64| {
+      let records = vec![record("temperature", json!(value)), record("status", json!("ok"))];
+      let records = report.filter("sensors/1", &deadband, records);
+      report.commit("sensors/1", &records);
+      records.iter().any(|r| r["variable"] == "temperature")
+    };
+
+    assert!(forwarded(20.0)); // first value
+    assert!(!forwarded(20.3)); // below both thresholds
+    assert!(forwarded(20.6)); // 0.6 above the last forwarded value
+    assert!(!forwarded(20.9));
+    assert_eq!(
+      report
+        .filter("sensors/1", &deadband, vec![record("status", json!("ok"))])
+        .len(),
+      1
+    );
+  }
+
+  #[test]
+  fn test_baseline_moves_on_commit() {
+    let report = ReportByException::new("test_deadband_commit");
+    let deadband = Deadband {
+      absolute: Some(0.5),
+      ..Default::default()
+    };
+
+    report.commit("sensors/1", &[record("temperature", json!(20.0))]);
+    // Not sent, so 21 stays above the baseline until it is committed
+    assert_eq!(
+      report
+        .filter("sensors/1", &deadband, vec![record("temperature", json!(21))])
+        .len(),
+      1
+    );
+    assert_eq!(
+      report
+        .filter("sensors/1", &deadband, vec![record("temperature", json!(21))])
+        .len(),
+      1
+    );
+    report.commit("sensors/1", &[record("temperature", json!(21))]);
+    assert!(report
+      .filter("sensors/1", &deadband, vec![record("temperature", json!(21))])
+      .is_empty());
+  }
+
+  #[test]
+  fn test_max_silence_heartbeat() {
+    let report = ReportByException::new("test_deadband_silence");
+    let deadband = Deadband {
+      absolute: Some(100.0),
+      max_silence_secs: Some(0),
+      ..Default::default()
+    };
+
+    report.commit("sensors/1", &[record("level", json!(1))]);
+    assert_eq!(
+      report
+        .filter("sensors/1", &deadband, vec![record("level", json!(1))])
+        .len(),
+      1
+    );
+  }
+}
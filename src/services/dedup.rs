@@ -0,0 +1,174 @@
+use crate::{
+  schema::{Dedup, RelayConfig},
+  services::{filters::json_field, metrics},
+};
+use once_cell::sync::Lazy;
+use rumqttc::Publish;
+use std::{
+  collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+  hash::{Hash, Hasher},
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::{Duration, Instant},
+};
+
+/**
+ * Remembers the messages seen within the dedup window of a relay
+ */
+pub struct Deduplicator {
+  relay_id: String,
+  window: Duration,
+  max_entries: usize,
+  id_field: Option<String>,
+  seen: Mutex<SeenMessages>,
+  duplicates: AtomicU64,
+}
+
+#[derive(Default)]
+struct SeenMessages {
+  last_seen: HashMap<u64, Instant>,
+  /// Insertion order, used to expire old keys without scanning the map
+  order: VecDeque<(u64, Instant)>,
+}
+
+static DEDUPLICATORS: Lazy<Mutex<HashMap<String, Arc<Deduplicator>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/**
+ * Get the deduplicator of a relay, or `None` when dedup is disabled
+ */
+pub fn deduplicator(relay_cfg: &RelayConfig) -> Option<Arc<Deduplicator>> {
+  let policy = &relay_cfg.config.mqtt.dedup;
+  if !policy.enabled.unwrap_or(false) {
+    return None;
+  }
+  let deduplicator = DEDUPLICATORS
+    .lock()
+    .unwrap()
+    .entry(relay_cfg.id.clone())
+    .or_insert_with(|| Arc::new(Deduplicator::new(&relay_cfg.id, policy)))
+    .clone();
+  Some(deduplicator)
+}
+
+/**
+ * Duplicate counts of every relay, reported on `/status`
+ */
+pub fn dedup_status() -> serde_json::Value {
+  let deduplicators = DEDUPLICATORS.lock().unwrap();
+  let status: serde_json::Map<String, serde_json::Value> = deduplicators
+    .iter()
+    .map(|(relay_id, deduplicator)| {
+      (
+        relay_id.clone(),
+        serde_json::json!({ "duplicates": deduplicator.duplicates.load(Ordering::Relaxed) }),
+      )
+    })
+    .collect();
+  serde_json::Value::Object(status)
+}
+
+impl Deduplicator {
+  pub fn new(relay_id: &str, policy: &Dedup) -> Self {
+    Deduplicator {
+      relay_id: relay_id.to_string(),
+      window: Duration::from_secs(policy.window_secs.unwrap_or(60)),
+      max_entries: policy.max_entries.unwrap_or(10_000),
+      id_field: policy.id_field.clone(),
+      seen: Mutex::new(SeenMessages::default()),
+      duplicates: AtomicU64::new(0),
+    }
+  }
+
+  /**
+   * Topic plus the configured JSON id field, falling back to topic plus the whole payload
+   */
+  fn message_key(&self, publish: &Publish) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    publish.topic.hash(&mut hasher);
+
+    let id = self.id_field.as_ref().and_then(|field| {
+      let payload: serde_json::Value = serde_json::from_slice(&publish.payload).ok()?;
+      json_field(&payload, field).map(|id| id.to_string())
+    });
+    match id {
+      Some(id) => id.hash(&mut hasher),
+      None => publish.payload.hash(&mut hasher),
+    }
+    hasher.finish()
+  }
+
+  /**
+   * Whether the message was already seen within the window. Records it otherwise.
+   */
+  pub fn is_duplicate(&self, publish: &Publish) -> bool {
+    let key = self.message_key(publish);
+    let now = Instant::now();
+    let mut seen = self.seen.lock().unwrap();
+
+    // Forget keys that left the window, or the oldest ones once the limit is reached
+    while let Some(&(old_key, seen_at)) = seen.order.front() {
+      if now.duration_since(seen_at) < self.window && seen.order.len() < self.max_entries {
+        break;
+      }
+      seen.order.pop_front();
+      if seen.last_seen.get(&old_key) == Some(&seen_at) {
+        seen.last_seen.remove(&old_key);
+      }
+    }
+
+    if seen.last_seen.contains_key(&key) {
+      self.duplicates.fetch_add(1, Ordering::Relaxed);
+      metrics::increment_counter("messages_duplicate_total", &[("relay_id", &self.relay_id)]);
+      return true;
+    }
+
+    seen.last_seen.insert(key, now);
+    seen.order.push_back((key, now));
+    false
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rumqttc::QoS;
+
+  #[test]
+  fn test_is_duplicate() {
+    let deduplicator = Deduplicator::new("test_dedup", &Dedup::default());
+    let message = Publish::new("sensors/1", QoS::AtLeastOnce, r#"{"id":1,"value":10}"#);
+
+    assert!(!deduplicator.is_duplicate(&message));
+    assert!(deduplicator.is_duplicate(&message));
+    assert!(!deduplicator.is_duplicate(&Publish::new("sensors/2", QoS::AtLeastOnce, r#"{"id":1,"value":10}"#)));
+    assert_eq!(deduplicator.duplicates.load(Ordering::Relaxed), 1);
+  }
+
+  #[test]
+  fn test_is_duplicate_by_id_field() {
+    let policy = Dedup {
+      id_field: Some("id".to_string()),
+      ..Default::default()
+    };
+    let deduplicator = Deduplicator::new("test_dedup_id", &policy);
+
+    assert!(!deduplicator.is_duplicate(&Publish::new("sensors/1", QoS::AtLeastOnce, r#"{"id":1,"value":10}"#)));
+    assert!(deduplicator.is_duplicate(&Publish::new("sensors/1", QoS::AtLeastOnce, r#"{"id":1,"value":11}"#)));
+    assert!(!deduplicator.is_duplicate(&Publish::new("sensors/1", QoS::AtLeastOnce, r#"{"id":2,"value":11}"#)));
+  }
+
+  #[test]
+  fn test_window_expiry() {
+    let policy = Dedup {
+      window_secs: Some(0),
+      ..Default::default()
+    };
+    let deduplicator = Deduplicator::new("test_dedup_window", &policy);
+    let message = Publish::new("sensors/1", QoS::AtLeastOnce, "10");
+
+    assert!(!deduplicator.is_duplicate(&message));
+    assert!(!deduplicator.is_duplicate(&message));
+  }
+}
@@ -0,0 +1,357 @@
+use crate::services::{metrics, mqttrelay::PublishMessage};
+use rumqttc::{AsyncClient, ClientError, Publish, QoS};
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EntryState {
+  /// Waiting to be handed to the MQTT client
+  Pending,
+  /// Handed to the MQTT client, waiting for the requests to assign a packet id
+  Sent,
+  /// Written to the broker, waiting for the PubAck or PubComp
+  InFlight(u16),
+}
+
+struct Entry {
+  id: u64,
+  message: PublishMessage,
+  state: EntryState,
+}
+
+/**
+ * Publish handed to the MQTT client: a downlink entry, or `None` for other publishes such as
+ * Sparkplug rebirth requests. The requests writes them in this order.
+ */
+struct Handed {
+  id: Option<u64>,
+  qos: QoS,
+}
+
+#[derive(Default)]
+struct State {
+  entries: VecDeque<Entry>,
+  handed: VecDeque<Handed>,
+  next_id: u64,
+}
+
+/**
+ * Ordered queue of downlink publishes that survives broker reconnects.
+ * Messages are only removed once the broker acknowledges them or their TTL expires.
+ * Every publish on the client goes through the queue, so each packet id is matched to its own publish.
+ */
+pub struct DownlinkQueue {
+  relay_id: String,
+  state: Mutex<State>,
+  ttl: Duration,
+  max_size: usize,
+}
+
+fn qos(message: &PublishMessage) -> QoS {
+  rumqttc::qos(message.qos).unwrap_or(QoS::AtLeastOnce)
+}
+
+impl DownlinkQueue {
+  pub fn new(relay_id: &str, ttl: Duration, max_size: usize) -> Self {
+    DownlinkQueue {
+      relay_id: relay_id.to_string(),
+      state: Mutex::new(State::default()),
+      ttl,
+      max_size,
+    }
+  }
+
+  /**
+   * Queue a downlink. Messages that do not fit in a full queue are dropped and counted.
+   */
+  pub fn push(&self, message: PublishMessage) {
+    let mut state = self.state.lock().unwrap();
+    if state.entries.len() >= self.max_size {
+      log::warn!(target: "mqtt", relay_id = self.relay_id.as_str(), topic = message.topic.as_str(); "Downlink queue is full: message on topic {} dropped", message.topic);
+      metrics::increment_counter("downlink_dropped_total", &[("relay_id", &self.relay_id)]);
+      return;
+    }
+    let id = state.next_id;
+    state.next_id += 1;
+    state.entries.push_back(Entry {
+      id,
+      message,
+      state: EntryState::Pending,
+    });
+  }
+
+  /**
+   * Hand every pending message to the client, in order, with its own QoS. Expired messages are dropped.
+   * Returns `false` when the client's request queue is full and some messages are still pending.
+   */
+  pub fn publish_pending(&self, client: &AsyncClient) -> bool {
+    let mut state = self.state.lock().unwrap();
+    self.drop_expired(&mut state.entries);
+
+    let State { entries, handed, .. } = &mut *state;
+    for entry in entries.iter_mut().filter(|entry| entry.state == EntryState::Pending) {
+      let message = &entry.message;
+      let qos = qos(message);
+      if let Err(e) = client.try_publish(&message.topic, qos, message.retain, message.message.as_bytes()) {
+        log::debug!(target: "mqtt", relay_id = self.relay_id.as_str(), topic = message.topic.as_str(), error:% = e; "Downlink on topic {} waits for the MQTT client: {}", message.topic, e);
+        return false;
+      }
+      entry.state = EntryState::Sent;
+      handed.push_back(Handed {
+        id: Some(entry.id),
+        qos,
+      });
+    }
+    true
+  }
+
+  /**
+   * Hand a publish that is not a downlink to the client, e.g. a Sparkplug rebirth request,
+   * so its packet is not taken for one of the queued downlinks
+   */
+  pub fn publish_other(&self, client: &AsyncClient, publish: Publish) -> Result<(), ClientError> {
+    let mut state = self.state.lock().unwrap();
+    client.try_publish(&publish.topic, publish.qos, publish.retain, publish.payload)?;
+    state.handed.push_back(Handed {
+      id: None,
+      qos: publish.qos,
+    });
+    Ok(())
+  }
+
+  /**
+   * The requests wrote a publish to the broker. Publishes go out in the order they were handed
+   * to the client; the packet id must agree with the QoS of that publish (0 only for QoS 0).
+   */
+  pub fn on_outgoing_publish(&self, pkid: u16) {
+    let mut state = self.state.lock().unwrap();
+    let Some(handed) = state.handed.pop_front() else {
+      return;
+    };
+    if (handed.qos == QoS::AtMostOnce) != (pkid == 0) {
+      // Something published on the client without the queue: the order can no longer be trusted
+      log::warn!(target: "mqtt", relay_id = self.relay_id.as_str(); "Outgoing publish {} does not match the downlink queue: sending the unacknowledged downlinks again", pkid);
+      state.handed.clear();
+      for entry in state.entries.iter_mut().filter(|entry| entry.state == EntryState::Sent) {
+        entry.state = EntryState::Pending;
+      }
+      return;
+    }
+
+    let Some(id) = handed.id else {
+      return;
+    };
+    if let Some(index) = state.entries.iter().position(|entry| entry.id == id) {
+      if pkid == 0 {
+        // QoS 0 publishes are never acknowledged
+        state.entries.remove(index);
+      } else {
+        state.entries[index].state = EntryState::InFlight(pkid);
+      }
+    }
+  }
+
+  /**
+   * The broker acknowledged a packet id: PubAck for QoS 1, PubComp for QoS 2
+   */
+  pub fn on_ack(&self, pkid: u16) {
+    let mut state = self.state.lock().unwrap();
+    if let Some(index) = state
+      .entries
+      .iter()
+      .position(|entry| entry.state == EntryState::InFlight(pkid))
+    {
+      state.entries.remove(index);
+    }
+  }
+
+  /**
+   * Connection was lost: everything not acknowledged goes back to pending so it is
+   * replayed after the next ConnAck.
+   */
+  pub fn requeue(&self) {
+    let mut state = self.state.lock().unwrap();
+    state.handed.clear();
+    for entry in state.entries.iter_mut() {
+      entry.state = EntryState::Pending;
+    }
+    self.drop_expired(&mut state.entries);
+
+    if !state.entries.is_empty() {
+      log::info!(target: "mqtt", relay_id = self.relay_id.as_str(); "{} downlink message(s) queued for replay after reconnect", state.entries.len());
+    }
+  }
+
+  fn drop_expired(&self, entries: &mut VecDeque<Entry>) {
+    entries.retain(|entry| {
+      let expired = entry.state == EntryState::Pending && entry.message.queued_at.elapsed() > self.ttl;
+      if expired {
+        log::warn!(target: "mqtt", relay_id = self.relay_id.as_str(), topic = entry.message.topic.as_str(); "Dropping downlink message on topic {}: not delivered within {:?}", entry.message.topic, self.ttl);
+      }
+      !expired
+    });
+  }
+
+  #[cfg(test)]
+  pub(crate) fn len(&self) -> usize {
+    self.state.lock().unwrap().entries.len()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rumqttc::Request;
+  use std::time::Instant;
+
+  fn message(topic: &str) -> PublishMessage {
+    PublishMessage {
+      topic: topic.to_string(),
+      message: "payload".to_string(),
+      qos: 1,
+      retain: false,
+      queued_at: Instant::now(),
+    }
+  }
+
+  fn client(capacity: usize) -> (AsyncClient, flume::Receiver<Request>) {
+    let (requests_tx, requests_rx) = flume::bounded(capacity);
+    (AsyncClient::from_senders(requests_tx), requests_rx)
+  }
+
+  /**
+   * Topic and QoS of every publish the client was handed, in order
+   */
+  fn handed(requests: &flume::Receiver<Request>) -> Vec<(String, QoS)> {
+    let mut published = Vec::new();
+    while let Ok(Request::Publish(publish)) = requests.try_recv() {
+      published.push((publish.topic, publish.qos));
+    }
+    published
+  }
+
+  #[test]
+  fn test_acknowledged_messages_are_removed() {
+    let (client, _requests) = client(10);
+    let queue = DownlinkQueue::new("test_id", Duration::from_secs(60), 100);
+    queue.push(message("a"));
+    queue.push(message("b"));
+
+    assert!(queue.publish_pending(&client));
+    queue.on_outgoing_publish(1);
+    queue.on_outgoing_publish(2);
+    queue.on_ack(1);
+
+    assert_eq!(queue.len(), 1);
+    queue.on_ack(2);
+    assert_eq!(queue.len(), 0);
+  }
+
+  #[test]
+  fn test_unacknowledged_messages_are_replayed_in_order() {
+    let (client, requests) = client(10);
+    let queue = DownlinkQueue::new("test_id", Duration::from_secs(60), 100);
+    queue.push(message("a"));
+    queue.push(message("b"));
+    queue.push(message("c"));
+
+    queue.publish_pending(&client);
+    queue.on_outgoing_publish(1);
+    queue.on_ack(1);
+    queue.on_outgoing_publish(2);
+
+    queue.requeue();
+    queue.push(message("d"));
+
+    handed(&requests);
+    queue.publish_pending(&client);
+    let topics: Vec<String> = handed(&requests).into_iter().map(|(topic, _)| topic).collect();
+    assert_eq!(topics, vec!["b", "c", "d"]);
+  }
+
+  #[test]
+  fn test_expired_messages_are_dropped() {
+    let (client, requests) = client(10);
+    let queue = DownlinkQueue::new("test_id", Duration::from_millis(10), 100);
+    let mut old = message("old");
+    old.queued_at = Instant::now() - Duration::from_secs(1);
+    queue.push(old);
+    queue.push(message("new"));
+
+    queue.requeue();
+    queue.publish_pending(&client);
+
+    let topics: Vec<String> = handed(&requests).into_iter().map(|(topic, _)| topic).collect();
+    assert_eq!(topics, vec!["new"]);
+  }
+
+  #[test]
+  fn test_requested_qos_is_kept() {
+    let (client, requests) = client(10);
+    let queue = DownlinkQueue::new("test_id", Duration::from_secs(60), 100);
+    for qos in [0, 2] {
+      queue.push(PublishMessage { qos, ..message("a") });
+    }
+
+    queue.publish_pending(&client);
+    assert_eq!(
+      handed(&requests),
+      vec![("a".to_string(), QoS::AtMostOnce), ("a".to_string(), QoS::ExactlyOnce)]
+    );
+
+    // The QoS 0 downlink is done once written, the QoS 2 one once completed
+    queue.on_outgoing_publish(0);
+    assert_eq!(queue.len(), 1);
+    queue.on_outgoing_publish(1);
+    queue.on_ack(1);
+    assert_eq!(queue.len(), 0);
+  }
+
+  #[test]
+  fn test_other_publishes_keep_their_place() {
+    let (client, _requests) = client(10);
+    let queue = DownlinkQueue::new("test_id", Duration::from_secs(60), 100);
+    queue.push(message("a"));
+    queue.publish_pending(&client);
+    queue
+      .publish_other(&client, Publish::new("other", QoS::AtMostOnce, "x"))
+      .unwrap();
+    queue.push(message("b"));
+    queue.publish_pending(&client);
+
+    queue.on_outgoing_publish(1);
+    queue.on_outgoing_publish(0);
+    queue.on_outgoing_publish(2);
+    assert_eq!(queue.len(), 2);
+    queue.on_ack(2);
+    queue.on_ack(1);
+    assert_eq!(queue.len(), 0);
+  }
+
+  #[test]
+  fn test_full_queue_drops_new_messages() {
+    let (client, requests) = client(10);
+    let queue = DownlinkQueue::new("test_id", Duration::from_secs(60), 2);
+    for topic in ["a", "b", "c"] {
+      queue.push(message(topic));
+    }
+
+    assert_eq!(queue.len(), 2);
+    queue.publish_pending(&client);
+    let topics: Vec<String> = handed(&requests).into_iter().map(|(topic, _)| topic).collect();
+    assert_eq!(topics, vec!["a", "b"]);
+  }
+
+  #[test]
+  fn test_full_client_keeps_messages_pending() {
+    let (client, requests) = client(1);
+    let queue = DownlinkQueue::new("test_id", Duration::from_secs(60), 100);
+    queue.push(message("a"));
+    queue.push(message("b"));
+
+    assert!(!queue.publish_pending(&client));
+    assert_eq!(handed(&requests).len(), 1);
+    assert!(queue.publish_pending(&client));
+    let topics: Vec<String> = handed(&requests).into_iter().map(|(topic, _)| topic).collect();
+    assert_eq!(topics, vec!["b"]);
+  }
+}
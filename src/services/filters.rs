@@ -0,0 +1,163 @@
+use crate::schema::{JsonPredicate, MessageFilters};
+use rumqttc::Publish;
+use serde_json::Value;
+
+/**
+ * MQTT topic filter matching. Unlike `rumqttc::matches`, `$SYS/...` style topics
+ * match filters that name their first level explicitly, e.g. `$SYS/#`.
+ */
+pub fn topic_matches(topic: &str, filter: &str) -> bool {
+  if topic.starts_with('$') {
+    return match (topic.split_once('/'), filter.split_once('/')) {
+      (Some((topic_root, topic_rest)), Some((filter_root, filter_rest))) => {
+        topic_root == filter_root && rumqttc::matches(topic_rest, filter_rest)
+      }
+      _ => topic == filter,
+    };
+  }
+  rumqttc::matches(topic, filter)
+}
+
+/**
+ * Field of a JSON document addressed by a dotted path such as `data.temperature`
+ */
+pub fn json_field<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+  path.split('.').try_fold(value, |value, key| match value {
+    Value::Array(items) => key.parse::<usize>().ok().and_then(|index| items.get(index)),
+    _ => value.get(key),
+  })
+}
+
+fn predicate_matches(predicate: &JsonPredicate, payload: &Value) -> bool {
+  let field = json_field(payload, &predicate.field);
+
+  if let Some(exists) = predicate.exists {
+    if field.is_some() != exists {
+      return false;
+    }
+  }
+  if let Some(expected) = &predicate.equals {
+    if field != Some(expected) {
+      return false;
+    }
+  }
+  if let Some(unexpected) = &predicate.not_equals {
+    if field == Some(unexpected) {
+      return false;
+    }
+  }
+  if predicate.min.is_some() || predicate.max.is_some() {
+    let Some(number) = field.and_then(Value::as_f64) else {
+      return false;
+    };
+    if predicate.min.is_some_and(|min| number < min) || predicate.max.is_some_and(|max| number > max) {
+      return false;
+    }
+  }
+  true
+}
+
+/**
+ * Why an incoming message should not be forwarded, or `None` to forward it.
+ * The reason is used as the `reason` label of the `messages_dropped_total` counter.
+ */
+pub fn drop_reason(filters: &MessageFilters, publish: &Publish) -> Option<&'static str> {
+  if let Some(exclude) = &filters.exclude {
+    if exclude.iter().any(|filter| topic_matches(&publish.topic, filter)) {
+      return Some("excluded_topic");
+    }
+  }
+  if let Some(include) = &filters.include {
+    if !include.iter().any(|filter| topic_matches(&publish.topic, filter)) {
+      return Some("not_included");
+    }
+  }
+  if filters.skip_retained.unwrap_or(false) && publish.retain {
+    return Some("retained");
+  }
+  if filters.min_payload_bytes.is_some_and(|min| publish.payload.len() < min) {
+    return Some("payload_too_small");
+  }
+  if filters.max_payload_bytes.is_some_and(|max| publish.payload.len() > max) {
+    return Some("payload_too_large");
+  }
+  if !filters.json.is_empty() {
+    // Payloads that are not JSON cannot satisfy a predicate
+    let matched = serde_json::from_slice::<Value>(&publish.payload).is_ok_and(|payload| {
+      filters
+        .json
+        .iter()
+        .all(|predicate| predicate_matches(predicate, &payload))
+    });
+    if !matched {
+      return Some("json_predicate");
+    }
+  }
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rumqttc::QoS;
+
+  fn publish(topic: &str, payload: &str, retain: bool) -> Publish {
+    let mut publish = Publish::new(topic, QoS::AtMostOnce, payload);
+    publish.retain = retain;
+    publish
+  }
+
+  #[test]
+  fn test_topic_matches() {
+    assert!(topic_matches("sensors/1/debug", "+/+/debug"));
+    assert!(topic_matches("$SYS/broker/uptime", "$SYS/#"));
+    assert!(!topic_matches("$SYS/broker/uptime", "#"));
+    assert!(!topic_matches("sensors/1", "sensors/1/debug"));
+  }
+
+  #[test]
+  fn test_drop_reason() {
+    let filters = MessageFilters {
+      include: Some(vec!["sensors/#".to_string()]),
+      exclude: Some(vec!["sensors/+/debug".to_string()]),
+      skip_retained: Some(true),
+      max_payload_bytes: Some(64),
+      json: vec![JsonPredicate {
+        field: "data.temperature".to_string(),
+        min: Some(-40.0),
+        ..Default::default()
+      }],
+      ..Default::default()
+    };
+
+    let valid = r#"{"data":{"temperature":21.5}}"#;
+    assert_eq!(drop_reason(&filters, &publish("sensors/1", valid, false)), None);
+    assert_eq!(
+      drop_reason(&filters, &publish("sensors/1/debug", valid, false)),
+      Some("excluded_topic")
+    );
+    assert_eq!(
+      drop_reason(&filters, &publish("other/1", valid, false)),
+      Some("not_included")
+    );
+    assert_eq!(
+      drop_reason(&filters, &publish("sensors/1", valid, true)),
+      Some("retained")
+    );
+    assert_eq!(
+      drop_reason(&filters, &publish("sensors/1", &"x".repeat(65), false)),
+      Some("payload_too_large")
+    );
+    assert_eq!(
+      drop_reason(
+        &filters,
+        &publish("sensors/1", r#"{"data":{"temperature":-50}}"#, false)
+      ),
+      Some("json_predicate")
+    );
+    assert_eq!(
+      drop_reason(&filters, &publish("sensors/1", "21.5", false)),
+      Some("json_predicate")
+    );
+  }
+}
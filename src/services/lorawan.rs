@@ -0,0 +1,316 @@
+use crate::{
+  schema::{LoRaWan, RecordMetadata, RelayConfig, Subscription},
+  services::{
+    filters::{json_field, topic_matches},
+    payload,
+  },
+};
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use rumqttc::Publish;
+use serde_json::{json, Value};
+
+/**
+ * Field names of the uplink envelope of a network server, as dotted paths
+ */
+struct Envelope {
+  default_topic: &'static str,
+  dev_eui: &'static str,
+  device_name: &'static str,
+  application_id: &'static str,
+  time: &'static str,
+  fport: &'static str,
+  fcnt: &'static str,
+  data: &'static str,
+  decoded: &'static str,
+  rx_info: &'static str,
+  gateway_id: &'static str,
+  frequency: &'static str,
+}
+
+const CHIRPSTACK: Envelope = Envelope {
+  default_topic: "application/+/device/+/event/up",
+  dev_eui: "deviceInfo.devEui",
+  device_name: "deviceInfo.deviceName",
+  application_id: "deviceInfo.applicationId",
+  time: "time",
+  fport: "fPort",
+  fcnt: "fCnt",
+  data: "data",
+  decoded: "object",
+  rx_info: "rxInfo",
+  gateway_id: "gatewayId",
+  frequency: "txInfo.frequency",
+};
+
+const TTN: Envelope = Envelope {
+  default_topic: "v3/+/devices/+/up",
+  dev_eui: "end_device_ids.dev_eui",
+  device_name: "end_device_ids.device_id",
+  application_id: "end_device_ids.application_ids.application_id",
+  time: "received_at",
+  fport: "uplink_message.f_port",
+  fcnt: "uplink_message.f_cnt",
+  data: "uplink_message.frm_payload",
+  decoded: "uplink_message.decoded_payload",
+  rx_info: "uplink_message.rx_metadata",
+  gateway_id: "gateway_ids.gateway_id",
+  frequency: "uplink_message.settings.frequency",
+};
+
+fn envelope(adapter: &str) -> Option<&'static Envelope> {
+  match adapter {
+    "chirpstack" => Some(&CHIRPSTACK),
+    "ttn" => Some(&TTN),
+    _ => None,
+  }
+}
+
+/**
+ * Uplink topic filter of the configured adapter, subscribed to along with `subscribe`
+ */
+pub fn uplink_topic(lorawan: &LoRaWan) -> Option<String> {
+  let envelope = envelope(lorawan.adapter.as_deref()?)?;
+  Some(
+    lorawan
+      .uplink_topic
+      .clone()
+      .unwrap_or_else(|| envelope.default_topic.to_string()),
+  )
+}
+
+/**
+ * TagoIO data records of a network-server uplink: the decoded payload fields (or the raw
+ * payload as hex), `fport`, `fcnt`, `rssi` and `snr`. The device EUI is the record `serial`.
+ * Returns `None` when the message is not an uplink envelope of the configured adapter.
+ */
+pub fn decode_records(
+  relay_cfg: &RelayConfig,
+  subscription: Option<&Subscription>,
+  publish: &Publish,
+  received_at: i64,
+) -> Option<Vec<Value>> {
+  let lorawan = &relay_cfg.config.mqtt.lorawan;
+  let envelope = envelope(lorawan.adapter.as_deref()?)?;
+  if !topic_matches(&publish.topic, &uplink_topic(lorawan)?) {
+    return None;
+  }
+  let uplink: Value = serde_json::from_slice(&publish.payload).ok()?;
+  let dev_eui = json_field(&uplink, envelope.dev_eui)?.as_str()?.to_lowercase();
+
+  // The gateway that heard the uplink best
+  let gateways: Vec<Value> = json_field(&uplink, envelope.rx_info)
+    .and_then(Value::as_array)
+    .map(|rx_info| {
+      rx_info
+        .iter()
+        .map(|rx| {
+          json!({
+            "gateway_id": json_field(rx, envelope.gateway_id),
+            "rssi": rx.get("rssi"),
+            "snr": rx.get("snr"),
+          })
+        })
+        .collect()
+    })
+    .unwrap_or_default();
+  let best = gateways.iter().max_by(|a, b| {
+    let rssi = |gateway: &Value| gateway["rssi"].as_f64().unwrap_or(f64::NEG_INFINITY);
+    rssi(a).total_cmp(&rssi(b))
+  });
+
+  let default_options = RecordMetadata::default();
+  let options = subscription.map_or(&default_options, |subscription| &subscription.metadata);
+  let mut metadata = payload::record_metadata(relay_cfg, options, publish);
+  let fields = metadata.as_object_mut().unwrap();
+  fields.insert("serial".to_string(), json!(dev_eui));
+  fields.insert("dev_eui".to_string(), json!(dev_eui));
+  for (key, path) in [
+    ("device_name", envelope.device_name),
+    ("application_id", envelope.application_id),
+    ("frequency", envelope.frequency),
+  ] {
+    if let Some(value) = json_field(&uplink, path) {
+      fields.insert(key.to_string(), value.clone());
+    }
+  }
+  if let Some(best) = best {
+    fields.insert("gateway_id".to_string(), best["gateway_id"].clone());
+  }
+  fields.insert("gateways".to_string(), json!(gateways));
+
+  let mut variables = Vec::new();
+  match json_field(&uplink, envelope.decoded) {
+    Some(decoded @ Value::Object(_)) => payload::flatten_json("", decoded, &mut variables),
+    _ => {
+      let data = json_field(&uplink, envelope.data)
+        .and_then(Value::as_str)
+        .and_then(|data| BASE64.decode(data).ok())
+        .unwrap_or_default();
+      variables.push(("payload".to_string(), json!(hex::encode_upper(data))));
+    }
+  }
+  for (variable, path) in [("fport", envelope.fport), ("fcnt", envelope.fcnt)] {
+    if let Some(value) = json_field(&uplink, path) {
+      variables.push((variable.to_string(), value.clone()));
+    }
+  }
+  if let Some(best) = best {
+    variables.push(("rssi".to_string(), best["rssi"].clone()));
+    variables.push(("snr".to_string(), best["snr"].clone()));
+  }
+
+  let time = json_field(&uplink, envelope.time)
+    .and_then(Value::as_str)
+    .and_then(|time| time.parse::<jiff::Timestamp>().ok())
+    .map_or(received_at, |time| time.as_millisecond());
+  let records = variables
+    .into_iter()
+    .map(|(variable, value)| {
+      json!({
+        "variable": variable,
+        "value": value,
+        "time": payload::format_time(time),
+        "metadata": metadata,
+      })
+    })
+    .collect();
+  Some(records)
+}
+
+/**
+ * Network-server downlink for `device` (devEUI for ChirpStack, device ID for TTN) carrying
+ * `payload_hex` on `fport`. Returns the MQTT topic and JSON envelope to publish.
+ */
+pub fn downlink(
+  lorawan: &LoRaWan,
+  device: &str,
+  fport: u8,
+  confirmed: bool,
+  payload_hex: &str,
+) -> anyhow::Result<(String, String)> {
+  // 0 is for MAC commands and 224 and above are reserved
+  if !(1..=223).contains(&fport) {
+    anyhow::bail!("fport must be between 1 and 223, got {}", fport);
+  }
+  let application_id = lorawan
+    .application_id
+    .as_deref()
+    .context("lorawan.application_id is required for downlinks")?;
+  let data = BASE64.encode(hex::decode(payload_hex).context("the downlink message must be hex")?);
+
+  match lorawan.adapter.as_deref() {
+    Some("chirpstack") => Ok((
+      format!("application/{}/device/{}/command/down", application_id, device),
+      json!({ "devEui": device, "confirmed": confirmed, "fPort": fport, "data": data }).to_string(),
+    )),
+    Some("ttn") => Ok((
+      format!("v3/{}/devices/{}/down/push", application_id, device),
+      json!({ "downlinks": [{ "f_port": fport, "frm_payload": data, "priority": "NORMAL", "confirmed": confirmed }] })
+        .to_string(),
+    )),
+    _ => anyhow::bail!("no LoRaWAN adapter is configured for this relay"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::schema::{ConfigFile, Mqtt};
+  use rumqttc::QoS;
+
+  fn relay_config(adapter: &str) -> RelayConfig {
+    RelayConfig {
+      id: "test_id".to_string(),
+      config: ConfigFile {
+        mqtt: Mqtt {
+          address: "localhost".to_string(),
+          port: 1883,
+          lorawan: LoRaWan {
+            adapter: Some(adapter.to_string()),
+            application_id: Some("my-app@ttn".to_string()),
+            ..Default::default()
+          },
+          ..Default::default()
+        },
+        ..Default::default()
+      },
+      profile_id: None,
+      network_id: None,
+      http_client: reqwest::Client::new(),
+    }
+  }
+
+  #[test]
+  fn test_chirpstack_uplink() {
+    let uplink = json!({
+      "time": "2023-11-14T22:13:20Z",
+      "deviceInfo": {"devEui": "0101010101010101", "deviceName": "sensor-1", "applicationId": "app-uuid"},
+      "fPort": 10,
+      "fCnt": 7,
+      "data": "AQI=",
+      "rxInfo": [
+        {"gatewayId": "gw-far", "rssi": -110, "snr": -5.0},
+        {"gatewayId": "gw-near", "rssi": -57.5, "snr": 10.5}
+      ],
+      "txInfo": {"frequency": 868100000}
+    });
+    let publish = Publish::new(
+      "application/app-uuid/device/0101010101010101/event/up",
+      QoS::AtMostOnce,
+      uplink.to_string(),
+    );
+
+    let records = decode_records(&relay_config("chirpstack"), None, &publish, 0).unwrap();
+    let variables: Vec<(&str, &Value)> = records
+      .iter()
+      .map(|record| (record["variable"].as_str().unwrap(), &record["value"]))
+      .collect();
+    assert_eq!(
+      variables,
+      vec![
+        ("payload", &json!("0102")),
+        ("fport", &json!(10)),
+        ("fcnt", &json!(7)),
+        ("rssi", &json!(-57.5)),
+        ("snr", &json!(10.5)),
+      ]
+    );
+    assert_eq!(records[0]["time"], "2023-11-14T22:13:20Z");
+    assert_eq!(records[0]["metadata"]["serial"], "0101010101010101");
+    assert_eq!(records[0]["metadata"]["gateway_id"], "gw-near");
+    assert_eq!(records[0]["metadata"]["frequency"], 868100000);
+
+    let other = Publish::new("sensors/1", QoS::AtMostOnce, uplink.to_string());
+    assert!(decode_records(&relay_config("chirpstack"), None, &other, 0).is_none());
+  }
+
+  #[test]
+  fn test_ttn_uplink_and_downlink() {
+    let uplink = json!({
+      "end_device_ids": {"device_id": "sensor-1", "dev_eui": "0004A30B001C0530"},
+      "uplink_message": {
+        "f_port": 1,
+        "frm_payload": "AQI=",
+        "decoded_payload": {"temperature": 21.5},
+        "rx_metadata": [{"gateway_ids": {"gateway_id": "gw-1"}, "rssi": -42, "snr": 4.2}]
+      }
+    });
+    let publish = Publish::new("v3/my-app@ttn/devices/sensor-1/up", QoS::AtMostOnce, uplink.to_string());
+    let records = decode_records(&relay_config("ttn"), None, &publish, 0).unwrap();
+    assert_eq!(records[0]["variable"], "temperature");
+    assert_eq!(records[0]["value"], 21.5);
+    assert_eq!(records[0]["metadata"]["serial"], "0004a30b001c0530");
+    assert_eq!(records[0]["metadata"]["device_name"], "sensor-1");
+
+    let (topic, message) = downlink(&relay_config("ttn").config.mqtt.lorawan, "sensor-1", 15, false, "0102").unwrap();
+    assert_eq!(topic, "v3/my-app@ttn/devices/sensor-1/down/push");
+    let message: Value = serde_json::from_str(&message).unwrap();
+    assert_eq!(message["downlinks"][0]["frm_payload"], "AQI=");
+    assert_eq!(message["downlinks"][0]["f_port"], 15);
+
+    assert!(downlink(&relay_config("ttn").config.mqtt.lorawan, "sensor-1", 15, false, "zz").is_err());
+    assert!(downlink(&relay_config("ttn").config.mqtt.lorawan, "sensor-1", 0, false, "0102").is_err());
+    assert!(downlink(&relay_config("ttn").config.mqtt.lorawan, "sensor-1", 224, false, "0102").is_err());
+  }
+}
@@ -0,0 +1,79 @@
+use once_cell::sync::Lazy;
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex};
+
+/**
+ * Metric name plus its labels, rendered as `name{label="value",...}`
+ */
+type MetricKey = (String, Vec<(String, String)>);
+
+static COUNTERS: Lazy<Mutex<BTreeMap<MetricKey, u64>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+static GAUGES: Lazy<Mutex<BTreeMap<MetricKey, f64>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+fn metric_key(name: &str, labels: &[(&str, &str)]) -> MetricKey {
+  (
+    name.to_string(),
+    labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+  )
+}
+
+pub fn increment_counter(name: &str, labels: &[(&str, &str)]) {
+  *COUNTERS.lock().unwrap().entry(metric_key(name, labels)).or_insert(0) += 1;
+}
+
+pub fn set_gauge(name: &str, labels: &[(&str, &str)], value: f64) {
+  GAUGES.lock().unwrap().insert(metric_key(name, labels), value);
+}
+
+fn render_labels(labels: &[(String, String)]) -> String {
+  if labels.is_empty() {
+    return String::new();
+  }
+  let labels: Vec<String> = labels
+    .iter()
+    .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+    .collect();
+  format!("{{{}}}", labels.join(","))
+}
+
+/**
+ * Render every metric in the Prometheus text exposition format
+ */
+pub fn render_prometheus() -> String {
+  let mut output = String::new();
+
+  let mut last_name = String::new();
+  for ((name, labels), value) in COUNTERS.lock().unwrap().iter() {
+    if *name != last_name {
+      let _ = writeln!(output, "# TYPE tagoio_relay_{} counter", name);
+      last_name = name.clone();
+    }
+    let _ = writeln!(output, "tagoio_relay_{}{} {}", name, render_labels(labels), value);
+  }
+
+  for ((name, labels), value) in GAUGES.lock().unwrap().iter() {
+    if *name != last_name {
+      let _ = writeln!(output, "# TYPE tagoio_relay_{} gauge", name);
+      last_name = name.clone();
+    }
+    let _ = writeln!(output, "tagoio_relay_{}{} {}", name, render_labels(labels), value);
+  }
+
+  output
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_render_prometheus() {
+    increment_counter("test_render_total", &[("relay_id", "a")]);
+    increment_counter("test_render_total", &[("relay_id", "a")]);
+    set_gauge("test_render_gauge", &[("certificate", "say \"hi\"")], 1.5);
+
+    let output = render_prometheus();
+    assert!(output.contains("# TYPE tagoio_relay_test_render_total counter"));
+    assert!(output.contains("tagoio_relay_test_render_total{relay_id=\"a\"} 2"));
+    assert!(output.contains("tagoio_relay_test_render_gauge{certificate=\"say \\\"hi\\\"\"} 1.5"));
+  }
+}
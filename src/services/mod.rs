@@ -1,3 +1,20 @@
+pub mod aggregation;
+pub mod archive;
+pub mod broker_tls;
+pub mod certificates;
+pub mod codecs;
+pub mod deadband;
+pub mod dedup;
+pub mod downlink_queue;
+pub mod filters;
+pub mod lorawan;
+pub mod metrics;
 pub mod mosquitto_auth;
 pub mod mqttrelay;
+pub mod payload;
+pub mod rate_limit;
+pub mod scripting;
+pub mod sinks;
+pub mod sparkplug;
 pub mod tagoio;
+pub mod wasm_decoder;
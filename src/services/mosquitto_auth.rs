@@ -1,6 +1,9 @@
 use std::sync::Arc;
 
-use crate::{schema::RelayConfig, services::tagoio::verify_device_token};
+use crate::{
+  schema::{RelayConfig, Secret},
+  services::tagoio::verify_device_token,
+};
 use axum::{response::IntoResponse, Extension, Json};
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
@@ -9,7 +12,7 @@ use tokio::sync::RwLock;
 #[allow(dead_code)]
 pub struct AuthRequest {
   username: String,
-  password: String,
+  password: Secret,
 }
 
 #[derive(Debug, Serialize)]
@@ -27,7 +30,7 @@ pub async fn handle_auth(
 
   // Try to authenticate against any relay in the list
   for relay in relays.iter() {
-    if (verify_device_token(relay, &payload.password).await).is_ok() {
+    if (verify_device_token(relay, &payload.password.expose()).await).is_ok() {
       return (axum::http::StatusCode::OK, Json(AuthResponse { ok: true }));
     }
   }
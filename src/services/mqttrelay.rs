@@ -1,69 +1,115 @@
-use crate::{schema::RelayConfig, utils::calculate_backoff};
-use rumqttc::{
-  tokio_rustls::rustls::{ClientConfig, RootCertStore},
-  AsyncClient, MqttOptions, QoS, TlsConfiguration,
+use crate::{
+  schema::RelayConfig,
+  services::{
+    aggregation, archive,
+    broker_tls::{build_client_config, load_broker_credentials, BrokerCredentials},
+    certificates, dedup,
+    downlink_queue::DownlinkQueue,
+    filters, metrics, payload,
+    rate_limit::{self, Decision},
+    sinks,
+    sparkplug::{self, AliasNames},
+  },
+  utils::calculate_backoff,
 };
+use rumqttc::{AsyncClient, MqttOptions, Publish, QoS};
 use serde::Deserialize;
-use std::sync::Arc;
+use std::{sync::Arc, time::Instant};
 use tokio::{
   sync::{mpsc, Mutex, Semaphore},
   time::{sleep, Duration},
 };
 const BACKOFF_MAX_RETRIES: u32 = 20;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 #[allow(dead_code)]
 pub struct PublishMessage {
   pub topic: String,
   pub message: String,
   pub qos: u8,
   pub retain: bool,
+  #[serde(skip, default = "Instant::now")]
+  pub queued_at: Instant,
 }
 
 pub async fn run_mqtt_relay_connection(relay_cfg: Arc<RelayConfig>, publish_rx: mpsc::Receiver<PublishMessage>) {
-  log::info!(target: "mqtt", "Running relay task for client ID: {}", relay_cfg.id);
+  log::info!(target: "mqtt", relay_id = relay_cfg.id.as_str(); "Running relay task for client ID: {}", relay_cfg.id);
 
   let publish_rx = Arc::new(Mutex::new(publish_rx));
-
-  let mqttoptions = initialize_mqtt_options(&relay_cfg);
+  let downlink_ttl = Duration::from_secs(relay_cfg.config.downlink_ttl.unwrap_or(300));
+  let downlink_queue_size = relay_cfg.config.downlink_queue_size.unwrap_or(1000).max(1);
+  let downlink_queue = Arc::new(DownlinkQueue::new(&relay_cfg.id, downlink_ttl, downlink_queue_size));
+  // Windows keep closing while the broker is away; the task ends with the relay
+  let flush_task = aggregation::spawn_flush(relay_cfg.clone());
+  // Sinks of a previous run of this relay finish their queues and stop
+  sinks::rebuild(&relay_cfg);
 
   let mut backoff_retry_attempts = 0;
 
   loop {
-    let (client, mut eventloop) = AsyncClient::new(mqttoptions.clone(), 15);
+    // Built on every connection so reloaded secrets and rotated certificates are picked up.
+    // A file caught mid-rotation is retried with the connection backoff instead of ending the relay.
+    let mqttoptions = match initialize_mqtt_options(&relay_cfg) {
+      Ok(mqttoptions) => Some(mqttoptions),
+      Err(e) => {
+        log::error!(target: "mqtt", relay_id = relay_cfg.id.as_str(), error:% = format!("{:#}", e); "Invalid MQTT settings for relay {}: {:#}", relay_cfg.id, e);
+        None
+      }
+    };
 
-    subscribe_to_topics(&client, &relay_cfg).await;
+    if let Some(mqttoptions) = mqttoptions {
+      let (client, mut eventloop) = AsyncClient::new(mqttoptions, 15);
 
-    let publish_rx_clone = Arc::clone(&publish_rx);
-    let publish_task = tokio::spawn(async move {
-      if let Err(e) = publish_messages(client, publish_rx_clone).await {
-        log::error!(target: "mqtt", "Failed to publish messages: {:?}", e);
-      }
-    });
+      subscribe_to_topics(&client, &relay_cfg).await;
 
-    if let Err(e) = handle_mqtt_connection(&mut eventloop).await {
-      log::error!(target: "error", "Failed to connect to MQTT broker. Error details: {:?}", e.to_string());
-    } else {
-      log::info!(target: "mqtt", "Connected to MQTT broker successfully");
-      log::info!(target: "mqtt", "Subscribed to topics: {:?}", relay_cfg.config.mqtt.subscribe);
-      backoff_retry_attempts = 0;
-    }
+      if let Err(e) = handle_mqtt_connection(&relay_cfg.id, &mut eventloop).await {
+        log::error!(target: "error", relay_id = relay_cfg.id.as_str(), error:% = e; "Failed to connect to MQTT broker. Error details: {:?}", e.to_string());
+      } else {
+        log::info!(target: "mqtt", relay_id = relay_cfg.id.as_str(); "Connected to MQTT broker successfully");
+        log::info!(target: "mqtt", relay_id = relay_cfg.id.as_str(); "Subscribed to topics: {:?}", relay_cfg.config.mqtt.topic_filters());
+        backoff_retry_attempts = 0;
+        // Births and deaths sent while disconnected were missed; unknown aliases ask for a rebirth
+        if let Some(host) = sparkplug::sparkplug_host(&relay_cfg) {
+          host.reset();
+        }
 
-    process_incoming_messages(&mut eventloop, relay_cfg.clone()).await;
+        // Only start publishing after the ConnAck, so queued downlinks are replayed on a live session
+        let publish_rx_clone = Arc::clone(&publish_rx);
+        let downlink_queue_clone = Arc::clone(&downlink_queue);
+        let relay_id = relay_cfg.id.clone();
+        let incoming_client = client.clone();
+        let publish_task = tokio::spawn(async move {
+          if let Err(e) = publish_messages(client, &relay_id, publish_rx_clone, downlink_queue_clone).await {
+            log::error!(target: "mqtt", relay_id = relay_id.as_str(), error:% = e; "Failed to publish messages: {:?}", e);
+          }
+        });
+
+        process_incoming_messages(&mut eventloop, &incoming_client, relay_cfg.clone(), &downlink_queue).await;
+
+        publish_task.abort();
+        downlink_queue.requeue();
+      }
+    }
 
     if backoff_retry_attempts >= BACKOFF_MAX_RETRIES {
-      log::error!(target: "mqtt", "Max retries reached. Exiting: {}", relay_cfg.id);
+      log::error!(target: "mqtt", relay_id = relay_cfg.id.as_str(); "Max retries reached. Exiting: {}", relay_cfg.id);
+      if let Some(flush_task) = flush_task {
+        flush_task.abort();
+      }
+      sinks::close(&relay_cfg).await;
       return;
     }
     let backoff_duration = calculate_backoff(backoff_retry_attempts);
-    log::warn!(target: "mqtt", "Disconnected from MQTT broker. Retrying in {:?}", backoff_duration);
-    publish_task.abort();
+    log::warn!(target: "mqtt", relay_id = relay_cfg.id.as_str(); "Disconnected from MQTT broker. Retrying in {:?}", backoff_duration);
     sleep(backoff_duration).await;
     backoff_retry_attempts += 1;
   }
 }
 
-fn initialize_mqtt_options(relay_cfg: &RelayConfig) -> MqttOptions {
+/**
+ * Build the MQTT client options. Fails when a configured certificate or key cannot be loaded.
+ */
+pub fn initialize_mqtt_options(relay_cfg: &RelayConfig) -> anyhow::Result<MqttOptions> {
   let client_id = relay_cfg
     .config
     .mqtt
@@ -73,104 +119,79 @@ fn initialize_mqtt_options(relay_cfg: &RelayConfig) -> MqttOptions {
 
   let username = &relay_cfg.config.mqtt.username;
   let password = &relay_cfg.config.mqtt.password;
-  let ca_file = &relay_cfg.config.mqtt.broker_tls_ca;
-  let crt_file = &relay_cfg.config.mqtt.broker_tls_cert;
-  let key_file = &relay_cfg.config.mqtt.broker_tls_key;
-
-  let ca_content = if let Some(ca_path) = ca_file {
-    std::fs::read_to_string(ca_path).unwrap_or_else(|e| {
-      log::error!(target: "mqtt", "Failed to read CA file {}: {}", ca_path, e);
-      String::new()
-    })
-  } else {
-    String::new()
-  };
-
-  let crt_content = if let Some(crt_path) = crt_file {
-    std::fs::read_to_string(crt_path).unwrap_or_else(|e| {
-      log::error!(target: "mqtt", "Failed to read certificate file {}: {}", crt_path, e);
-      String::new()
-    })
-  } else {
-    String::new()
-  };
 
-  let key_content = if let Some(key_path) = key_file {
-    std::fs::read_to_string(key_path).unwrap_or_else(|e| {
-      log::error!(target: "mqtt", "Failed to read key file {}: {}", key_path, e);
-      String::new()
-    })
-  } else {
-    String::new()
-  };
+  let mqtt = &relay_cfg.config.mqtt;
+  let mut transport = rumqttc::Transport::Tcp;
 
-  // Replace the file paths with their contents
-  let ca_file = if !ca_content.is_empty() { Some(ca_content) } else { None };
-  let crt_file = if !crt_content.is_empty() {
-    Some(crt_content)
-  } else {
-    None
-  };
-  let key_file = if !key_content.is_empty() {
-    Some(key_content)
-  } else {
-    None
-  };
+  let credentials = load_broker_credentials(mqtt)?;
+  track_broker_certificates(&relay_cfg.id, &credentials);
+  if mqtt.tls_enabled || mqtt.address.starts_with("ssl") {
+    if mqtt.tls_insecure_skip_verify.unwrap_or(false) {
+      log::warn!(target: "security", relay_id = relay_cfg.id.as_str(); "Broker TLS certificate verification is DISABLED (tls_insecure_skip_verify). The connection can be intercepted.");
+    }
+    let client_config = build_client_config(mqtt, credentials)?;
+    transport = rumqttc::Transport::tls_with_config(client_config.into());
+  } else if credentials.ca.is_some() || credentials.client_auth.is_some() {
+    log::warn!(target: "security", relay_id = relay_cfg.id.as_str(); "Broker TLS certificates are configured but tls_enabled is false: connecting without TLS");
+  }
 
-  let mut mqttoptions = MqttOptions::new(client_id, &relay_cfg.config.mqtt.address, relay_cfg.config.mqtt.port);
+  let mut mqttoptions = MqttOptions::new(client_id, mqtt.address.clone(), mqtt.port);
   mqttoptions.set_keep_alive(Duration::from_secs(30));
   mqttoptions.set_max_packet_size(1024 * 1024, 1024 * 1024); // 1mb in/out
+  mqttoptions.set_transport(transport);
 
-  if relay_cfg.config.mqtt.tls_enabled || relay_cfg.config.mqtt.address.starts_with("ssl") {
-    if let (Some(ca_content), Some(crt_content), Some(key_content)) = (ca_file.clone(), crt_file, key_file) {
-      // All three files are provided - use client certificate authentication
-      mqttoptions.set_transport(rumqttc::Transport::tls_with_config(TlsConfiguration::Simple {
-        ca: ca_content.into_bytes(),
-        alpn: None,
-        client_auth: Some((crt_content.into_bytes(), key_content.into_bytes())),
-      }));
-    } else if let Some(ca_content) = ca_file {
-      // Only CA is provided - use server verification only
-      mqttoptions.set_transport(rumqttc::Transport::tls_with_config(TlsConfiguration::Simple {
-        ca: ca_content.into_bytes(),
-        alpn: None,
-        client_auth: None,
-      }));
-    } else {
-      // No certificates provided - use system root certificates
-      let mut root_cert_store = RootCertStore::empty();
-      root_cert_store
-        .add_parsable_certificates(rustls_native_certs::load_native_certs().expect("could not load platform certs"));
+  if let Some(username) = username {
+    let password = password
+      .as_ref()
+      .ok_or_else(|| anyhow::anyhow!("Password must be provided if username is set"))?;
+    mqttoptions.set_credentials(username, password.expose());
+  }
 
-      let client_config = ClientConfig::builder()
-        .with_root_certificates(Arc::new(root_cert_store))
-        .with_no_client_auth();
+  Ok(mqttoptions)
+}
 
-      mqttoptions.set_transport(rumqttc::Transport::tls_with_config(client_config.into()));
-    }
-  }
+/**
+ * Record the broker CA and client chain for expiry monitoring
+ */
+fn track_broker_certificates(relay_id: &str, credentials: &BrokerCredentials) {
+  let certificates = [
+    ("broker_ca", credentials.ca.as_deref().unwrap_or_default()),
+    (
+      "broker_client",
+      credentials
+        .client_auth
+        .as_ref()
+        .map(|(chain, _)| chain.as_slice())
+        .unwrap_or_default(),
+    ),
+  ];
 
-  if let Some(username) = username {
-    mqttoptions.set_credentials(
-      username,
-      password.as_ref().expect("Password must be provided if username is set"),
-    );
+  for (name, chain) in certificates {
+    for (index, cert) in chain.iter().enumerate() {
+      let source = if index == 0 {
+        format!("{}/{}", relay_id, name)
+      } else {
+        format!("{}/{}.{}", relay_id, name, index)
+      };
+      certificates::track_der(&source, cert);
+    }
   }
-
-  mqttoptions
 }
 
 async fn subscribe_to_topics(client: &AsyncClient, relay_cfg: &RelayConfig) {
-  for topic in relay_cfg.config.mqtt.subscribe.iter() {
+  for topic in relay_cfg.config.mqtt.topic_filters().iter() {
     client.subscribe(topic, QoS::AtMostOnce).await.unwrap();
   }
 }
 
-async fn handle_mqtt_connection(eventloop: &mut rumqttc::EventLoop) -> Result<(), rumqttc::ConnectionError> {
+async fn handle_mqtt_connection(
+  relay_id: &str,
+  eventloop: &mut rumqttc::EventLoop,
+) -> Result<(), rumqttc::ConnectionError> {
   match eventloop.poll().await {
     Ok(notification) => {
       if let rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_)) = notification {
-        log::info!("Connection to MQTT broker was successful");
+        log::info!(target: "mqtt", relay_id = relay_id; "Connection to MQTT broker was successful");
       }
       Ok(())
     }
@@ -180,47 +201,148 @@ async fn handle_mqtt_connection(eventloop: &mut rumqttc::EventLoop) -> Result<()
 
 async fn publish_messages(
   client: AsyncClient,
+  relay_id: &str,
   publish_rx: Arc<Mutex<mpsc::Receiver<PublishMessage>>>,
+  downlink_queue: Arc<DownlinkQueue>,
 ) -> anyhow::Result<()> {
-  while let Some(publish_message) = publish_rx.lock().await.recv().await {
-    log::info!(target: "mqtt", "[API] External published received on topic {}.", publish_message.topic);
-    if let Err(e) = client
-      .publish(
-        &publish_message.topic,
-        QoS::AtLeastOnce,
-        publish_message.retain,
-        publish_message.message.as_bytes(),
-      )
-      .await
-    {
-      log::error!(target: "mqtt", "Failed to publish message: {:?}", e);
-    }
+  loop {
+    // Replay whatever was left unacknowledged by the previous connection before taking new messages
+    let retry_in = if downlink_queue.publish_pending(&client) {
+      Duration::from_secs(1)
+    } else {
+      // The client's request queue is full: try again once the event loop made room
+      Duration::from_millis(100)
+    };
+
+    let next = tokio::time::timeout(retry_in, async { publish_rx.lock().await.recv().await }).await;
+    let publish_message = match next {
+      Ok(Some(publish_message)) => publish_message,
+      Ok(None) => return Ok(()),
+      Err(_) => continue,
+    };
+    log::info!(target: "mqtt", relay_id = relay_id, topic = publish_message.topic.as_str(); "[API] External published received on topic {}.", publish_message.topic);
+    downlink_queue.push(publish_message);
   }
-  Ok(())
 }
 
-async fn process_incoming_messages(eventloop: &mut rumqttc::EventLoop, relay_cfg: Arc<RelayConfig>) {
+async fn process_incoming_messages(
+  eventloop: &mut rumqttc::EventLoop,
+  client: &AsyncClient,
+  relay_cfg: Arc<RelayConfig>,
+  downlink_queue: &DownlinkQueue,
+) {
   // Limit concurrent requests to avoid overwhelming TagoIO or running out of file descriptors
-  let semaphore = Arc::new(Semaphore::new(50));
+  let max_concurrency = relay_cfg.config.forwarding.max_concurrency.unwrap_or(50);
+  let semaphore = Arc::new(Semaphore::new(max_concurrency));
+  let deduplicator = dedup::deduplicator(&relay_cfg);
+  let rate_limiter = rate_limit::rate_limiter(&relay_cfg);
+  let sparkplug_host = sparkplug::sparkplug_host(&relay_cfg);
+  let archive = archive::archive(&relay_cfg);
 
   while let Ok(notification) = eventloop.poll().await {
-    if let rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish)) = notification {
-      log::info!(target: "mqtt", "[Broker] Received message on topic {}", publish.topic);
+    match notification {
+      rumqttc::Event::Outgoing(rumqttc::Outgoing::Publish(pkid)) => downlink_queue.on_outgoing_publish(pkid),
+      rumqttc::Event::Incoming(rumqttc::Packet::PubAck(ack)) => downlink_queue.on_ack(ack.pkid),
+      rumqttc::Event::Incoming(rumqttc::Packet::PubComp(comp)) => downlink_queue.on_ack(comp.pkid),
+      rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish)) => {
+        let received_at = payload::now_ms();
+        log::info!(target: "mqtt", relay_id = relay_cfg.id.as_str(), topic = publish.topic.as_str(), message_id = publish.pkid; "[Broker] Received message on topic {}", publish.topic);
+        if let Some(archive) = &archive {
+          archive.record(&publish, received_at);
+        }
 
-      let relay_cfg = relay_cfg.clone();
-      let semaphore = semaphore.clone();
+        // Aliases and online state follow the broker order, before messages are handled concurrently
+        let tracked = sparkplug_host
+          .as_ref()
+          .map(|host| host.track(&publish))
+          .unwrap_or_default();
+        if let Some(rebirth) = tracked.rebirth {
+          // Not awaited: the event loop that would make room in the request queue is this one
+          let topic = rebirth.topic.clone();
+          match downlink_queue.publish_other(client, rebirth) {
+            Ok(()) => {
+              log::info!(target: "mqtt", relay_id = relay_cfg.id.as_str(), topic = topic.as_str(); "[Broker] Unknown Sparkplug alias on topic {}: requested a rebirth", publish.topic)
+            }
+            Err(e) => {
+              log::warn!(target: "mqtt", relay_id = relay_cfg.id.as_str(), topic = topic.as_str(), error:% = e; "Failed to request a Sparkplug rebirth on {}: {}", topic, e)
+            }
+          }
+        }
+        if let Some(reason) = tracked
+          .drop
+          .or_else(|| filters::drop_reason(&relay_cfg.config.mqtt.filters, &publish))
+        {
+          log::debug!(target: "mqtt", relay_id = relay_cfg.id.as_str(), topic = publish.topic.as_str(), message_id = publish.pkid; "[Broker] Message on topic {} dropped: {}", publish.topic, reason);
+          metrics::increment_counter(
+            "messages_dropped_total",
+            &[("relay_id", &relay_cfg.id), ("reason", reason)],
+          );
+          continue;
+        }
 
-      tokio::spawn(async move {
-        // Acquire a permit. If the semaphore is closed, we just return.
-        let _permit = match semaphore.acquire().await {
-          Ok(p) => p,
-          Err(_) => return,
-        };
+        if deduplicator
+          .as_ref()
+          .is_some_and(|deduplicator| deduplicator.is_duplicate(&publish))
+        {
+          log::debug!(target: "mqtt", relay_id = relay_cfg.id.as_str(), topic = publish.topic.as_str(), message_id = publish.pkid; "[Broker] Duplicate message on topic {} skipped", publish.topic);
+          continue;
+        }
 
-        if let Err(e) = crate::services::tagoio::forward_buffer_messages(&relay_cfg, &publish).await {
-          log::error!(target: "mqtt", "Failed to forward message to TagoIO: {:?}", e.to_string());
+        match rate_limiter
+          .as_ref()
+          .map(|limiter| limiter.check(&publish, &tracked.aliases, received_at))
+        {
+          None | Some(Decision::Forward) => {}
+          Some(Decision::Skip) => {
+            log::debug!(target: "mqtt", relay_id = relay_cfg.id.as_str(), topic = publish.topic.as_str(), message_id = publish.pkid; "[Broker] Message on topic {} rate limited", publish.topic);
+            continue;
+          }
+          Some(Decision::Defer(key, delay)) => {
+            // Only the latest message of the interval is sent, once the interval is over
+            let limiter = rate_limiter.clone().unwrap();
+            let relay_cfg = relay_cfg.clone();
+            let semaphore = semaphore.clone();
+            tokio::spawn(async move {
+              sleep(delay).await;
+              if let Some((latest, aliases, received_at)) = limiter.take_latest(&key) {
+                forward_message(relay_cfg, semaphore, latest, aliases, received_at).await;
+              }
+            });
+            continue;
+          }
         }
-      });
+
+        tokio::spawn(forward_message(
+          relay_cfg.clone(),
+          semaphore.clone(),
+          publish,
+          tracked.aliases,
+          received_at,
+        ));
+      }
+      _ => {}
     }
   }
 }
+
+/**
+ * Forward an uplink to TagoIO, waiting for a free slot first
+ */
+async fn forward_message(
+  relay_cfg: Arc<RelayConfig>,
+  semaphore: Arc<Semaphore>,
+  publish: Publish,
+  aliases: AliasNames,
+  received_at: i64,
+) {
+  // Acquire a permit. If the semaphore is closed, we just return.
+  let _permit = match semaphore.acquire().await {
+    Ok(p) => p,
+    Err(_) => return,
+  };
+
+  if let Err(e) = crate::services::tagoio::forward_buffer_messages(&relay_cfg, &publish, &aliases, received_at).await {
+    let error = crate::services::tagoio::error_chain(e.as_ref());
+    log::error!(target: "mqtt", relay_id = relay_cfg.id.as_str(), topic = publish.topic.as_str(), message_id = publish.pkid, error = error.as_str(); "Failed to forward message to TagoIO: {:?}", e.to_string());
+  }
+}
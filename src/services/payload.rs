@@ -0,0 +1,379 @@
+use crate::{
+  schema::{RecordMetadata, RelayConfig, Subscription},
+  services::{codecs, filters::json_field},
+};
+use rumqttc::{Publish, QoS};
+use serde_json::{json, Value};
+use std::{collections::HashMap, sync::Mutex, time::SystemTime};
+
+/**
+ * Values loaded from files, by path. A value is loaded again once the modification time of its file changes,
+ * so a replaced script, decoder or descriptor set is used for the next message without restarting the relay.
+ */
+pub struct FileCache<T> {
+  entries: Mutex<HashMap<String, (Option<SystemTime>, T)>>,
+}
+
+impl<T> Default for FileCache<T> {
+  fn default() -> Self {
+    FileCache {
+      entries: Mutex::new(HashMap::new()),
+    }
+  }
+}
+
+impl<T: Clone> FileCache<T> {
+  pub fn get_or_load(&self, path: &str, load: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    let modified = std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    if let Some((loaded_at, value)) = self.entries.lock().unwrap().get(path) {
+      if *loaded_at == modified {
+        return Ok(value.clone());
+      }
+    }
+
+    let value = load()?;
+    self
+      .entries
+      .lock()
+      .unwrap()
+      .insert(path.to_string(), (modified, value.clone()));
+    Ok(value)
+  }
+}
+
+/**
+ * Give records built by a decoder or script the `metadata` and `time` of the default records
+ * when they have none
+ */
+pub fn merge_defaults(mut records: Vec<Value>, defaults: &[Value]) -> Vec<Value> {
+  if let Some(default) = defaults.first() {
+    for record in records.iter_mut() {
+      for field in ["metadata", "time"] {
+        if record.get(field).is_none() && default.get(field).is_some() {
+          record[field] = default[field].clone();
+        }
+      }
+    }
+  }
+  records
+}
+
+/**
+ * RFC 3339 representation of a unix timestamp in milliseconds
+ */
+pub fn format_time(ms: i64) -> String {
+  jiff::Timestamp::from_millisecond(ms)
+    .map(|timestamp| timestamp.to_string())
+    .unwrap_or_default()
+}
+
+/**
+ * Current unix time in milliseconds
+ */
+pub fn now_ms() -> i64 {
+  jiff::Timestamp::now().as_millisecond()
+}
+
+/**
+ * Payload as a string, or uppercase hex when it is not valid UTF-8
+ */
+pub fn payload_value(payload: &[u8]) -> Value {
+  match std::str::from_utf8(payload) {
+    Ok(utf8_str) => Value::String(utf8_str.to_string()),
+    Err(_) => Value::String(hex::encode_upper(payload)),
+  }
+}
+
+pub fn qos_number(qos: QoS) -> u8 {
+  match qos {
+    QoS::AtMostOnce => 0,
+    QoS::AtLeastOnce => 1,
+    QoS::ExactlyOnce => 2,
+  }
+}
+
+/**
+ * Flatten nested objects into `parent_child` variables. Arrays are kept as JSON text.
+ */
+pub fn flatten_json(prefix: &str, value: &Value, variables: &mut Vec<(String, Value)>) {
+  match value {
+    Value::Object(fields) => {
+      for (key, value) in fields {
+        let name = if prefix.is_empty() {
+          key.clone()
+        } else {
+          format!("{}_{}", prefix, key)
+        };
+        flatten_json(&name, value, variables);
+      }
+    }
+    Value::Array(_) => variables.push((prefix.to_string(), Value::String(value.to_string()))),
+    _ => variables.push((prefix.to_string(), value.clone())),
+  }
+}
+
+/**
+ * Device timestamp read from a JSON field: RFC 3339 text, or unix seconds/milliseconds
+ */
+fn payload_timestamp(payload: &Value, field: &str) -> Option<i64> {
+  match json_field(payload, field)? {
+    Value::Number(number) => {
+      let number = number.as_f64()?;
+      // Values this large can only be milliseconds
+      Some(if number >= 1e12 {
+        number as i64
+      } else {
+        (number * 1000.0) as i64
+      })
+    }
+    Value::String(text) => text
+      .parse::<jiff::Timestamp>()
+      .ok()
+      .map(|timestamp| timestamp.as_millisecond()),
+    _ => None,
+  }
+}
+
+/**
+ * Record `metadata`: topic and QoS, plus the broker fields enabled for the subscription
+ */
+pub fn record_metadata(relay_cfg: &RelayConfig, options: &RecordMetadata, publish: &Publish) -> Value {
+  let mut metadata = json!({
+    "topic": publish.topic.clone(),
+    "qos": qos_number(publish.qos),
+  });
+  let fields = metadata.as_object_mut().unwrap();
+  if options.retain.unwrap_or(true) {
+    fields.insert("retain".to_string(), json!(publish.retain));
+  }
+  if options.dup.unwrap_or(true) {
+    fields.insert("dup".to_string(), json!(publish.dup));
+  }
+  if options.packet_id.unwrap_or(true) {
+    fields.insert("packet_id".to_string(), json!(publish.pkid));
+  }
+  if options.relay_id.unwrap_or(true) {
+    fields.insert("relay_id".to_string(), json!(relay_cfg.id));
+  }
+  if options.broker.unwrap_or(true) {
+    let mqtt = &relay_cfg.config.mqtt;
+    fields.insert("broker".to_string(), json!(format!("{}:{}", mqtt.address, mqtt.port)));
+  }
+  metadata
+}
+
+/**
+ * Payload decoded according to the subscription `payload_format`
+ */
+fn structured_payload(
+  relay_cfg: &RelayConfig,
+  subscription: Option<&Subscription>,
+  payload: &[u8],
+) -> anyhow::Result<Value> {
+  match subscription.and_then(|s| s.payload_format.as_deref()) {
+    Some("cbor") => codecs::decode_cbor(payload),
+    Some("msgpack") => codecs::decode_msgpack(payload),
+    Some("protobuf") => {
+      let descriptor = relay_cfg.config.mqtt.protobuf_descriptor.as_deref().unwrap_or_default();
+      let message = subscription
+        .and_then(|s| s.protobuf_message.as_deref())
+        .unwrap_or_default();
+      codecs::decode_protobuf(codecs::protobuf_message(descriptor, message)?, payload)
+    }
+    _ => Ok(serde_json::from_slice(payload)?),
+  }
+}
+
+/**
+ * TagoIO data records for an uplink received at `received_at` (unix milliseconds).
+ * Raw payloads become a single `payload` variable; with `payload_format` "json", "cbor",
+ * "msgpack" or "protobuf", every field of the decoded object becomes a variable.
+ */
+pub fn decode_records(
+  relay_cfg: &RelayConfig,
+  subscription: Option<&Subscription>,
+  publish: &Publish,
+  received_at: i64,
+) -> Vec<Value> {
+  let default_options = RecordMetadata::default();
+  let options = subscription.map_or(&default_options, |subscription| &subscription.metadata);
+  let metadata = record_metadata(relay_cfg, options, publish);
+
+  let format = subscription.and_then(|s| s.payload_format.as_deref()).unwrap_or("raw");
+  let structured = if format != "raw" || options.timestamp_field.is_some() {
+    structured_payload(relay_cfg, subscription, &publish.payload)
+      .inspect_err(|e| {
+        if format != "raw" {
+          log::debug!(target: "mqtt", relay_id = relay_cfg.id.as_str(), topic = publish.topic.as_str(); "Payload on topic {} is not valid {}: {:#}", publish.topic, format, e);
+        }
+      })
+      .ok()
+  } else {
+    None
+  };
+
+  let time = options
+    .timestamp_field
+    .as_deref()
+    .zip(structured.as_ref())
+    .and_then(|(field, payload)| payload_timestamp(payload, field))
+    .or_else(|| options.time.unwrap_or(true).then_some(received_at));
+
+  let mut variables = Vec::new();
+  match structured.filter(|_| format != "raw") {
+    Some(Value::Object(fields)) if !fields.is_empty() => flatten_json("", &Value::Object(fields), &mut variables),
+    Some(value @ (Value::Number(_) | Value::Bool(_) | Value::String(_))) => {
+      variables.push(("payload".to_string(), value))
+    }
+    _ => {
+      if format != "raw" {
+        log::debug!(target: "mqtt", relay_id = relay_cfg.id.as_str(), topic = publish.topic.as_str(); "Payload on topic {} is not a {} object: forwarding it as is", publish.topic, format);
+      }
+      variables.push(("payload".to_string(), payload_value(&publish.payload)));
+    }
+  }
+
+  variables
+    .into_iter()
+    .map(|(variable, value)| {
+      let mut record = json!({ "variable": variable, "value": value, "metadata": metadata });
+      if let Some(time) = time {
+        record["time"] = json!(format_time(time));
+      }
+      record
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::schema::{ConfigFile, Mqtt};
+
+  fn relay_config() -> RelayConfig {
+    RelayConfig {
+      id: "test_id".to_string(),
+      config: ConfigFile {
+        mqtt: Mqtt {
+          address: "broker.local".to_string(),
+          port: 1883,
+          ..Default::default()
+        },
+        ..Default::default()
+      },
+      profile_id: None,
+      network_id: None,
+      http_client: reqwest::Client::new(),
+    }
+  }
+
+  #[test]
+  fn test_decode_json_records() {
+    let subscription = Subscription {
+      topic: "sensors/#".to_string(),
+      payload_format: Some("json".to_string()),
+      ..Default::default()
+    };
+    let publish = Publish::new(
+      "sensors/1",
+      QoS::AtLeastOnce,
+      r#"{"temperature":21.5,"battery":{"level":90},"tags":["a"]}"#,
+    );
+
+    let records = decode_records(&relay_config(), Some(&subscription), &publish, 0);
+    let variables: Vec<(&str, &Value)> = records
+      .iter()
+      .map(|record| (record["variable"].as_str().unwrap(), &record["value"]))
+      .collect();
+    assert_eq!(
+      variables,
+      vec![
+        ("battery_level", &json!(90)),
+        ("tags", &json!("[\"a\"]")),
+        ("temperature", &json!(21.5)),
+      ]
+    );
+    assert_eq!(records[0]["metadata"]["topic"], "sensors/1");
+    assert_eq!(records[0]["metadata"]["qos"], 1);
+
+    // Not JSON: forwarded as a raw payload
+    let records = decode_records(
+      &relay_config(),
+      Some(&subscription),
+      &Publish::new("sensors/1", QoS::AtMostOnce, vec![0xff]),
+      0,
+    );
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["variable"], "payload");
+    assert_eq!(records[0]["value"], "FF");
+  }
+
+  #[test]
+  fn test_decode_msgpack_records() {
+    let subscription = Subscription {
+      topic: "sensors/#".to_string(),
+      payload_format: Some("msgpack".to_string()),
+      ..Default::default()
+    };
+    // {"t": 215}
+    let publish = Publish::new("sensors/1", QoS::AtMostOnce, vec![0x81, 0xA1, 0x74, 0xCC, 0xD7]);
+    let records = decode_records(&relay_config(), Some(&subscription), &publish, 0);
+    assert_eq!(records[0]["variable"], "t");
+    assert_eq!(records[0]["value"], 215);
+
+    // Truncated: falls back to hex
+    let publish = Publish::new("sensors/1", QoS::AtMostOnce, vec![0x81, 0xA1]);
+    let records = decode_records(&relay_config(), Some(&subscription), &publish, 0);
+    assert_eq!(records[0]["variable"], "payload");
+    assert_eq!(records[0]["value"], "81A1");
+  }
+
+  #[test]
+  fn test_record_time_and_metadata() {
+    let mut publish = Publish::new("sensors/1", QoS::AtLeastOnce, r#"{"ts":1700000000,"value":1}"#);
+    publish.pkid = 7;
+    publish.retain = true;
+
+    let records = decode_records(&relay_config(), None, &publish, 1_700_000_001_500);
+    assert_eq!(records[0]["time"], "2023-11-14T22:13:21.5Z");
+    assert_eq!(
+      records[0]["metadata"],
+      json!({
+        "topic": "sensors/1",
+        "qos": 1,
+        "retain": true,
+        "dup": false,
+        "packet_id": 7,
+        "relay_id": "test_id",
+        "broker": "broker.local:1883",
+      })
+    );
+
+    // Device timestamp from the payload, and per-subscription toggles
+    let subscription = Subscription {
+      topic: "sensors/#".to_string(),
+      metadata: RecordMetadata {
+        timestamp_field: Some("ts".to_string()),
+        packet_id: Some(false),
+        broker: Some(false),
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+    let records = decode_records(&relay_config(), Some(&subscription), &publish, 0);
+    assert_eq!(records[0]["time"], "2023-11-14T22:13:20Z");
+    assert!(records[0]["metadata"].get("packet_id").is_none());
+    assert!(records[0]["metadata"].get("broker").is_none());
+
+    let subscription = Subscription {
+      topic: "sensors/#".to_string(),
+      metadata: RecordMetadata {
+        time: Some(false),
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+    let records = decode_records(&relay_config(), Some(&subscription), &publish, 0);
+    assert!(records[0].get("time").is_none());
+  }
+}
@@ -0,0 +1,369 @@
+use crate::{
+  schema::{RateLimit, RelayConfig},
+  services::{filters::topic_matches, metrics, sparkplug::AliasNames},
+};
+use once_cell::sync::Lazy;
+use rumqttc::Publish;
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+/**
+ * Longest time a message is kept as the latest of its bucket
+ */
+const MAX_DEFER: Duration = Duration::from_secs(3600);
+
+/**
+ * How often buckets back at their full burst are removed
+ */
+const EVICT_INTERVAL: Duration = Duration::from_secs(60);
+
+/**
+ * Rule index plus the topic, device id or nothing, depending on the rule scope
+ */
+pub type BucketKey = (usize, String);
+
+/**
+ * What to do with an incoming message
+ */
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+  Forward,
+  Skip,
+  /// Kept as the latest message of its bucket: call `take_latest` after the delay
+  Defer(BucketKey, Duration),
+}
+
+struct Bucket {
+  tokens: f64,
+  updated_at: Instant,
+  over_limit: u64,
+  latest: Option<(Publish, AliasNames, i64)>,
+}
+
+/**
+ * Token buckets of a relay, one per rule and topic/device
+ */
+pub struct RateLimiter {
+  relay_id: String,
+  rules: Vec<RateLimit>,
+  buckets: Mutex<HashMap<BucketKey, Bucket>>,
+  evicted_at: Mutex<Instant>,
+}
+
+static RATE_LIMITERS: Lazy<Mutex<HashMap<String, Arc<RateLimiter>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/**
+ * Get the rate limiter of a relay, or `None` when no rate limit is configured
+ */
+pub fn rate_limiter(relay_cfg: &RelayConfig) -> Option<Arc<RateLimiter>> {
+  let rules = &relay_cfg.config.mqtt.rate_limits;
+  if rules.is_empty() {
+    return None;
+  }
+  let limiter = RATE_LIMITERS
+    .lock()
+    .unwrap()
+    .entry(relay_cfg.id.clone())
+    .or_insert_with(|| Arc::new(RateLimiter::new(&relay_cfg.id, rules.clone())))
+    .clone();
+  Some(limiter)
+}
+
+/**
+ * Device id captured by the first `+` wildcard of the filter
+ */
+fn captured_device_id(topic: &str, filter: &str) -> String {
+  filter
+    .split('/')
+    .zip(topic.split('/'))
+    .find(|(level, _)| *level == "+")
+    .map(|(_, device_id)| device_id.to_string())
+    .unwrap_or_else(|| topic.to_string())
+}
+
+fn burst(rule: &RateLimit) -> f64 {
+  rule.burst.unwrap_or(rule.rate).max(1.0)
+}
+
+impl Bucket {
+  fn refill(&mut self, rule: &RateLimit, now: Instant) {
+    let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * rule.rate).min(burst(rule));
+    self.updated_at = now;
+  }
+}
+
+impl RateLimiter {
+  pub fn new(relay_id: &str, rules: Vec<RateLimit>) -> Self {
+    RateLimiter {
+      relay_id: relay_id.to_string(),
+      rules,
+      buckets: Mutex::new(HashMap::new()),
+      evicted_at: Mutex::new(Instant::now()),
+    }
+  }
+
+  /**
+   * Remove buckets that refilled to their burst with no message waiting: they are the same as new ones,
+   * so topics or devices that stopped sending do not stay in memory
+   */
+  fn evict_idle(&self, now: Instant) {
+    self.buckets.lock().unwrap().retain(|(index, _), bucket| {
+      let rule = &self.rules[*index];
+      bucket.refill(rule, now);
+      bucket.latest.is_some() || bucket.tokens < burst(rule)
+    });
+    *self.evicted_at.lock().unwrap() = now;
+  }
+
+  fn count_limited(&self, action: &str) {
+    metrics::increment_counter(
+      "messages_rate_limited_total",
+      &[("relay_id", &self.relay_id), ("action", action)],
+    );
+  }
+
+  /**
+   * Apply every rule matching the topic, in order. The first one over its limit decides, and tokens are
+   * only taken once every rule lets the message through.
+   * A deferred message is kept with its Sparkplug `aliases` and receive time.
+   */
+  pub fn check(&self, publish: &Publish, aliases: &AliasNames, received_at: i64) -> Decision {
+    let now = Instant::now();
+    if now.duration_since(*self.evicted_at.lock().unwrap()) >= EVICT_INTERVAL {
+      self.evict_idle(now);
+    }
+    let mut buckets = self.buckets.lock().unwrap();
+
+    let mut allowed = Vec::new();
+    for (index, rule) in self.rules.iter().enumerate() {
+      let filter = rule.topic.as_deref().unwrap_or("#");
+      if !topic_matches(&publish.topic, filter) {
+        continue;
+      }
+
+      let key = match rule.scope.as_deref().unwrap_or("topic") {
+        "relay" => String::new(),
+        "device" => captured_device_id(&publish.topic, filter),
+        _ => publish.topic.clone(),
+      };
+      let bucket = buckets.entry((index, key.clone())).or_insert_with(|| Bucket {
+        tokens: burst(rule),
+        updated_at: now,
+        over_limit: 0,
+        latest: None,
+      });
+      bucket.refill(rule, now);
+
+      if bucket.tokens >= 1.0 {
+        allowed.push((index, key));
+        continue;
+      }
+
+      let action = rule.action.as_deref().unwrap_or("drop");
+      match action {
+        "sample" => {
+          bucket.over_limit += 1;
+          if bucket.over_limit.is_multiple_of(rule.sample_every.unwrap_or(10).max(1)) {
+            continue;
+          }
+        }
+        "latest" => {
+          let waiting = bucket
+            .latest
+            .replace((publish.clone(), aliases.clone(), received_at))
+            .is_some();
+          if !waiting {
+            let delay = Duration::try_from_secs_f64((1.0 - bucket.tokens) / rule.rate)
+              .unwrap_or(MAX_DEFER)
+              .min(MAX_DEFER);
+            return Decision::Defer((index, key), delay);
+          }
+        }
+        _ => {}
+      }
+      self.count_limited(action);
+      return Decision::Skip;
+    }
+
+    for key in allowed {
+      if let Some(bucket) = buckets.get_mut(&key) {
+        bucket.tokens -= 1.0;
+        // A newer message supersedes the one waiting for the end of the interval
+        bucket.latest = None;
+      }
+    }
+    Decision::Forward
+  }
+
+  /**
+   * The latest message kept for a bucket, with its aliases and receive time, once the interval is over
+   */
+  pub fn take_latest(&self, key: &BucketKey) -> Option<(Publish, AliasNames, i64)> {
+    let mut buckets = self.buckets.lock().unwrap();
+    let bucket = buckets.get_mut(key)?;
+    let latest = bucket.latest.take()?;
+    bucket.refill(&self.rules[key.0], Instant::now());
+    bucket.tokens = (bucket.tokens - 1.0).max(0.0);
+    Some(latest)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rumqttc::QoS;
+
+  fn publish(topic: &str, payload: &str) -> Publish {
+    Publish::new(topic, QoS::AtMostOnce, payload)
+  }
+
+  #[test]
+  fn test_drop_per_device() {
+    let limiter = RateLimiter::new(
+      "test_rate_drop",
+      vec![RateLimit {
+        topic: Some("devices/+/data".to_string()),
+        scope: Some("device".to_string()),
+        rate: 0.001,
+        burst: Some(2.0),
+        ..Default::default()
+      }],
+    );
+
+    assert_eq!(
+      limiter.check(&publish("devices/a/data", "1"), &AliasNames::new(), 0),
+      Decision::Forward
+    );
+    assert_eq!(
+      limiter.check(&publish("devices/a/data", "2"), &AliasNames::new(), 0),
+      Decision::Forward
+    );
+    assert_eq!(
+      limiter.check(&publish("devices/a/data", "3"), &AliasNames::new(), 0),
+      Decision::Skip
+    );
+    assert_eq!(
+      limiter.check(&publish("devices/b/data", "1"), &AliasNames::new(), 0),
+      Decision::Forward
+    );
+    assert_eq!(
+      limiter.check(&publish("other/a", "1"), &AliasNames::new(), 0),
+      Decision::Forward
+    );
+  }
+
+  #[test]
+  fn test_rejected_message_takes_no_token() {
+    let limiter = RateLimiter::new(
+      "test_rate_two_rules",
+      vec![
+        RateLimit {
+          scope: Some("relay".to_string()),
+          rate: 0.001,
+          burst: Some(3.0),
+          ..Default::default()
+        },
+        RateLimit {
+          topic: Some("devices/a".to_string()),
+          rate: 0.001,
+          burst: Some(1.0),
+          ..Default::default()
+        },
+      ],
+    );
+
+    let check = |topic: &str| limiter.check(&publish(topic, "1"), &AliasNames::new(), 0);
+    assert_eq!(check("devices/a"), Decision::Forward);
+    // Over the limit of the second rule: the relay-wide bucket keeps its token
+    assert_eq!(check("devices/a"), Decision::Skip);
+    assert_eq!(check("devices/a"), Decision::Skip);
+    assert_eq!(check("devices/b"), Decision::Forward);
+    assert_eq!(check("devices/b"), Decision::Forward);
+    assert_eq!(check("devices/b"), Decision::Skip);
+  }
+
+  #[test]
+  fn test_sample_every_nth() {
+    let limiter = RateLimiter::new(
+      "test_rate_sample",
+      vec![RateLimit {
+        rate: 0.001,
+        action: Some("sample".to_string()),
+        sample_every: Some(3),
+        ..Default::default()
+      }],
+    );
+
+    let decisions: Vec<Decision> = (0..7)
+      .map(|_| limiter.check(&publish("sensors/1", "1"), &AliasNames::new(), 0))
+      .collect();
+    let forwarded = decisions.iter().filter(|d| **d == Decision::Forward).count();
+    assert_eq!(forwarded, 3); // burst of 1, then the 3rd and 6th over the limit
+  }
+
+  #[test]
+  fn test_keep_latest() {
+    let limiter = RateLimiter::new(
+      "test_rate_latest",
+      vec![RateLimit {
+        scope: Some("relay".to_string()),
+        rate: 0.001,
+        action: Some("latest".to_string()),
+        ..Default::default()
+      }],
+    );
+
+    assert_eq!(
+      limiter.check(&publish("sensors/1", "1"), &AliasNames::new(), 0),
+      Decision::Forward
+    );
+    let Decision::Defer(key, _) = limiter.check(&publish("sensors/1", "2"), &AliasNames::new(), 0) else {
+      panic!("expected the message to be deferred");
+    };
+    assert_eq!(
+      limiter.check(&publish("sensors/2", "3"), &AliasNames::new(), 0),
+      Decision::Skip
+    );
+
+    let (latest, _, _) = limiter.take_latest(&key).unwrap();
+    assert_eq!(latest.payload.as_ref(), b"3");
+    assert!(limiter.take_latest(&key).is_none());
+  }
+
+  #[test]
+  fn test_evict_idle_buckets() {
+    let limiter = RateLimiter::new(
+      "test_rate_evict",
+      vec![RateLimit {
+        scope: Some("device".to_string()),
+        topic: Some("devices/+/data".to_string()),
+        rate: 1.0,
+        action: Some("latest".to_string()),
+        ..Default::default()
+      }],
+    );
+
+    assert_eq!(
+      limiter.check(&publish("devices/a/data", "1"), &AliasNames::new(), 0),
+      Decision::Forward
+    );
+    assert_eq!(
+      limiter.check(&publish("devices/b/data", "1"), &AliasNames::new(), 0),
+      Decision::Forward
+    );
+    let Decision::Defer(_, delay) = limiter.check(&publish("devices/b/data", "2"), &AliasNames::new(), 0) else {
+      panic!("expected the message to be deferred");
+    };
+    assert!(delay <= Duration::from_secs(1));
+
+    // `a` refilled and is removed, `b` still has a message waiting
+    limiter.evict_idle(Instant::now() + Duration::from_secs(5));
+    let buckets = limiter.buckets.lock().unwrap();
+    assert_eq!(buckets.len(), 1);
+    assert!(buckets.contains_key(&(0, "b".to_string())));
+  }
+}
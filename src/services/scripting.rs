@@ -0,0 +1,241 @@
+use crate::{
+  schema::{RelayConfig, ScriptLimits},
+  services::{
+    metrics,
+    payload::{merge_defaults, FileCache},
+  },
+};
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use rhai::{Array, Dynamic, Engine, Scope, AST};
+use rumqttc::Publish;
+use serde_json::Value;
+use std::{
+  cell::{Cell, RefCell},
+  collections::HashMap,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+static SCRIPTS: Lazy<FileCache<Arc<AST>>> = Lazy::new(FileCache::default);
+
+/**
+ * Limits an engine is built with: operations, string, array and map sizes
+ */
+type EngineLimits = (u64, usize, usize, usize);
+
+static ENGINES: Lazy<Mutex<HashMap<EngineLimits, Arc<Engine>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+thread_local! {
+  /**
+   * Deadline of the script running on this thread. Engines are shared, so each run tags its thread instead.
+   */
+  static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+
+  /**
+   * Relay id and topic of the script running on this thread, for the logs of `print` and `debug`
+   */
+  static RUN: RefCell<(String, String)> = const { RefCell::new((String::new(), String::new())) };
+}
+
+/**
+ * Engine with the configured limits, built once per set of limits. Runs are stopped once the
+ * deadline of their thread has passed. `print` and `debug` go to the log instead of stdout.
+ */
+fn engine(limits: &ScriptLimits) -> Arc<Engine> {
+  let key = (
+    limits.max_operations.unwrap_or(1_000_000),
+    limits.max_string_size.unwrap_or(1024 * 1024),
+    limits.max_array_size.unwrap_or(10_000),
+    limits.max_map_size.unwrap_or(10_000),
+  );
+  ENGINES
+    .lock()
+    .unwrap()
+    .entry(key)
+    .or_insert_with(|| {
+      let mut engine = Engine::new();
+      engine.set_max_operations(key.0);
+      engine.set_max_string_size(key.1);
+      engine.set_max_array_size(key.2);
+      engine.set_max_map_size(key.3);
+      engine.set_max_call_levels(32);
+      engine.on_progress(|_| {
+        DEADLINE
+          .get()
+          .is_some_and(|deadline| Instant::now() > deadline)
+          .then(|| Dynamic::from("timeout"))
+      });
+      engine.on_print(|text| {
+        RUN.with_borrow(|(relay_id, topic)| {
+          log::info!(target: "mqtt", relay_id = relay_id.as_str(), topic = topic.as_str(); "[Script] {}", text)
+        })
+      });
+      engine.on_debug(|text, _, position| {
+        RUN.with_borrow(|(relay_id, topic)| {
+          log::debug!(target: "mqtt", relay_id = relay_id.as_str(), topic = topic.as_str(); "[Script] {} at {}", text, position)
+        })
+      });
+      Arc::new(engine)
+    })
+    .clone()
+}
+
+/**
+ * Compile a script file, reusing the cached AST while the file is unchanged
+ */
+pub fn compile(path: &str) -> anyhow::Result<Arc<AST>> {
+  SCRIPTS.get_or_load(path, || {
+    let source = std::fs::read_to_string(path).with_context(|| format!("Failed to read script {}", path))?;
+    let ast = engine(&ScriptLimits::default())
+      .compile(source)
+      .map_err(|e| anyhow::anyhow!("Failed to compile script {}: {}", path, e))?;
+    Ok(Arc::new(ast))
+  })
+}
+
+/**
+ * Run the script with `topic`, `payload` (bytes), `payload_text`, `metadata` and the
+ * default `records` in scope. It returns the records to send: an array of maps, one map or `()`.
+ */
+fn evaluate(
+  ast: &AST,
+  limits: &ScriptLimits,
+  relay_id: &str,
+  publish: &Publish,
+  records: &[Value],
+) -> anyhow::Result<Vec<Value>> {
+  let timeout = Duration::from_millis(limits.timeout_ms.unwrap_or(100));
+  let engine = engine(limits);
+
+  let metadata = records
+    .first()
+    .map(|record| record["metadata"].clone())
+    .unwrap_or_default();
+  let mut scope = Scope::new();
+  scope.push("topic", publish.topic.clone());
+  scope.push("payload", Dynamic::from_blob(publish.payload.to_vec()));
+  scope.push("payload_text", String::from_utf8_lossy(&publish.payload).to_string());
+  scope.push(
+    "metadata",
+    rhai::serde::to_dynamic(&metadata).map_err(|e| anyhow::anyhow!("{}", e))?,
+  );
+  scope.push(
+    "records",
+    rhai::serde::to_dynamic(records).map_err(|e| anyhow::anyhow!("{}", e))?,
+  );
+
+  RUN.set((relay_id.to_string(), publish.topic.clone()));
+  DEADLINE.set(Some(Instant::now() + timeout));
+  let result = engine.eval_ast_with_scope::<Dynamic>(&mut scope, ast);
+  DEADLINE.set(None);
+  let result = result.map_err(|e| match *e {
+    rhai::EvalAltResult::ErrorTerminated(..) => anyhow::anyhow!("timed out after {:?}", timeout),
+    e => anyhow::anyhow!("{}", e),
+  })?;
+
+  let result = if result.is_unit() {
+    Array::new()
+  } else if result.is_map() {
+    vec![result]
+  } else {
+    result
+      .try_cast::<Array>()
+      .context("the script must return an array of records, a record or ()")?
+  };
+
+  result
+    .iter()
+    .map(|record| {
+      let record: Value = rhai::serde::from_dynamic(record).map_err(|e| anyhow::anyhow!("{}", e))?;
+      if record.get("variable").and_then(Value::as_str).is_none() {
+        anyhow::bail!("every record needs a `variable` string, got {}", record);
+      }
+      Ok(record)
+    })
+    .collect()
+}
+
+/**
+ * Replace the default records of an uplink with the ones built by the subscription script.
+ * The script runs on the blocking pool. On error the default records are kept.
+ */
+pub async fn transform(relay_cfg: &RelayConfig, path: &str, publish: &Publish, records: Vec<Value>) -> Vec<Value> {
+  let limits = relay_cfg.config.mqtt.script_limits.clone();
+  let (relay_id, publish_copy, defaults) = (relay_cfg.id.clone(), publish.clone(), records.clone());
+  let result = match compile(path) {
+    Ok(ast) => tokio::task::spawn_blocking(move || evaluate(&ast, &limits, &relay_id, &publish_copy, &defaults))
+      .await
+      .unwrap_or_else(|e| Err(anyhow::anyhow!("{}", e))),
+    Err(e) => Err(e),
+  };
+
+  match result {
+    Ok(scripted) => merge_defaults(scripted, &records),
+    Err(e) => {
+      log::error!(target: "mqtt", relay_id = relay_cfg.id.as_str(), topic = publish.topic.as_str(), error:% = format!("{:#}", e); "Script {} failed on topic {}: {:#}", path, publish.topic, e);
+      metrics::increment_counter("script_errors_total", &[("relay_id", &relay_cfg.id), ("script", path)]);
+      records
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rumqttc::QoS;
+  use serde_json::json;
+
+  fn evaluate_source(source: &str, limits: &ScriptLimits, payload: &str) -> anyhow::Result<Vec<Value>> {
+    let ast = engine(limits).compile(source).unwrap();
+    let publish = Publish::new("sensors/1", QoS::AtMostOnce, payload);
+    let records = vec![json!({"variable": "payload", "value": payload, "metadata": {"topic": "sensors/1"}})];
+    evaluate(&ast, limits, "test_script", &publish, &records)
+  }
+
+  #[test]
+  fn test_script_records() {
+    let source = r#"
+      let data = parse_json(payload_text);
+      [
+        #{ variable: "temperature", value: data.t / 10.0, unit: "C" },
+        #{ variable: "first_byte", value: payload[0], metadata: #{ topic: topic } },
+      ]
+    "#;
+    let records = evaluate_source(source, &ScriptLimits::default(), r#"{"t":215}"#).unwrap();
+    assert_eq!(
+      records[0],
+      json!({"variable": "temperature", "value": 21.5, "unit": "C"})
+    );
+    assert_eq!(records[1]["value"], 123);
+    assert_eq!(records[1]["metadata"]["topic"], "sensors/1");
+
+    assert!(evaluate_source("()", &ScriptLimits::default(), "x").unwrap().is_empty());
+    assert!(evaluate_source(r#"print("hello"); debug(topic); ()"#, &ScriptLimits::default(), "x").is_ok());
+    assert!(evaluate_source("#{ value: 1 }", &ScriptLimits::default(), "x").is_err());
+  }
+
+  #[test]
+  fn test_script_limits() {
+    let limits = ScriptLimits {
+      timeout_ms: Some(10),
+      ..Default::default()
+    };
+    let error = evaluate_source("loop {}", &limits, "x").unwrap_err();
+    assert!(error.to_string().contains("timed out"), "{}", error);
+    // The timeout is per run, so it does not need an engine of its own
+    assert!(Arc::ptr_eq(&engine(&limits), &engine(&ScriptLimits::default())));
+    assert!(evaluate_source(
+      "let x = 0; for i in 0..1000 { x += i; } []",
+      &ScriptLimits::default(),
+      "x"
+    )
+    .is_ok());
+
+    let limits = ScriptLimits {
+      max_operations: Some(1000),
+      ..Default::default()
+    };
+    assert!(evaluate_source("let x = 0; loop { x += 1; }", &limits, "x").is_err());
+  }
+}
@@ -0,0 +1,506 @@
+use crate::{
+  schema::{Forwarding, RelayConfig, Sink as SinkConfig},
+  services::{
+    metrics,
+    tagoio::{forward_to_tagoio, make_request, retry_backoff, send_sink_data, should_retry, Delivery},
+  },
+};
+use anyhow::Context;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::{
+  collections::HashMap,
+  fs::{self, File, OpenOptions},
+  future::Future,
+  io::{self, Write},
+  path::{Path, PathBuf},
+  pin::Pin,
+  sync::{Arc, Mutex},
+};
+use tokio::{
+  sync::mpsc::{self, error::TrySendError},
+  task::JoinHandle,
+  time::sleep,
+};
+
+/**
+ * Name of the relay's own TagoIO destination, the one routes use when they list no sinks
+ */
+pub const TAGOIO_SINK: &str = "tagoio";
+
+const DEFAULT_BODY_TEMPLATE: &str = r#"{"topic": {{topic}}, "relay_id": {{relay_id}}, "records": {{records}}}"#;
+
+pub type SinkFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<Delivery>> + Send + 'a>>;
+
+/**
+ * Destination of the data records of a topic, retrying on its own
+ */
+pub trait Sink: Send + Sync {
+  fn send<'a>(&'a self, relay_cfg: &'a RelayConfig, topic: String, records: Vec<Value>) -> SinkFuture<'a>;
+}
+
+/**
+ * Sinks of a relay, and the tasks sending the queues of its configured sinks
+ */
+struct RelaySinks {
+  sinks: Arc<HashMap<String, Arc<dyn Sink>>>,
+  workers: Vec<JoinHandle<()>>,
+}
+
+static SINKS: Lazy<Mutex<HashMap<String, RelaySinks>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/**
+ * Get a sink of a relay by name: `tagoio` or one of the configured sinks.
+ * The configured sinks are created on first use and again by `rebuild`.
+ */
+pub fn sink(relay_cfg: &RelayConfig, name: &str) -> Option<Arc<dyn Sink>> {
+  let sinks = SINKS
+    .lock()
+    .unwrap()
+    .entry(relay_cfg.id.clone())
+    .or_insert_with(|| build_all(relay_cfg))
+    .sinks
+    .clone();
+  sinks.get(name).cloned()
+}
+
+/**
+ * Create the sinks of a relay again from its configuration, e.g. when the relay restarts.
+ * The queues of the previous sinks are still sent, then their tasks end.
+ */
+pub fn rebuild(relay_cfg: &RelayConfig) {
+  let sinks = build_all(relay_cfg);
+  SINKS.lock().unwrap().insert(relay_cfg.id.clone(), sinks);
+}
+
+/**
+ * Remove the sinks of a relay and wait until their queues are sent, e.g. before the process exits
+ */
+pub async fn close(relay_cfg: &RelayConfig) {
+  let removed = SINKS.lock().unwrap().remove(&relay_cfg.id);
+  if let Some(RelaySinks { sinks, workers }) = removed {
+    drop(sinks);
+    for worker in workers {
+      let _ = worker.await;
+    }
+  }
+}
+
+fn build_all(relay_cfg: &RelayConfig) -> RelaySinks {
+  let snapshot = Arc::new(relay_cfg.clone());
+  let mut sinks: HashMap<String, Arc<dyn Sink>> = HashMap::new();
+  let mut workers = Vec::new();
+  for config in &relay_cfg.config.sinks {
+    let (sink, worker) = queued(&snapshot, config, build(config));
+    sinks.insert(config.name.clone(), sink);
+    workers.push(worker);
+  }
+  sinks.insert(TAGOIO_SINK.to_string(), Arc::new(RouteSink));
+  RelaySinks {
+    sinks: Arc::new(sinks),
+    workers,
+  }
+}
+
+fn build(config: &SinkConfig) -> Arc<dyn Sink> {
+  match config.kind.as_str() {
+    "webhook" => Arc::new(WebhookSink {
+      config: config.clone(),
+      // Both are checked with the configuration
+      method: webhook_method(config).unwrap_or(reqwest::Method::POST),
+      headers: Mutex::new(webhook_headers(config).unwrap_or_default()),
+    }),
+    "file" => Arc::new(FileSink {
+      file: Arc::new(RotatingFile::new(
+        config.path.as_deref().unwrap_or_default(),
+        config.max_bytes.unwrap_or(10 * 1024 * 1024),
+        config.max_files.unwrap_or(5),
+      )),
+    }),
+    _ => Arc::new(TagoIOSink { config: config.clone() }),
+  }
+}
+
+/**
+ * Retries of a configured sink
+ */
+fn forwarding<'a>(relay_cfg: &'a RelayConfig, config: &'a SinkConfig) -> &'a Forwarding {
+  config.forwarding.as_ref().unwrap_or(&relay_cfg.config.forwarding)
+}
+
+/**
+ * The relay's own TagoIO destination: the route matching the topic, or the relay tokens
+ */
+struct RouteSink;
+
+impl Sink for RouteSink {
+  fn send<'a>(&'a self, relay_cfg: &'a RelayConfig, topic: String, records: Vec<Value>) -> SinkFuture<'a> {
+    Box::pin(async move {
+      forward_to_tagoio(relay_cfg, &topic, records)
+        .await
+        .map_err(|e| anyhow::anyhow!("{}", e))
+    })
+  }
+}
+
+/**
+ * Configured sink behind a bounded queue, sent from its own task so a slow or failing destination
+ * does not hold up the uplinks. Records that do not fit in the queue are dropped and counted.
+ */
+struct QueuedSink {
+  name: String,
+  sender: mpsc::Sender<(String, Vec<Value>)>,
+}
+
+fn queued(relay_cfg: &Arc<RelayConfig>, config: &SinkConfig, sink: Arc<dyn Sink>) -> (Arc<dyn Sink>, JoinHandle<()>) {
+  let (sender, mut receiver) = mpsc::channel::<(String, Vec<Value>)>(config.queue_size.unwrap_or(1000).max(1));
+  let relay_cfg = relay_cfg.clone();
+  let name = config.name.clone();
+  let worker = tokio::spawn(async move {
+    while let Some((topic, records)) = receiver.recv().await {
+      if let Err(e) = sink.send(&relay_cfg, topic.clone(), records).await {
+        log::error!(target: "network", relay_id = relay_cfg.id.as_str(), topic = topic.as_str(), error:% = format!("{:#}", e); "Sink {} failed for topic {}: {:#}", name, topic, e);
+        metrics::increment_counter("sink_errors_total", &[("relay_id", &relay_cfg.id), ("sink", &name)]);
+      }
+    }
+  });
+  let sink = Arc::new(QueuedSink {
+    name: config.name.clone(),
+    sender,
+  });
+  (sink, worker)
+}
+
+impl Sink for QueuedSink {
+  fn send<'a>(&'a self, relay_cfg: &'a RelayConfig, topic: String, records: Vec<Value>) -> SinkFuture<'a> {
+    Box::pin(async move {
+      if let Err(TrySendError::Full((topic, _))) = self.sender.try_send((topic, records)) {
+        log::warn!(target: "network", relay_id = relay_cfg.id.as_str(), topic = topic.as_str(); "Sink {} is behind: records of topic {} dropped", self.name, topic);
+        metrics::increment_counter(
+          "sink_dropped_total",
+          &[("relay_id", &relay_cfg.id), ("sink", &self.name)],
+        );
+        return Ok(Delivery::Dropped);
+      }
+      Ok(Delivery::Delivered)
+    })
+  }
+}
+
+/**
+ * Network data endpoint of another TagoIO region or network, e.g. while migrating.
+ * It has its own circuit breaker so an outage there does not hold up the primary region.
+ */
+struct TagoIOSink {
+  config: SinkConfig,
+}
+
+impl Sink for TagoIOSink {
+  fn send<'a>(&'a self, relay_cfg: &'a RelayConfig, _topic: String, records: Vec<Value>) -> SinkFuture<'a> {
+    Box::pin(async move {
+      send_sink_data(relay_cfg, forwarding(relay_cfg, &self.config), &self.config, records)
+        .await
+        .map_err(|e| anyhow::anyhow!("{}", e))
+    })
+  }
+}
+
+/**
+ * Generic HTTP endpoint. The body is `body_template` with `{{topic}}`, `{{relay_id}}` and
+ * `{{records}}` replaced by their JSON values; the result must be JSON.
+ */
+struct WebhookSink {
+  config: SinkConfig,
+  method: reqwest::Method,
+  headers: Mutex<HeaderMap>,
+}
+
+/**
+ * HTTP method of a webhook sink, POST by default
+ */
+pub(crate) fn webhook_method(config: &SinkConfig) -> anyhow::Result<reqwest::Method> {
+  let method = config.method.as_deref().unwrap_or("POST");
+  reqwest::Method::from_bytes(method.as_bytes())
+    .with_context(|| format!("webhook sink \"{}\" has an invalid method \"{}\"", config.name, method))
+}
+
+/**
+ * Headers of a webhook sink, with the current values of their secrets
+ */
+pub(crate) fn webhook_headers(config: &SinkConfig) -> anyhow::Result<HeaderMap> {
+  let mut headers = HeaderMap::new();
+  for (name, value) in &config.headers {
+    let header = HeaderName::from_bytes(name.as_bytes()).with_context(|| {
+      format!(
+        "webhook sink \"{}\" has an invalid header name \"{}\"",
+        config.name, name
+      )
+    })?;
+    let mut value = HeaderValue::from_str(&value.expose()).with_context(|| {
+      format!(
+        "webhook sink \"{}\" has an invalid value for header \"{}\"",
+        config.name, name
+      )
+    })?;
+    value.set_sensitive(true);
+    headers.insert(header, value);
+  }
+  Ok(headers)
+}
+
+impl WebhookSink {
+  /**
+   * Headers built with the sink, built again only once a secret header value was reloaded
+   */
+  fn headers(&self) -> anyhow::Result<HeaderMap> {
+    let mut headers = self.headers.lock().unwrap();
+    let current = self.config.headers.iter().all(|(name, value)| {
+      headers
+        .get(name.as_str())
+        .is_some_and(|header| header.as_bytes() == value.expose().as_bytes())
+    });
+    if !current {
+      *headers = webhook_headers(&self.config)?;
+    }
+    Ok(headers.clone())
+  }
+}
+
+/**
+ * Fill in the placeholders of a body template in a single pass, so placeholders inside
+ * the substituted values (e.g. a topic containing `{{records}}`) are left alone
+ */
+fn render_body(template: &str, relay_id: &str, topic: &str, records: &[Value]) -> anyhow::Result<Value> {
+  let mut body = String::with_capacity(template.len());
+  let mut rest = template;
+  while let Some(start) = rest.find("{{") {
+    body.push_str(&rest[..start]);
+    let after = &rest[start + 2..];
+    let placeholder = after.find("}}").map(|end| (&after[..end], &after[end + 2..]));
+    let value = match placeholder {
+      Some(("topic", _)) => Some(Value::from(topic).to_string()),
+      Some(("relay_id", _)) => Some(Value::from(relay_id).to_string()),
+      Some(("records", _)) => Some(serde_json::to_string(records)?),
+      _ => None,
+    };
+    match (value, placeholder) {
+      (Some(value), Some((_, remaining))) => {
+        body.push_str(&value);
+        rest = remaining;
+      }
+      _ => {
+        body.push_str("{{");
+        rest = after;
+      }
+    }
+  }
+  body.push_str(rest);
+  serde_json::from_str(&body).context("the rendered body_template is not valid JSON")
+}
+
+impl Sink for WebhookSink {
+  fn send<'a>(&'a self, relay_cfg: &'a RelayConfig, topic: String, records: Vec<Value>) -> SinkFuture<'a> {
+    Box::pin(async move {
+      let forwarding = forwarding(relay_cfg, &self.config);
+      let template = self.config.body_template.as_deref().unwrap_or(DEFAULT_BODY_TEMPLATE);
+      let body = render_body(template, &relay_cfg.id, &topic, &records)?;
+      let url = self.config.url.as_deref().unwrap_or_default();
+      let headers = self.headers()?;
+
+      let max_retries = forwarding.max_retries.unwrap_or(5);
+      let mut attempt = 0;
+      loop {
+        match make_request(
+          &relay_cfg.http_client,
+          self.method.clone(),
+          url,
+          headers.clone(),
+          Some(body.clone()),
+        )
+        .await
+        {
+          Ok(_) => return Ok(Delivery::Delivered),
+          Err(e) if should_retry(forwarding, e.status) && attempt < max_retries => {
+            attempt += 1;
+            sleep(retry_backoff(forwarding, attempt)).await;
+          }
+          Err(e) => return Err(anyhow::anyhow!("{}", e)),
+        }
+      }
+    })
+  }
+}
+
+/**
+ * Local JSON Lines archive, one line per record with its topic
+ */
+struct FileSink {
+  file: Arc<RotatingFile>,
+}
+
+impl Sink for FileSink {
+  fn send<'a>(&'a self, relay_cfg: &'a RelayConfig, topic: String, records: Vec<Value>) -> SinkFuture<'a> {
+    Box::pin(async move {
+      let forwarding = &relay_cfg.config.forwarding;
+      let lines: Vec<String> = records
+        .iter()
+        .map(|record| serde_json::json!({ "topic": topic, "record": record }).to_string())
+        .collect();
+
+      let max_retries = forwarding.max_retries.unwrap_or(5);
+      let mut attempt = 0;
+      loop {
+        let file = self.file.clone();
+        let lines = lines.clone();
+        match tokio::task::spawn_blocking(move || file.append(&lines)).await? {
+          Ok(()) => return Ok(Delivery::Delivered),
+          Err(_) if attempt < max_retries => {
+            attempt += 1;
+            sleep(retry_backoff(forwarding, attempt)).await;
+          }
+          Err(e) => return Err(e).with_context(|| format!("Failed to write {}", self.file.path.display())),
+        }
+      }
+    })
+  }
+}
+
+/**
+ * Append-only text file that is rotated once it would grow past `max_bytes`:
+ * `path` becomes `path.1`, `path.1` becomes `path.2` and so on, keeping `max_files` old files.
+ */
+pub struct RotatingFile {
+  path: PathBuf,
+  max_bytes: u64,
+  max_files: usize,
+  lock: Mutex<()>,
+}
+
+impl RotatingFile {
+  pub fn new(path: impl AsRef<Path>, max_bytes: u64, max_files: usize) -> Self {
+    RotatingFile {
+      path: path.as_ref().to_path_buf(),
+      max_bytes,
+      max_files,
+      lock: Mutex::new(()),
+    }
+  }
+
+  fn rotated(&self, index: usize) -> PathBuf {
+    let mut path = self.path.clone().into_os_string();
+    path.push(format!(".{}", index));
+    path.into()
+  }
+
+  /**
+   * The current file followed by the rotated ones, newest first
+   */
+  pub fn files(&self) -> Vec<PathBuf> {
+    std::iter::once(self.path.clone())
+      .chain((1..=self.max_files).map(|index| self.rotated(index)))
+      .filter(|path| path.exists())
+      .collect()
+  }
+
+  fn rotate(&self) -> io::Result<()> {
+    if self.max_files == 0 {
+      return fs::remove_file(&self.path);
+    }
+    for index in (1..self.max_files).rev() {
+      let from = self.rotated(index);
+      if from.exists() {
+        fs::rename(from, self.rotated(index + 1))?;
+      }
+    }
+    fs::rename(&self.path, self.rotated(1))
+  }
+
+  /**
+   * Append lines, all of them to the same file
+   */
+  pub fn append(&self, lines: &[String]) -> io::Result<()> {
+    let _guard = self.lock.lock().unwrap();
+    let text: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+
+    let size = fs::metadata(&self.path).map_or(0, |metadata| metadata.len());
+    if size > 0 && size + text.len() as u64 > self.max_bytes {
+      self.rotate()?;
+    }
+    if let Some(parent) = self.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+      fs::create_dir_all(parent)?;
+    }
+    let mut file: File = OpenOptions::new().create(true).append(true).open(&self.path)?;
+    file.write_all(text.as_bytes())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_rotating_file() {
+    let dir = std::env::temp_dir().join(format!("tagoio-relay-sinks-{}", std::process::id()));
+    let file = RotatingFile::new(dir.join("uplinks.jsonl"), 10, 2);
+    for line in ["first", "second", "third", "fourth"] {
+      file.append(&[line.to_string()]).unwrap();
+    }
+
+    let contents: Vec<String> = file
+      .files()
+      .iter()
+      .map(|path| fs::read_to_string(path).unwrap())
+      .collect();
+    assert_eq!(contents, vec!["fourth\n", "third\n", "second\n"]);
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn test_webhook_request_options() {
+    let mut config = SinkConfig {
+      name: "hook".to_string(),
+      kind: "webhook".to_string(),
+      headers: HashMap::from([("X-Api-Key".to_string(), "secret".into())]),
+      ..Default::default()
+    };
+    assert_eq!(webhook_method(&config).unwrap(), reqwest::Method::POST);
+    assert_eq!(webhook_headers(&config).unwrap()["x-api-key"], "secret");
+
+    config.method = Some("NOT A METHOD".to_string());
+    assert!(webhook_method(&config).is_err());
+    config.headers = HashMap::from([("Bad Header".to_string(), "secret".into())]);
+    assert!(webhook_headers(&config).is_err());
+    config.headers = HashMap::from([("X-Api-Key".to_string(), "line\nbreak".into())]);
+    assert!(webhook_headers(&config).is_err());
+  }
+
+  #[test]
+  fn test_render_body() {
+    let records = vec![json!({"variable": "temperature", "value": 21.5})];
+    let body = render_body(DEFAULT_BODY_TEMPLATE, "test_id", "sensors/\"1\"", &records).unwrap();
+    assert_eq!(body["topic"], "sensors/\"1\"");
+
+    // Placeholders inside values are not replaced
+    let body = render_body(DEFAULT_BODY_TEMPLATE, "{{topic}}", "sensors/{{records}}", &records).unwrap();
+    assert_eq!(
+      body,
+      json!({"topic": "sensors/{{records}}", "relay_id": "{{topic}}", "records": records})
+    );
+
+    let body = render_body(
+      r#"{"data": {{records}}, "source": "relay"}"#,
+      "test_id",
+      "sensors/1",
+      &records,
+    )
+    .unwrap();
+    assert_eq!(body["data"][0]["value"], 21.5);
+    assert!(render_body("{{records", "test_id", "sensors/1", &records).is_err());
+    assert_eq!(
+      render_body(r#"{"{{unknown}}": {{records}}}"#, "test_id", "sensors/1", &records).unwrap(),
+      json!({"{{unknown}}": records})
+    );
+  }
+}
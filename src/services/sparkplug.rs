@@ -0,0 +1,586 @@
+use crate::{
+  schema::{RecordMetadata, RelayConfig, Subscription},
+  services::payload,
+};
+use once_cell::sync::Lazy;
+use prost::Message;
+use rumqttc::{Publish, QoS};
+use serde_json::{json, Value};
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+};
+
+/**
+ * Sparkplug B payload, as defined by `sparkplug_b.proto`. Metadata, properties,
+ * datasets and templates are not decoded.
+ */
+#[derive(Clone, PartialEq, Message)]
+pub struct Payload {
+  #[prost(uint64, optional, tag = "1")]
+  pub timestamp: Option<u64>,
+  #[prost(message, repeated, tag = "2")]
+  pub metrics: Vec<Metric>,
+  #[prost(uint64, optional, tag = "3")]
+  pub seq: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Metric {
+  #[prost(string, optional, tag = "1")]
+  pub name: Option<String>,
+  #[prost(uint64, optional, tag = "2")]
+  pub alias: Option<u64>,
+  #[prost(uint64, optional, tag = "3")]
+  pub timestamp: Option<u64>,
+  #[prost(uint32, optional, tag = "4")]
+  pub datatype: Option<u32>,
+  #[prost(bool, optional, tag = "7")]
+  pub is_null: Option<bool>,
+  #[prost(oneof = "MetricValue", tags = "10, 11, 12, 13, 14, 15, 16")]
+  pub value: Option<MetricValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum MetricValue {
+  #[prost(uint32, tag = "10")]
+  Int(u32),
+  #[prost(uint64, tag = "11")]
+  Long(u64),
+  #[prost(float, tag = "12")]
+  Float(f32),
+  #[prost(double, tag = "13")]
+  Double(f64),
+  #[prost(bool, tag = "14")]
+  Boolean(bool),
+  #[prost(string, tag = "15")]
+  String(String),
+  #[prost(bytes = "vec", tag = "16")]
+  Bytes(Vec<u8>),
+}
+
+/**
+ * Parts of a `spBv1.0/{group_id}/{message_type}/{edge_node_id}[/{device_id}]` topic
+ */
+#[derive(Debug, PartialEq)]
+struct SparkplugTopic<'a> {
+  group_id: &'a str,
+  message_type: &'a str,
+  edge_node_id: &'a str,
+  device_id: Option<&'a str>,
+}
+
+fn parse_topic(topic: &str) -> Option<SparkplugTopic<'_>> {
+  let mut levels = topic.split('/');
+  if levels.next()? != "spBv1.0" {
+    return None;
+  }
+  let (group_id, message_type, edge_node_id) = (levels.next()?, levels.next()?, levels.next()?);
+  let device_id = levels.next();
+  if levels.next().is_some() {
+    return None;
+  }
+  let is_node_message = matches!(message_type, "NBIRTH" | "NDEATH" | "NDATA");
+  let is_device_message = matches!(message_type, "DBIRTH" | "DDEATH" | "DDATA");
+  if !(is_node_message && device_id.is_none() || is_device_message && device_id.is_some()) {
+    return None;
+  }
+  Some(SparkplugTopic {
+    group_id,
+    message_type,
+    edge_node_id,
+    device_id,
+  })
+}
+
+/**
+ * Names and data types of metric aliases
+ */
+pub type AliasNames = HashMap<u64, (String, Option<u32>)>;
+
+/**
+ * What the relay knows about an edge node: aliases are shared by the node and its devices
+ */
+#[derive(Default)]
+struct EdgeNode {
+  online: bool,
+  bd_seq: Option<u64>,
+  aliases: AliasNames,
+  devices: HashMap<String, bool>,
+  rebirth_requested: bool,
+}
+
+/**
+ * Outcome of tracking a message
+ */
+#[derive(Debug, Default)]
+pub struct Tracked {
+  pub drop: Option<&'static str>, // Why the message should be dropped: a death certificate older than the last birth
+  pub aliases: AliasNames,        // Names of the aliases the message uses, to decode it later
+  pub rebirth: Option<Publish>,   // NCMD asking the edge node for a rebirth, for aliases without a birth certificate
+}
+
+/**
+ * Sparkplug host state of a relay: metric aliases and online state of every edge node and device
+ */
+pub struct SparkplugHost {
+  nodes: Mutex<HashMap<(String, String), EdgeNode>>,
+}
+
+static HOSTS: Lazy<Mutex<HashMap<String, Arc<SparkplugHost>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/**
+ * Get the Sparkplug host state of a relay, or `None` when Sparkplug mode is disabled
+ */
+pub fn sparkplug_host(relay_cfg: &RelayConfig) -> Option<Arc<SparkplugHost>> {
+  if !relay_cfg.config.mqtt.sparkplug.enabled.unwrap_or(false) {
+    return None;
+  }
+  let host = HOSTS
+    .lock()
+    .unwrap()
+    .entry(relay_cfg.id.clone())
+    .or_insert_with(|| Arc::new(SparkplugHost::new()))
+    .clone();
+  Some(host)
+}
+
+/**
+ * Edge nodes and devices online for every relay, reported on `/status`
+ */
+pub fn sparkplug_status() -> Value {
+  let hosts = HOSTS.lock().unwrap();
+  let status: serde_json::Map<String, Value> = hosts
+    .iter()
+    .map(|(relay_id, host)| {
+      let nodes = host.nodes.lock().unwrap();
+      let nodes_online = nodes.values().filter(|node| node.online).count();
+      let devices_online = nodes
+        .values()
+        .flat_map(|node| node.devices.values())
+        .filter(|online| **online)
+        .count();
+      (
+        relay_id.clone(),
+        json!({ "nodes_online": nodes_online, "devices_online": devices_online }),
+      )
+    })
+    .collect();
+  Value::Object(status)
+}
+
+impl Default for SparkplugHost {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl SparkplugHost {
+  pub fn new() -> Self {
+    SparkplugHost {
+      nodes: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /**
+   * Forget every edge node, e.g. after a reconnect: births and deaths sent meanwhile were missed
+   */
+  pub fn reset(&self) {
+    self.nodes.lock().unwrap().clear();
+  }
+
+  /**
+   * Update the alias tables and online state with a message, in the order the broker delivered them,
+   * and look up the names of its aliased metrics so the message can be decoded on its own later.
+   * The payload itself is left as it is.
+   */
+  pub fn track(&self, publish: &Publish) -> Tracked {
+    let mut tracked = Tracked::default();
+    let Some(topic) = parse_topic(&publish.topic) else {
+      return tracked;
+    };
+    let Ok(payload) = Payload::decode(publish.payload.as_ref()) else {
+      return tracked;
+    };
+
+    let mut nodes = self.nodes.lock().unwrap();
+    let node = nodes
+      .entry((topic.group_id.to_string(), topic.edge_node_id.to_string()))
+      .or_default();
+
+    match topic.message_type {
+      "NBIRTH" => {
+        node.online = true;
+        node.aliases.clear();
+        node.devices.values_mut().for_each(|online| *online = false);
+        node.bd_seq = bd_seq(&payload);
+        node.rebirth_requested = false;
+      }
+      "NDEATH" => {
+        // The broker sends the will of a previous session after a newer birth when the node reconnects quickly
+        if bd_seq(&payload).is_some_and(|seq| node.bd_seq.is_some_and(|current| current != seq)) {
+          tracked.drop = Some("stale_death");
+          return tracked;
+        }
+        node.online = false;
+        node.devices.values_mut().for_each(|online| *online = false);
+        return tracked;
+      }
+      "DBIRTH" | "DDEATH" => {
+        let online = topic.message_type == "DBIRTH";
+        node
+          .devices
+          .insert(topic.device_id.unwrap_or_default().to_string(), online);
+      }
+      _ => {}
+    }
+
+    let mut unknown = false;
+    for metric in payload.metrics {
+      match (metric.name, metric.alias) {
+        (Some(name), Some(alias)) if topic.message_type.ends_with("BIRTH") => {
+          node.aliases.insert(alias, (name, metric.datatype));
+        }
+        (None, Some(alias)) => match node.aliases.get(&alias) {
+          Some(resolved) => {
+            tracked.aliases.insert(alias, resolved.clone());
+          }
+          None => unknown = true,
+        },
+        _ => {}
+      }
+    }
+
+    // One request until the node is born again
+    if unknown && !node.rebirth_requested {
+      node.rebirth_requested = true;
+      tracked.rebirth = Some(rebirth_request(&topic));
+    }
+    tracked
+  }
+}
+
+/**
+ * `Node Control/Rebirth` command for the edge node of a topic
+ */
+fn rebirth_request(topic: &SparkplugTopic) -> Publish {
+  let payload = Payload {
+    timestamp: Some(payload::now_ms() as u64),
+    metrics: vec![Metric {
+      name: Some("Node Control/Rebirth".to_string()),
+      datatype: Some(11), // Boolean
+      value: Some(MetricValue::Boolean(true)),
+      ..Default::default()
+    }],
+    seq: None,
+  };
+  Publish::new(
+    format!("spBv1.0/{}/NCMD/{}", topic.group_id, topic.edge_node_id),
+    QoS::AtMostOnce,
+    payload.encode_to_vec(),
+  )
+}
+
+fn bd_seq(payload: &Payload) -> Option<u64> {
+  payload
+    .metrics
+    .iter()
+    .find(|metric| metric.name.as_deref() == Some("bdSeq"))
+    .and_then(|metric| match metric.value {
+      Some(MetricValue::Long(seq)) => Some(seq),
+      Some(MetricValue::Int(seq)) => Some(seq.into()),
+      _ => None,
+    })
+}
+
+/**
+ * JSON value of a metric according to its Sparkplug data type. Signed integers arrive as
+ * two's complement in the unsigned fields; DateTime values (unix milliseconds) become RFC 3339 text.
+ */
+fn metric_value(datatype: Option<u32>, value: &MetricValue) -> Value {
+  match (datatype, value) {
+    (Some(1), MetricValue::Int(int)) => json!(*int as u8 as i8),
+    (Some(2), MetricValue::Int(int)) => json!(*int as u16 as i16),
+    (Some(3), MetricValue::Int(int)) => json!(*int as i32),
+    (Some(4), MetricValue::Long(long)) => json!(*long as i64),
+    (Some(13), MetricValue::Long(long)) => json!(payload::format_time(*long as i64)),
+    (_, MetricValue::Int(int)) => json!(int),
+    (_, MetricValue::Long(long)) => json!(long),
+    (_, MetricValue::Float(float)) => json!(float),
+    (_, MetricValue::Double(double)) => json!(double),
+    (_, MetricValue::Boolean(boolean)) => json!(boolean),
+    (_, MetricValue::String(text)) => json!(text),
+    (_, MetricValue::Bytes(bytes)) => json!(hex::encode_upper(bytes)),
+  }
+}
+
+/**
+ * TagoIO variable name of a metric: `Inputs/Temperature` becomes `inputs_temperature`
+ */
+fn variable_name(metric: &str) -> String {
+  metric
+    .chars()
+    .map(|c| {
+      if c.is_ascii_alphanumeric() {
+        c.to_ascii_lowercase()
+      } else {
+        '_'
+      }
+    })
+    .collect()
+}
+
+fn serial(template: &str, topic: &SparkplugTopic) -> String {
+  template
+    .replace("{group_id}", topic.group_id)
+    .replace("{edge_node_id}", topic.edge_node_id)
+    .replace("{device_id}", topic.device_id.unwrap_or_default())
+}
+
+/**
+ * TagoIO data records of a Sparkplug message: one per named metric, plus an `online` record
+ * for births and deaths. `aliases` names the aliased metrics, as found by `SparkplugHost::track`.
+ * Returns `None` when the message is not a Sparkplug B message.
+ */
+pub fn decode_records(
+  relay_cfg: &RelayConfig,
+  subscription: Option<&Subscription>,
+  publish: &Publish,
+  aliases: &AliasNames,
+  received_at: i64,
+) -> Option<Vec<Value>> {
+  let topic = parse_topic(&publish.topic)?;
+  let payload = Payload::decode(publish.payload.as_ref()).ok()?;
+  let options = &relay_cfg.config.mqtt.sparkplug;
+
+  let default_options = RecordMetadata::default();
+  let metadata_options = subscription.map_or(&default_options, |subscription| &subscription.metadata);
+  let mut metadata = payload::record_metadata(relay_cfg, metadata_options, publish);
+  let template = match topic.device_id {
+    Some(_) => options
+      .device_serial
+      .as_deref()
+      .unwrap_or("{group_id}/{edge_node_id}/{device_id}"),
+    None => options.node_serial.as_deref().unwrap_or("{group_id}/{edge_node_id}"),
+  };
+  let fields = metadata.as_object_mut().unwrap();
+  fields.insert("serial".to_string(), json!(serial(template, &topic)));
+  fields.insert("group_id".to_string(), json!(topic.group_id));
+  fields.insert("edge_node_id".to_string(), json!(topic.edge_node_id));
+  if let Some(device_id) = topic.device_id {
+    fields.insert("device_id".to_string(), json!(device_id));
+  }
+  fields.insert("message_type".to_string(), json!(topic.message_type));
+  if let Some(seq) = payload.seq {
+    fields.insert("seq".to_string(), json!(seq));
+  }
+
+  let payload_time = payload.timestamp.map_or(received_at, |timestamp| timestamp as i64);
+  let mut records = Vec::new();
+  match topic.message_type {
+    "NBIRTH" | "DBIRTH" | "NDEATH" | "DDEATH" => records.push(json!({
+      "variable": "online",
+      "value": topic.message_type.ends_with("BIRTH"),
+      "time": payload::format_time(payload_time),
+      "metadata": metadata,
+    })),
+    _ => {}
+  }
+  if topic.message_type.ends_with("DEATH") {
+    return Some(records);
+  }
+
+  for metric in &payload.metrics {
+    let resolved = metric.alias.and_then(|alias| aliases.get(&alias));
+    let Some(name) = metric.name.as_ref().or(resolved.map(|(name, _)| name)) else {
+      log::debug!(target: "mqtt", relay_id = relay_cfg.id.as_str(), topic = publish.topic.as_str(); "Metric alias {:?} on topic {} has no birth certificate: skipped", metric.alias, publish.topic);
+      continue;
+    };
+    let datatype = metric.datatype.or(resolved.and_then(|(_, datatype)| *datatype));
+    let value = match &metric.value {
+      Some(value) => metric_value(datatype, value),
+      None if metric.is_null.unwrap_or(false) => Value::Null,
+      None => continue,
+    };
+    let mut metadata = metadata.clone();
+    metadata["metric"] = json!(name);
+    let time = metric.timestamp.map_or(payload_time, |timestamp| timestamp as i64);
+    records.push(json!({
+      "variable": variable_name(name),
+      "value": value,
+      "time": payload::format_time(time),
+      "metadata": metadata,
+    }));
+  }
+  Some(records)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::schema::{ConfigFile, Mqtt};
+  use crate::services::{downlink_queue::DownlinkQueue, mqttrelay::PublishMessage};
+  use rumqttc::{AsyncClient, QoS};
+  use std::time::{Duration, Instant};
+
+  fn metric(name: Option<&str>, alias: u64, value: MetricValue) -> Metric {
+    Metric {
+      name: name.map(str::to_string),
+      alias: Some(alias),
+      datatype: Some(10),
+      value: Some(value),
+      ..Default::default()
+    }
+  }
+
+  fn publish(topic: &str, metrics: Vec<Metric>) -> Publish {
+    let payload = Payload {
+      timestamp: Some(1_700_000_000_000),
+      metrics,
+      seq: Some(0),
+    };
+    Publish::new(topic, QoS::AtMostOnce, payload.encode_to_vec())
+  }
+
+  #[test]
+  fn test_parse_topic() {
+    assert_eq!(
+      parse_topic("spBv1.0/plant/DDATA/gateway/press"),
+      Some(SparkplugTopic {
+        group_id: "plant",
+        message_type: "DDATA",
+        edge_node_id: "gateway",
+        device_id: Some("press"),
+      })
+    );
+    assert!(parse_topic("spBv1.0/plant/NDATA/gateway/press").is_none());
+    assert!(parse_topic("spBv1.0/STATE/host").is_none());
+    assert!(parse_topic("sensors/1").is_none());
+  }
+
+  #[test]
+  fn test_sparkplug_aliases_and_state() {
+    let relay_cfg = RelayConfig {
+      id: "test_id".to_string(),
+      config: ConfigFile {
+        mqtt: Mqtt {
+          address: "localhost".to_string(),
+          port: 1883,
+          ..Default::default()
+        },
+        ..Default::default()
+      },
+      profile_id: None,
+      network_id: None,
+      http_client: reqwest::Client::new(),
+    };
+    let host = SparkplugHost::new();
+
+    let birth = publish(
+      "spBv1.0/plant/NBIRTH/gateway",
+      vec![
+        metric(Some("bdSeq"), 0, MetricValue::Long(3)),
+        metric(Some("Inputs/Temperature"), 1, MetricValue::Double(20.0)),
+      ],
+    );
+    let tracked = host.track(&birth);
+    assert!(tracked.drop.is_none() && tracked.rebirth.is_none());
+    let records = decode_records(&relay_cfg, None, &birth, &tracked.aliases, 0).unwrap();
+    assert_eq!(records[0]["variable"], "online");
+    assert_eq!(records[0]["value"], true);
+    assert_eq!(records[0]["metadata"]["serial"], "plant/gateway");
+
+    let data = publish(
+      "spBv1.0/plant/NDATA/gateway",
+      vec![metric(None, 1, MetricValue::Double(21.5))],
+    );
+    let original = data.payload.clone();
+    let tracked = host.track(&data);
+    assert_eq!(data.payload, original);
+    let records = decode_records(&relay_cfg, None, &data, &tracked.aliases, 0).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["variable"], "inputs_temperature");
+    assert_eq!(records[0]["value"], 21.5);
+    assert_eq!(records[0]["time"], "2023-11-14T22:13:20Z");
+    assert_eq!(records[0]["metadata"]["metric"], "Inputs/Temperature");
+
+    // The will of an older session does not take the node offline
+    let stale_death = publish(
+      "spBv1.0/plant/NDEATH/gateway",
+      vec![metric(Some("bdSeq"), 0, MetricValue::Long(2))],
+    );
+    assert_eq!(host.track(&stale_death).drop, Some("stale_death"));
+    assert!(host.nodes.lock().unwrap().values().all(|node| node.online));
+
+    let death = publish(
+      "spBv1.0/plant/NDEATH/gateway",
+      vec![metric(Some("bdSeq"), 0, MetricValue::Long(3))],
+    );
+    assert!(host.track(&death).drop.is_none());
+    let records = decode_records(&relay_cfg, None, &death, &AliasNames::default(), 0).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["value"], false);
+    assert!(host.nodes.lock().unwrap().values().all(|node| !node.online));
+  }
+
+  #[test]
+  fn test_sparkplug_rebirth_request() {
+    let host = SparkplugHost::new();
+    let data = publish(
+      "spBv1.0/plant/DDATA/gateway/press",
+      vec![metric(None, 7, MetricValue::Double(1.0))],
+    );
+
+    // Aliases unknown after a reset ask the node for a rebirth, once until it is born again
+    host.reset();
+    let rebirth = host.track(&data).rebirth.unwrap();
+    assert_eq!(rebirth.topic, "spBv1.0/plant/NCMD/gateway");
+    let command = Payload::decode(rebirth.payload.as_ref()).unwrap();
+    assert_eq!(command.metrics[0].name.as_deref(), Some("Node Control/Rebirth"));
+    assert_eq!(command.metrics[0].value, Some(MetricValue::Boolean(true)));
+    assert!(host.track(&data).rebirth.is_none());
+
+    let birth = publish(
+      "spBv1.0/plant/NBIRTH/gateway",
+      vec![metric(Some("Pressure"), 7, MetricValue::Double(0.0))],
+    );
+    host.track(&birth);
+    let tracked = host.track(&data);
+    assert!(tracked.rebirth.is_none());
+    assert_eq!(tracked.aliases[&7].0, "Pressure");
+  }
+
+  #[test]
+  fn test_rebirth_does_not_take_the_place_of_a_pending_downlink() {
+    let (requests_tx, requests_rx) = flume::bounded(10);
+    let client = AsyncClient::from_senders(requests_tx);
+    let queue = DownlinkQueue::new("test_rebirth_downlinks", Duration::from_secs(60), 100);
+    let downlink = PublishMessage {
+      topic: "devices/1/down".to_string(),
+      message: "payload".to_string(),
+      qos: 1,
+      retain: false,
+      queued_at: Instant::now(),
+    };
+    queue.push(downlink);
+
+    let host = SparkplugHost::new();
+    host.reset();
+    let data = publish(
+      "spBv1.0/plant/DDATA/gateway/press",
+      vec![metric(None, 7, MetricValue::Double(1.0))],
+    );
+    let rebirth = host.track(&data).rebirth.unwrap();
+    queue.publish_other(&client, rebirth).unwrap();
+    queue.publish_pending(&client);
+    assert_eq!(requests_rx.len(), 2);
+
+    // The rebirth NCMD goes out first at QoS 0; the downlink stays until the broker acknowledges it
+    queue.on_outgoing_publish(0);
+    assert_eq!(queue.len(), 1);
+    queue.on_outgoing_publish(1);
+    assert_eq!(queue.len(), 1);
+    queue.on_ack(1);
+    assert_eq!(queue.len(), 0);
+  }
+}
@@ -1,24 +1,35 @@
-use std::sync::Arc;
+use std::{
+  collections::{HashMap, VecDeque},
+  sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex, RwLock,
+  },
+};
 
-use anyhow::Error;
+use anyhow::{Context, Error};
 use axum::http::{HeaderMap, HeaderValue};
-use hex;
 use once_cell::sync::Lazy;
+use rand::Rng;
 use reqwest::StatusCode;
-use rumqttc::{Publish, QoS};
+use rumqttc::Publish;
 use serde_json;
-use std::fmt;
+use std::{
+  fmt,
+  time::{Duration, Instant},
+};
 
-use crate::{schema::RelayConfig, CONFIG_FILE};
+use crate::{
+  schema::{scrub_url, CircuitBreakerPolicy, ConfigFile, Forwarding, RelayConfig, Secret, Sink as SinkConfig},
+  services::{
+    aggregation, deadband, filters, lorawan, metrics, payload, scripting, sinks,
+    sparkplug::{self, AliasNames},
+    wasm_decoder,
+  },
+  CONFIG_FILE,
+};
 
 use tokio::time::sleep;
 
-// ...
-
-static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
-
-const MAX_RETRIES: u32 = 5;
-
 /**
  * Get the list of relay configurations
  * TODO: To be improved later to support multiple relays
@@ -37,6 +48,472 @@ pub async fn get_relay_list() -> Result<Vec<Arc<RelayConfig>>, Error> {
   Err(anyhow::anyhow!("Config file not found or invalid"))
 }
 
+/**
+ * Build the HTTP client used to talk to TagoIO, applying the forwarding timeouts,
+ * extra trust anchors, client identity and proxy settings
+ */
+pub fn build_http_client(config: &ConfigFile) -> Result<reqwest::Client, Error> {
+  let forwarding = &config.forwarding;
+  let mut builder = reqwest::Client::builder();
+
+  if let Some(timeout) = forwarding.request_timeout_ms {
+    builder = builder.timeout(Duration::from_millis(timeout));
+  }
+  if let Some(timeout) = forwarding.connect_timeout_ms {
+    builder = builder.connect_timeout(Duration::from_millis(timeout));
+  }
+
+  if let Some(ca_path) = &config.tagoio_tls_ca {
+    let bundle = std::fs::read(ca_path).with_context(|| format!("Failed to read tagoio_tls_ca file {}", ca_path))?;
+    let certs = reqwest::Certificate::from_pem_bundle(&bundle)
+      .with_context(|| format!("Invalid certificate bundle in {}", ca_path))?;
+    builder = builder.tls_certs_merge(certs);
+  }
+
+  match (&config.tagoio_tls_cert, &config.tagoio_tls_key) {
+    (Some(cert_path), Some(key_path)) => {
+      let mut pem =
+        std::fs::read(cert_path).with_context(|| format!("Failed to read tagoio_tls_cert file {}", cert_path))?;
+      pem.push(b'\n');
+      pem.extend(std::fs::read(key_path).with_context(|| format!("Failed to read tagoio_tls_key file {}", key_path))?);
+      let identity = reqwest::Identity::from_pem(&pem).context("Invalid TagoIO client certificate or key")?;
+      builder = builder.identity(identity);
+    }
+    (None, None) => {}
+    _ => anyhow::bail!("tagoio_tls_cert and tagoio_tls_key must be set together"),
+  }
+
+  if let Some(proxy_url) = &config.tagoio_proxy {
+    let no_proxy = config
+      .tagoio_no_proxy
+      .as_ref()
+      .and_then(|hosts| reqwest::NoProxy::from_string(&hosts.join(",")));
+    let proxy = reqwest::Proxy::all(proxy_url)
+      .with_context(|| format!("Invalid tagoio_proxy URL {}", proxy_url))?
+      .no_proxy(no_proxy);
+    builder = builder.proxy(proxy);
+  }
+
+  Ok(builder.build()?)
+}
+
+/**
+ * Describe how the relay reaches TagoIO, to make connection errors actionable
+ */
+fn describe_transport(config: &ConfigFile) -> String {
+  let mut parts = vec![];
+  if let Some(proxy_url) = &config.tagoio_proxy {
+    parts.push(format!("proxy {}", proxy_url));
+  }
+  if let Some(ca_path) = &config.tagoio_tls_ca {
+    parts.push(format!("CA bundle {}", ca_path));
+  }
+  if config.tagoio_tls_cert.is_some() {
+    parts.push("client certificate".to_string());
+  }
+
+  if parts.is_empty() {
+    "direct connection".to_string()
+  } else {
+    parts.join(", ")
+  }
+}
+
+/**
+ * Flatten an error and its sources into a single line, e.g. the TLS or proxy failure behind a request error
+ */
+pub(crate) fn error_chain(error: &dyn std::error::Error) -> String {
+  let mut message = error.to_string();
+  let mut source = error.source();
+  while let Some(cause) = source {
+    message.push_str(&format!(": {}", cause));
+    source = cause.source();
+  }
+  message
+}
+
+/**
+ * Whether a failed request should be retried according to the forwarding policy
+ */
+pub(crate) fn should_retry(forwarding: &Forwarding, status: StatusCode) -> bool {
+  match &forwarding.retry_status_codes {
+    Some(codes) => codes.contains(&status.as_u16()),
+    None => status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error(),
+  }
+}
+
+/**
+ * Exponential backoff for the given attempt, capped and optionally jittered
+ */
+pub(crate) fn retry_backoff(forwarding: &Forwarding, attempt: u32) -> Duration {
+  let base = forwarding.backoff_base_ms.unwrap_or(500);
+  let max = forwarding.backoff_max_ms.unwrap_or(30_000);
+  let delay = base.saturating_mul(2u64.saturating_pow(attempt)).min(max);
+
+  if forwarding.backoff_jitter.unwrap_or(true) && delay > 0 {
+    // Equal jitter: keep half of the delay and randomize the other half
+    let half = delay / 2;
+    return Duration::from_millis(half + rand::rng().random_range(0..=delay - half));
+  }
+  Duration::from_millis(delay)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerState {
+  Closed,
+  Open,
+  HalfOpen,
+}
+
+impl fmt::Display for BreakerState {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      BreakerState::Closed => write!(f, "closed"),
+      BreakerState::Open => write!(f, "open"),
+      BreakerState::HalfOpen => write!(f, "half-open"),
+    }
+  }
+}
+
+/**
+ * What became of records sent to a destination, from best to worst
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Delivery {
+  /// Accepted by the destination, or by the queue of a sink that sends on its own
+  Delivered,
+  /// Held by an open circuit breaker until the destination answers again
+  Buffered,
+  /// Dropped by an open circuit breaker or a full sink queue
+  Dropped,
+}
+
+struct BreakerInner {
+  state: BreakerState,
+  consecutive_failures: u32,
+  opened_at: Option<Instant>,
+  probe_in_flight: bool,
+  buffer: VecDeque<BufferedRequest>,
+}
+
+/**
+ * Request held while the breaker is open. It is built again when it is sent, so a rotated token
+ * or a changed route applies to it too.
+ */
+#[derive(Debug, Clone, PartialEq)]
+struct BufferedRequest {
+  destination: Destination,
+  body: serde_json::Value,
+}
+
+/**
+ * Where network data goes, looked up in the configuration every time a request is built
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Destination {
+  Route(String), // The first route matching this topic, or the relay itself
+  Sink(String),  // A `tagoio` sink, by name
+}
+
+/**
+ * TagoIO endpoint of a destination and the tokens to reach it with
+ */
+pub(crate) struct TagoIOTarget {
+  pub tagoio_url: String,
+  device_token: Option<Secret>,
+  authorization_token: Secret,
+  network_token: Secret,
+}
+
+impl Destination {
+  /**
+   * `None` when the sink is no longer configured
+   */
+  pub(crate) fn target(&self, config: &ConfigFile) -> Option<TagoIOTarget> {
+    let relay_url = || {
+      config
+        .tagoio_url
+        .clone()
+        .unwrap_or_else(|| "https://api.tago.io".to_string())
+    };
+    match self {
+      Destination::Route(topic) => {
+        let route = config.route(topic);
+        Some(TagoIOTarget {
+          tagoio_url: route
+            .and_then(|route| route.tagoio_url.clone())
+            .unwrap_or_else(relay_url),
+          device_token: route.and_then(|route| route.device_token.clone()),
+          authorization_token: route
+            .and_then(|route| route.authorization_token.clone())
+            .unwrap_or_else(|| config.authorization_token.clone()),
+          network_token: route
+            .and_then(|route| route.network_token.clone())
+            .unwrap_or_else(|| config.network_token.clone()),
+        })
+      }
+      Destination::Sink(name) => {
+        let sink = config.sinks.iter().find(|sink| sink.name == *name)?;
+        Some(TagoIOTarget {
+          tagoio_url: sink.tagoio_url.clone().unwrap_or_else(relay_url),
+          device_token: None,
+          authorization_token: sink
+            .authorization_token
+            .clone()
+            .unwrap_or_else(|| config.authorization_token.clone()),
+          network_token: sink
+            .network_token
+            .clone()
+            .unwrap_or_else(|| config.network_token.clone()),
+        })
+      }
+    }
+  }
+}
+
+impl TagoIOTarget {
+  /**
+   * The device data endpoint with a device token, or the network data endpoint with the network tokens
+   */
+  fn request(&self) -> Result<(String, HeaderMap), CustomError> {
+    let (endpoint, token) = match &self.device_token {
+      Some(device_token) => (format!("{}/data", self.tagoio_url), device_token.expose()),
+      None => (
+        format!(
+          "{}/integration/network/data?authorization_token={}",
+          self.tagoio_url,
+          self.authorization_token.expose()
+        ),
+        self.network_token.expose(),
+      ),
+    };
+    let mut headers = HeaderMap::new();
+    let token = HeaderValue::from_str(&token).map_err(|e| CustomError {
+      status: StatusCode::BAD_REQUEST,
+      body: String::new(),
+      message: format!("Invalid token: {}", e),
+    })?;
+    headers.insert("AUTHORIZATION", token);
+    Ok((endpoint, headers))
+  }
+}
+
+/**
+ * Circuit breaker around the TagoIO API.
+ * While open, requests skip the API entirely and go to the configured fallback.
+ */
+pub struct CircuitBreaker {
+  relay_id: String,
+  policy: RwLock<CircuitBreakerPolicy>,
+  inner: Mutex<BreakerInner>,
+  flushing: AtomicBool,
+  rejected: AtomicU64,
+  dropped: AtomicU64,
+}
+
+static CIRCUIT_BREAKERS: Lazy<Mutex<HashMap<String, Arc<CircuitBreaker>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/**
+ * Get the circuit breaker of a relay for a TagoIO URL, creating it on first use.
+ * Routes to another region do not share the breaker of the relay's own region.
+ */
+pub fn circuit_breaker(relay_cfg: &RelayConfig, tagoio_url: &str) -> Arc<CircuitBreaker> {
+  keyed_circuit_breaker(
+    &format!("{}@{}", relay_cfg.id, tagoio_url),
+    &relay_cfg.config.forwarding,
+  )
+}
+
+/**
+ * Get a circuit breaker by key, creating it on first use. Routes use `relay_id@tagoio_url`, TagoIO sinks `relay_id/sink`.
+ */
+pub(crate) fn keyed_circuit_breaker(key: &str, forwarding: &Forwarding) -> Arc<CircuitBreaker> {
+  let breaker = CIRCUIT_BREAKERS
+    .lock()
+    .unwrap()
+    .entry(key.to_string())
+    .or_insert_with(|| Arc::new(CircuitBreaker::new(key, forwarding.circuit_breaker.clone())))
+    .clone();
+  breaker.update_policy(&forwarding.circuit_breaker);
+  breaker
+}
+
+/**
+ * Circuit breaker state of every relay, reported on `/status`
+ */
+pub fn circuit_breaker_status() -> serde_json::Value {
+  let breakers = CIRCUIT_BREAKERS.lock().unwrap();
+  let status: serde_json::Map<String, serde_json::Value> = breakers
+    .iter()
+    .map(|(relay_id, breaker)| (relay_id.clone(), breaker.status()))
+    .collect();
+  serde_json::Value::Object(status)
+}
+
+impl CircuitBreaker {
+  pub fn new(relay_id: &str, policy: CircuitBreakerPolicy) -> Self {
+    CircuitBreaker {
+      relay_id: relay_id.to_string(),
+      policy: RwLock::new(policy),
+      inner: Mutex::new(BreakerInner {
+        state: BreakerState::Closed,
+        consecutive_failures: 0,
+        opened_at: None,
+        probe_in_flight: false,
+        buffer: VecDeque::new(),
+      }),
+      flushing: AtomicBool::new(false),
+      rejected: AtomicU64::new(0),
+      dropped: AtomicU64::new(0),
+    }
+  }
+
+  fn policy(&self) -> CircuitBreakerPolicy {
+    self.policy.read().unwrap().clone()
+  }
+
+  /**
+   * Apply a changed policy from a reloaded configuration, keeping the state and the buffer
+   */
+  fn update_policy(&self, policy: &CircuitBreakerPolicy) {
+    if *self.policy.read().unwrap() != *policy {
+      *self.policy.write().unwrap() = policy.clone();
+    }
+  }
+
+  /**
+   * Whether a request may be sent to TagoIO right now.
+   * Once the open period is over a single probe request is let through (half-open).
+   */
+  pub fn allow_request(&self) -> bool {
+    if !self.policy().enabled.unwrap_or(true) {
+      return true;
+    }
+
+    let mut inner = self.inner.lock().unwrap();
+    match inner.state {
+      BreakerState::Closed => true,
+      BreakerState::Open => {
+        let open_duration = Duration::from_secs(self.policy().open_duration_secs.unwrap_or(30));
+        if inner
+          .opened_at
+          .is_some_and(|opened_at| opened_at.elapsed() >= open_duration)
+        {
+          log::info!(target: "network", relay_id = self.relay_id.as_str(); "Circuit breaker half-open: probing TagoIO API");
+          inner.state = BreakerState::HalfOpen;
+          inner.probe_in_flight = true;
+          true
+        } else {
+          false
+        }
+      }
+      BreakerState::HalfOpen => {
+        if inner.probe_in_flight {
+          false
+        } else {
+          inner.probe_in_flight = true;
+          true
+        }
+      }
+    }
+  }
+
+  pub fn record_success(&self) {
+    let mut inner = self.inner.lock().unwrap();
+    if inner.state != BreakerState::Closed {
+      log::info!(target: "network", relay_id = self.relay_id.as_str(); "Circuit breaker closed: TagoIO API recovered");
+    }
+    inner.state = BreakerState::Closed;
+    inner.consecutive_failures = 0;
+    inner.opened_at = None;
+    inner.probe_in_flight = false;
+  }
+
+  pub fn record_failure(&self) {
+    if !self.policy().enabled.unwrap_or(true) {
+      return;
+    }
+
+    let mut inner = self.inner.lock().unwrap();
+    inner.consecutive_failures += 1;
+    inner.probe_in_flight = false;
+
+    let threshold = self.policy().failure_threshold.unwrap_or(5);
+    let should_open = inner.state == BreakerState::HalfOpen || inner.consecutive_failures >= threshold;
+    if should_open && inner.state != BreakerState::Open {
+      log::warn!(target: "network", relay_id = self.relay_id.as_str(); "Circuit breaker open after {} consecutive failures", inner.consecutive_failures);
+      inner.state = BreakerState::Open;
+      inner.opened_at = Some(Instant::now());
+    }
+  }
+
+  #[cfg(test)]
+  fn state(&self) -> BreakerState {
+    self.inner.lock().unwrap().state
+  }
+
+  /**
+   * Handle a message that could not be sent because the breaker is open
+   */
+  fn fallback(&self, request: BufferedRequest) -> Delivery {
+    self.rejected.fetch_add(1, Ordering::Relaxed);
+    metrics::increment_counter("circuit_breaker_rejected_total", &[("relay_id", &self.relay_id)]);
+
+    if self.policy().fallback.as_deref() == Some("drop") {
+      self.dropped.fetch_add(1, Ordering::Relaxed);
+      metrics::increment_counter("circuit_breaker_dropped_total", &[("relay_id", &self.relay_id)]);
+      log::debug!(target: "network", relay_id = self.relay_id.as_str(); "Circuit breaker open: message dropped");
+      return Delivery::Dropped;
+    }
+
+    let buffer_size = self.policy().buffer_size.unwrap_or(1000);
+    let mut inner = self.inner.lock().unwrap();
+    if inner.buffer.len() >= buffer_size {
+      inner.buffer.pop_front();
+      self.dropped.fetch_add(1, Ordering::Relaxed);
+      metrics::increment_counter("circuit_breaker_dropped_total", &[("relay_id", &self.relay_id)]);
+    }
+    inner.buffer.push_back(request);
+    Delivery::Buffered
+  }
+
+  fn pop_buffered(&self) -> Option<BufferedRequest> {
+    self.inner.lock().unwrap().buffer.pop_front()
+  }
+
+  fn unpop_buffered(&self, request: BufferedRequest) {
+    self.inner.lock().unwrap().buffer.push_front(request);
+  }
+
+  fn has_buffered(&self) -> bool {
+    !self.inner.lock().unwrap().buffer.is_empty()
+  }
+
+  pub fn status(&self) -> serde_json::Value {
+    let inner = self.inner.lock().unwrap();
+    serde_json::json!({
+      "state": inner.state.to_string(),
+      "consecutive_failures": inner.consecutive_failures,
+      "buffered": inner.buffer.len(),
+      "rejected": self.rejected.load(Ordering::Relaxed),
+      "dropped": self.dropped.load(Ordering::Relaxed),
+    })
+  }
+}
+
+/**
+ * Whether a failed request says something about the health of the TagoIO API.
+ * Client errors mean the API is reachable and answering.
+ */
+fn is_breaker_failure(status: StatusCode) -> bool {
+  status.is_server_error()
+}
+
+/**
+ * Longest response body included when an error is displayed
+ */
+const MAX_ERROR_BODY_LEN: usize = 256;
+
 #[derive(Debug)]
 pub struct CustomError {
   pub status: StatusCode,
@@ -46,10 +523,17 @@ pub struct CustomError {
 
 impl fmt::Display for CustomError {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    // Bodies may echo the request back, so they are truncated and scrubbed like the message
+    let body = match self.body.char_indices().nth(MAX_ERROR_BODY_LEN) {
+      Some((end, _)) => format!("{}...", &self.body[..end]),
+      None => self.body.clone(),
+    };
     write!(
       f,
       "Status: {}, Body: {}, Message: {}",
-      self.status, self.body, self.message
+      self.status,
+      scrub_url(&body),
+      scrub_url(&self.message)
     )
   }
 }
@@ -59,13 +543,13 @@ impl std::error::Error for CustomError {}
 /**
  * Wrapper function to make a request to the TagoIO API
  */
-async fn make_request(
+pub(crate) async fn make_request(
+  client: &reqwest::Client,
   method: reqwest::Method,
   url: &str,
   headers: HeaderMap,
   body: Option<serde_json::Value>,
 ) -> Result<String, CustomError> {
-  let client = &*CLIENT;
   let request = client.request(method, url).headers(headers);
 
   let request = if let Some(body) = body {
@@ -75,9 +559,13 @@ async fn make_request(
   };
 
   let response = request.send().await.map_err(|e| CustomError {
-    status: StatusCode::INTERNAL_SERVER_ERROR,
+    status: if e.is_timeout() {
+      StatusCode::GATEWAY_TIMEOUT
+    } else {
+      StatusCode::INTERNAL_SERVER_ERROR
+    },
     body: String::new(),
-    message: e.to_string(),
+    message: scrub_url(&error_chain(&e)),
   })?;
 
   let status = response.status();
@@ -98,79 +586,368 @@ async fn make_request(
 }
 
 /**
- * Forward the buffered messages to TagoIO Network
+ * Forward the buffered messages to TagoIO Network. `received_at` is the broker-receive time, in unix milliseconds.
+ * `aliases` names the Sparkplug metric aliases of the message.
  */
 pub async fn forward_buffer_messages(
   relay_cfg: &RelayConfig,
   event: &Publish,
+  aliases: &AliasNames,
+  received_at: i64,
 ) -> Result<(), Box<dyn std::error::Error>> {
-  let endpoint = relay_cfg
+  let records = uplink_records(relay_cfg, event, aliases, received_at).await;
+  send_records(relay_cfg, &event.topic, records).await
+}
+
+/**
+ * Data records of an archived uplink as the relay would build them now. Births and deaths replayed
+ * before it rebuild the Sparkplug alias tables. `None` when Sparkplug or the filters drop the uplink.
+ * Without `aggregate`, the records before aggregation, leaving the windows alone.
+ */
+pub async fn replayed_records(
+  relay_cfg: &RelayConfig,
+  publish: &Publish,
+  received_at: i64,
+  aggregate: bool,
+) -> Option<Vec<serde_json::Value>> {
+  // Rebirth requests are left out: the replay does not talk to the broker
+  let tracked = sparkplug::sparkplug_host(relay_cfg)
+    .map(|host| host.track(publish))
+    .unwrap_or_default();
+  if tracked
+    .drop
+    .or_else(|| filters::drop_reason(&relay_cfg.config.mqtt.filters, publish))
+    .is_some()
+  {
+    return None;
+  }
+  match aggregate {
+    true => Some(uplink_records(relay_cfg, publish, &tracked.aliases, received_at).await),
+    false => Some(decoded_records(relay_cfg, publish, &tracked.aliases, received_at).await),
+  }
+}
+
+/**
+ * Forward the data records of a topic, then move its deadband baseline to them once they are delivered
+ */
+pub async fn send_records(
+  relay_cfg: &RelayConfig,
+  topic: &str,
+  records: Vec<serde_json::Value>,
+) -> Result<(), Box<dyn std::error::Error>> {
+  if records.is_empty() {
+    log::debug!(target: "mqtt", relay_id = relay_cfg.id.as_str(), topic = topic; "Nothing to send yet for topic {}", topic);
+    return Ok(());
+  }
+
+  let delivery = forward_records(relay_cfg, topic, records.clone()).await?;
+  let subscription = relay_cfg.config.mqtt.subscription(topic);
+  if subscription.is_some_and(|subscription| subscription.deadband.is_some()) {
+    if delivery == Delivery::Delivered {
+      deadband::report_by_exception(relay_cfg).commit(topic, &records);
+    } else {
+      // Buffered or dropped records are compared against the last delivered values again
+      log::debug!(target: "mqtt", relay_id = relay_cfg.id.as_str(), topic = topic; "Records of topic {} were {:?}: deadband baseline kept", topic, delivery);
+    }
+  }
+  Ok(())
+}
+
+/**
+ * Data records of an uplink after decoding, decoders, scripts, aggregation and deadband.
+ * Empty while aggregated samples wait for their window to close.
+ */
+pub async fn uplink_records(
+  relay_cfg: &RelayConfig,
+  event: &Publish,
+  aliases: &AliasNames,
+  received_at: i64,
+) -> Vec<serde_json::Value> {
+  let mut records = decoded_records(relay_cfg, event, aliases, received_at).await;
+  let subscription = relay_cfg.config.mqtt.subscription(&event.topic);
+  if let Some(aggregate) = subscription.and_then(|subscription| subscription.aggregate.as_ref()) {
+    // Numeric samples are held until their window closes
+    records = aggregation::aggregator(relay_cfg).add(received_at, &event.topic, aggregate, records);
+  }
+  post_aggregation(relay_cfg, &event.topic, records)
+}
+
+/**
+ * Data records of an uplink after decoding, decoders and scripts, before aggregation
+ */
+async fn decoded_records(
+  relay_cfg: &RelayConfig,
+  event: &Publish,
+  aliases: &AliasNames,
+  received_at: i64,
+) -> Vec<serde_json::Value> {
+  let subscription = relay_cfg.config.mqtt.subscription(&event.topic);
+  let sparkplug_records = relay_cfg
     .config
-    .tagoio_url
-    .clone()
-    .unwrap_or_else(|| "https://api.tago.io".to_string());
+    .mqtt
+    .sparkplug
+    .enabled
+    .unwrap_or(false)
+    .then(|| sparkplug::decode_records(relay_cfg, subscription, event, aliases, received_at))
+    .flatten();
+  let mut records = sparkplug_records
+    .or_else(|| lorawan::decode_records(relay_cfg, subscription, event, received_at))
+    .unwrap_or_else(|| payload::decode_records(relay_cfg, subscription, event, received_at));
 
-  let query_string = format!("?authorization_token={}", relay_cfg.config.authorization_token);
+  if let Some(decoder) = subscription.and_then(|subscription| subscription.decoder.as_deref()) {
+    records = wasm_decoder::transform(relay_cfg, decoder, event, records).await;
+  }
 
-  let endpoint = format!("{}/integration/network/data{}", endpoint, query_string);
+  if let Some(script) = subscription.and_then(|subscription| subscription.script.as_deref()) {
+    records = scripting::transform(relay_cfg, script, event, records).await;
+  }
 
-  let mut headers = HeaderMap::new();
-  headers.insert("AUTHORIZATION", HeaderValue::from_str(&relay_cfg.config.network_token)?);
+  records
+}
 
-  let payload_value = match String::from_utf8(event.payload.to_vec()) {
-    Ok(utf8_str) => {
-      // Convert to uppercase hex
-      serde_json::Value::String(utf8_str)
-    }
-    Err(_) => {
-      // Not valid UTF-8, convert to hex
-      serde_json::Value::String(hex::encode_upper(&event.payload))
-    }
-  };
+/**
+ * Stages after aggregation, shared by uplinks and the windows closed by time: the deadband
+ */
+pub fn post_aggregation(
+  relay_cfg: &RelayConfig,
+  topic: &str,
+  records: Vec<serde_json::Value>,
+) -> Vec<serde_json::Value> {
+  match relay_cfg
+    .config
+    .mqtt
+    .subscription(topic)
+    .and_then(|subscription| subscription.deadband.as_ref())
+  {
+    Some(deadband) => deadband::report_by_exception(relay_cfg).filter(topic, deadband, records),
+    None => records,
+  }
+}
 
-  let qos_number = match event.qos {
-    QoS::AtMostOnce => 0,
-    QoS::AtLeastOnce => 1,
-    QoS::ExactlyOnce => 2,
+/**
+ * Send the data records of a topic to the sinks of the first route matching the topic, `tagoio` by default.
+ * Configured sinks only queue the records and send them from their own task.
+ * The result is the worst delivery of the sinks.
+ */
+pub async fn forward_records(
+  relay_cfg: &RelayConfig,
+  topic: &str,
+  records: Vec<serde_json::Value>,
+) -> Result<Delivery, Box<dyn std::error::Error>> {
+  let names = match relay_cfg.config.route(topic) {
+    Some(route) if !route.sinks.is_empty() => route.sinks.clone(),
+    _ => vec![sinks::TAGOIO_SINK.to_string()],
   };
 
-  let body = serde_json::json!([{
-      "variable": "payload",
-      "value": payload_value,
-      "metadata": {
-          "topic": event.topic.clone(),
-          "qos": qos_number,
-      }
-  }]);
+  let mut result = Ok(Delivery::Delivered);
+  for sink in names.iter().filter_map(|name| sinks::sink(relay_cfg, name)) {
+    match sink.send(relay_cfg, topic.to_string(), records.clone()).await {
+      Ok(delivery) => result = result.map(|worst| worst.max(delivery)),
+      Err(e) => result = Err(e),
+    }
+  }
+  result.map_err(|e| e.into())
+}
+
+/**
+ * Send the data records of a topic to TagoIO, following the first route matching the topic:
+ * the network data endpoint of its region and tokens, or the device data endpoint with a device token
+ */
+pub(crate) async fn forward_to_tagoio(
+  relay_cfg: &RelayConfig,
+  topic: &str,
+  records: Vec<serde_json::Value>,
+) -> Result<Delivery, Box<dyn std::error::Error + Send + Sync>> {
+  let destination = Destination::Route(topic.to_string());
+  let tagoio_url = destination
+    .target(&relay_cfg.config)
+    .map(|target| target.tagoio_url)
+    .unwrap_or_default();
+
+  let delivery = send_network_data(
+    relay_cfg,
+    &relay_cfg.config.forwarding,
+    circuit_breaker(relay_cfg, &tagoio_url),
+    &destination,
+    serde_json::Value::Array(records),
+  )
+  .await?;
+  Ok(delivery)
+}
+
+/**
+ * Post a body to TagoIO, retrying according to the forwarding policy.
+ * Requests go through the given circuit breaker: while it is open the body goes to the fallback.
+ */
+pub(crate) async fn send_network_data(
+  relay_cfg: &RelayConfig,
+  forwarding: &Forwarding,
+  breaker: Arc<CircuitBreaker>,
+  destination: &Destination,
+  body: serde_json::Value,
+) -> Result<Delivery, CustomError> {
+  let max_retries = forwarding.max_retries.unwrap_or(5);
+  let Some(target) = destination.target(&relay_cfg.config) else {
+    return Err(CustomError {
+      status: StatusCode::NOT_FOUND,
+      body: String::new(),
+      message: format!("{:?} is not configured", destination),
+    });
+  };
+  let (endpoint, headers) = target.request()?;
 
   let mut attempt = 0;
   loop {
-    let headers_clone = headers.clone();
-    let body_clone = body.clone();
+    if !breaker.allow_request() {
+      return Ok(breaker.fallback(BufferedRequest {
+        destination: destination.clone(),
+        body,
+      }));
+    }
 
-    match make_request(reqwest::Method::POST, &endpoint, headers_clone, Some(body_clone)).await {
-      Ok(_) => return Ok(()),
+    match make_request(
+      &relay_cfg.http_client,
+      reqwest::Method::POST,
+      &endpoint,
+      headers.clone(),
+      Some(body.clone()),
+    )
+    .await
+    {
+      Ok(_) => {
+        breaker.record_success();
+        flush_breaker_buffer(relay_cfg, &breaker);
+        return Ok(Delivery::Delivered);
+      }
       Err(e) => {
-        let should_retry = match e.status {
-          StatusCode::TOO_MANY_REQUESTS => true,
-          status if status.is_server_error() => true,
-          _ => false,
-        };
+        if is_breaker_failure(e.status) {
+          breaker.record_failure();
+        } else {
+          breaker.record_success();
+        }
 
-        if should_retry && attempt < MAX_RETRIES {
+        if should_retry(forwarding, e.status) && attempt < max_retries {
           attempt += 1;
-          let backoff = std::time::Duration::from_millis(500 * 2u64.pow(attempt));
-          log::warn!(target: "mqtt", "Request failed with status: {}. Retrying in {:?} (Attempt {}/{})", e.status, backoff, attempt, MAX_RETRIES);
+          let backoff = retry_backoff(forwarding, attempt);
+          log::warn!(target: "mqtt", relay_id = relay_cfg.id.as_str(), error = e.message.as_str(); "Request failed with status: {}. Retrying in {:?} (Attempt {}/{})", e.status, backoff, attempt, max_retries);
           sleep(backoff).await;
           continue;
         }
 
-        return Err(Box::new(e));
+        return Err(e);
       }
     };
   }
 }
 
+/**
+ * Send records to the network data endpoint of a `tagoio` sink, through the circuit breaker of the sink.
+ * Its URL and tokens default to the relay ones.
+ */
+pub(crate) async fn send_sink_data(
+  relay_cfg: &RelayConfig,
+  forwarding: &Forwarding,
+  sink: &SinkConfig,
+  records: Vec<serde_json::Value>,
+) -> Result<Delivery, CustomError> {
+  let breaker = keyed_circuit_breaker(&format!("{}/{}", relay_cfg.id, sink.name), forwarding);
+  send_network_data(
+    relay_cfg,
+    forwarding,
+    breaker,
+    &Destination::Sink(sink.name.clone()),
+    serde_json::Value::Array(records),
+  )
+  .await
+}
+
+/**
+ * Once the API answers again, send the messages buffered while the breaker was open,
+ * built with the configuration and tokens of now.
+ * Runs in the background and stops at the first failure, keeping the remaining messages.
+ */
+fn flush_breaker_buffer(relay_cfg: &RelayConfig, breaker: &Arc<CircuitBreaker>) {
+  if !breaker.has_buffered() || breaker.flushing.swap(true, Ordering::SeqCst) {
+    return;
+  }
+
+  let relay_cfg = relay_cfg.clone();
+  let breaker = breaker.clone();
+
+  tokio::spawn(async move {
+    log::info!(target: "network", relay_id = breaker.relay_id.as_str(); "Sending messages buffered while the circuit breaker was open");
+    send_breaker_buffer(&relay_cfg, &breaker).await;
+    breaker.flushing.store(false, Ordering::SeqCst);
+  });
+}
+
+/**
+ * Send the buffered messages of a breaker until the buffer is empty or the API fails again
+ */
+async fn send_breaker_buffer(relay_cfg: &RelayConfig, breaker: &CircuitBreaker) {
+  while let Some(request) = breaker.pop_buffered() {
+    let built = request
+      .destination
+      .target(&relay_cfg.config)
+      .map(|target| target.request());
+    let (endpoint, headers) = match built {
+      Some(Ok(built)) => built,
+      Some(Err(e)) => {
+        log::error!(target: "network", relay_id = breaker.relay_id.as_str(), error:% = e; "Failed to send buffered message to TagoIO: {}", e);
+        continue;
+      }
+      None => {
+        log::warn!(target: "network", relay_id = breaker.relay_id.as_str(); "Buffered message for {:?} dropped: no longer configured", request.destination);
+        continue;
+      }
+    };
+
+    if let Err(e) = make_request(
+      &relay_cfg.http_client,
+      reqwest::Method::POST,
+      &endpoint,
+      headers,
+      Some(request.body.clone()),
+    )
+    .await
+    {
+      if is_breaker_failure(e.status) {
+        breaker.record_failure();
+        breaker.unpop_buffered(request);
+        break;
+      }
+      log::error!(target: "network", relay_id = breaker.relay_id.as_str(), error:% = e; "Failed to send buffered message to TagoIO: {}", e);
+    }
+  }
+}
+
+/**
+ * Send what the circuit breakers of a relay still buffer, e.g. before a replay ends.
+ * Returns the number of messages that could not be sent.
+ */
+pub async fn drain_circuit_breakers(relay_cfg: &RelayConfig) -> usize {
+  let prefixes = [format!("{}@", relay_cfg.id), format!("{}/", relay_cfg.id)];
+  let breakers: Vec<Arc<CircuitBreaker>> = CIRCUIT_BREAKERS
+    .lock()
+    .unwrap()
+    .iter()
+    .filter(|(key, _)| prefixes.iter().any(|prefix| key.starts_with(prefix.as_str())))
+    .map(|(_, breaker)| breaker.clone())
+    .collect();
+
+  let mut unsent = 0;
+  for breaker in breakers {
+    // Wait for a background flush of this breaker to end
+    while breaker.flushing.swap(true, Ordering::SeqCst) {
+      sleep(Duration::from_millis(50)).await;
+    }
+    send_breaker_buffer(relay_cfg, &breaker).await;
+    breaker.flushing.store(false, Ordering::SeqCst);
+    unsent += breaker.inner.lock().unwrap().buffer.len();
+  }
+  unsent
+}
+
 /**
  * Verify that the network token is valid
  */
@@ -186,13 +963,20 @@ pub async fn verify_network_token(relay_cfg: &RelayConfig) -> Result<String, Cus
   let mut headers = HeaderMap::new();
   headers.insert(
     "Authorization",
-    HeaderValue::from_str(&relay_cfg.config.network_token).unwrap(),
+    HeaderValue::from_str(&relay_cfg.config.network_token.expose()).unwrap(),
   );
 
-  let resp = make_request(reqwest::Method::GET, &endpoint, headers, None).await?;
+  let resp = make_request(&relay_cfg.http_client, reqwest::Method::GET, &endpoint, headers, None)
+    .await
+    .inspect_err(|e| {
+      if e.body.is_empty() && e.status.is_server_error() {
+        // No response at all: TLS handshake, proxy or connection failure
+        log::error!(target: "network", relay_id = relay_cfg.id.as_str(); "Could not reach TagoIO at {} ({}): {}", scrub_url(&endpoint), describe_transport(&relay_cfg.config), e.message);
+      }
+    })?;
 
   if resp.is_empty() {
-    log::error!(target: "error", "Invalid Network Token: Check your network token and TagoIO API URL and try again");
+    log::error!(target: "error", relay_id = relay_cfg.id.as_str(); "Invalid Network Token: Check your network token and TagoIO API URL and try again");
     return Err(CustomError {
       status: StatusCode::UNAUTHORIZED,
       body: String::new(),
@@ -234,10 +1018,10 @@ pub async fn verify_device_token(relay_cfg: &RelayConfig, device_token: &str) ->
   let mut headers = HeaderMap::new();
   headers.insert("Authorization", HeaderValue::from_str(device_token).unwrap());
 
-  let resp = make_request(reqwest::Method::GET, &endpoint, headers, None).await?;
+  let resp = make_request(&relay_cfg.http_client, reqwest::Method::GET, &endpoint, headers, None).await?;
 
   if resp.is_empty() {
-    log::error!(target: "error", "Invalid Device Token: Check your device token and TagoIO API URL and try again");
+    log::error!(target: "error", relay_id = relay_cfg.id.as_str(); "Invalid Device Token: Check your device token and TagoIO API URL and try again");
     return Err(CustomError {
       status: StatusCode::UNAUTHORIZED,
       body: String::new(),
@@ -258,7 +1042,7 @@ pub async fn verify_device_token(relay_cfg: &RelayConfig, device_token: &str) ->
   })?;
 
   if network_id != relay_cfg.network_id.as_ref().unwrap() {
-    log::error!(target: "error", "Invalid Device Token: Check your device token and TagoIO API URL and try again");
+    log::error!(target: "error", relay_id = relay_cfg.id.as_str(); "Invalid Device Token: Check your device token and TagoIO API URL and try again");
     return Err(CustomError {
       status: StatusCode::UNAUTHORIZED,
       body: String::new(),
@@ -275,32 +1059,39 @@ pub async fn verify_device_token(relay_cfg: &RelayConfig, device_token: &str) ->
 #[cfg(test)]
 mod tests {
   use super::*;
-  use crate::schema::{ConfigFile, Mqtt};
+  use crate::schema::{ConfigFile, Deadband, Forwarding, Mqtt, Route, Sink, Subscription};
   use mockito::Matcher;
   use rumqttc::{Publish, QoS};
   use tokio;
 
-  fn get_test_relay_config(server: &mockito::Server) -> RelayConfig {
-    RelayConfig {
-      id: "test_id".to_string(),
-      config: ConfigFile {
-        network_token: "test_network_token".to_string(),
-        authorization_token: "test_authorization_token".to_string(),
-        tagoio_url: Some(server.url()),
-        downlink_port: Some(3000),
-        mqtt: Mqtt {
-          client_id: Some("test_client_id".to_string()),
-          tls_enabled: false,
-          address: "localhost".to_string(),
-          port: 1883,
-          subscribe: vec!["/tago/#".to_string(), "/device/+".to_string()],
-          username: Some("test_username".to_string()),
-          password: Some("test_password".to_string()),
-          broker_tls_ca: None,
-          broker_tls_cert: None,
-          broker_tls_key: None,
-        },
+  fn get_test_relay_config(server: &mockito::Server, id: &str) -> RelayConfig {
+    let config = ConfigFile {
+      network_token: "test_network_token".into(),
+      authorization_token: "test_authorization_token".into(),
+      tagoio_url: Some(server.url()),
+      downlink_port: Some(3000),
+      downlink_ttl: Some(300),
+      mqtt: Mqtt {
+        client_id: Some("test_client_id".to_string()),
+        tls_enabled: false,
+        address: "localhost".to_string(),
+        port: 1883,
+        subscribe: vec!["/tago/#".to_string(), "/device/+".to_string()],
+        username: Some("test_username".to_string()),
+        password: Some("test_password".into()),
+        broker_tls_ca: None,
+        broker_tls_cert: None,
+        broker_tls_key: None,
+        ..Default::default()
       },
+      forwarding: Forwarding::default().with_defaults().unwrap(),
+      ..Default::default()
+    };
+
+    RelayConfig {
+      id: id.to_string(),
+      http_client: build_http_client(&config).unwrap(),
+      config,
       profile_id: None,
       network_id: None,
     }
@@ -309,7 +1100,7 @@ mod tests {
   #[tokio::test]
   async fn test_forward_buffer_messages() {
     let mut server = mockito::Server::new_async().await;
-    let relay_cfg = get_test_relay_config(&server);
+    let relay_cfg = get_test_relay_config(&server, "test_forward_buffer_messages");
     let event = Publish::new(
       "test/topic",
       QoS::AtLeastOnce,
@@ -326,9 +1117,15 @@ mod tests {
       .match_body(Matcher::Json(serde_json::json!([{
           "variable": "payload",
           "value": "hello",
+          "time": "2023-11-14T22:13:20Z",
           "metadata": {
               "topic": "test/topic",
               "qos": 1,
+              "retain": false,
+              "dup": false,
+              "packet_id": 0,
+              "relay_id": "test_forward_buffer_messages",
+              "broker": "localhost:1883",
           }
       }])))
       .with_status(200)
@@ -336,14 +1133,384 @@ mod tests {
       .create_async()
       .await;
 
-    let result = forward_buffer_messages(&relay_cfg, &event).await;
+    let result = forward_buffer_messages(&relay_cfg, &event, &AliasNames::default(), 1_700_000_000_000).await;
     assert!(result.is_ok());
   }
 
+  #[tokio::test]
+  async fn test_forward_records_follows_routes() {
+    let mut server = mockito::Server::new_async().await;
+    let mut relay_cfg = get_test_relay_config(&server, "test_forward_records_follows_routes");
+    relay_cfg.config.routes = vec![
+      Route {
+        topic: "plant-a/#".to_string(),
+        authorization_token: Some("plant_a_authorization_token".into()),
+        network_token: Some("plant_a_network_token".into()),
+        ..Default::default()
+      },
+      Route {
+        topic: "meters/+".to_string(),
+        device_token: Some("meter_device_token".into()),
+        ..Default::default()
+      },
+    ];
+    let records = vec![serde_json::json!({"variable": "temperature", "value": 21.5})];
+
+    let network = server
+      .mock("POST", "/integration/network/data")
+      .match_query(Matcher::UrlEncoded(
+        "authorization_token".into(),
+        "plant_a_authorization_token".into(),
+      ))
+      .match_header("AUTHORIZATION", "plant_a_network_token")
+      .with_status(200)
+      .create_async()
+      .await;
+    let device = server
+      .mock("POST", "/data")
+      .match_header("AUTHORIZATION", "meter_device_token")
+      .with_status(200)
+      .create_async()
+      .await;
+
+    assert!(forward_records(&relay_cfg, "plant-a/line/1", records.clone())
+      .await
+      .is_ok());
+    assert!(forward_records(&relay_cfg, "meters/42", records).await.is_ok());
+    network.assert_async().await;
+    device.assert_async().await;
+  }
+
+  #[tokio::test]
+  async fn test_forward_records_fans_out_to_sinks() {
+    let mut server = mockito::Server::new_async().await;
+    let mut relay_cfg = get_test_relay_config(&server, "test_forward_records_fans_out_to_sinks");
+    let archive = std::env::temp_dir().join(format!("tagoio-relay-archive-{}.jsonl", std::process::id()));
+    relay_cfg.config.sinks = vec![
+      Sink {
+        name: "webhook".to_string(),
+        kind: "webhook".to_string(),
+        url: Some(format!("{}/hooks/uplinks", server.url())),
+        body_template: Some(r#"{"source": {{topic}}, "data": {{records}}}"#.to_string()),
+        ..Default::default()
+      },
+      Sink {
+        name: "archive".to_string(),
+        kind: "file".to_string(),
+        path: Some(archive.to_str().unwrap().to_string()),
+        ..Default::default()
+      },
+    ];
+    relay_cfg.config.routes = vec![Route {
+      topic: "alarms/#".to_string(),
+      sinks: vec!["webhook".to_string(), "archive".to_string()],
+      ..Default::default()
+    }];
+    let records = vec![serde_json::json!({"variable": "alarm", "value": true})];
+
+    let webhook = server
+      .mock("POST", "/hooks/uplinks")
+      .match_body(Matcher::Json(
+        serde_json::json!({"source": "alarms/1", "data": records}),
+      ))
+      .with_status(200)
+      .create_async()
+      .await;
+    let tagoio = server
+      .mock("POST", "/integration/network/data")
+      .match_query(Matcher::Any)
+      .expect(0)
+      .create_async()
+      .await;
+
+    assert!(forward_records(&relay_cfg, "alarms/1", records).await.is_ok());
+    // The sinks send from their own tasks
+    for _ in 0..100 {
+      if webhook.matched_async().await && archive.exists() {
+        break;
+      }
+      sleep(Duration::from_millis(20)).await;
+    }
+    webhook.assert_async().await;
+    tagoio.assert_async().await;
+    let archived = std::fs::read_to_string(&archive).unwrap();
+    assert_eq!(
+      archived,
+      "{\"record\":{\"value\":true,\"variable\":\"alarm\"},\"topic\":\"alarms/1\"}\n"
+    );
+    std::fs::remove_file(archive).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_forward_buffer_messages_retries_configured_status() {
+    let mut server = mockito::Server::new_async().await;
+    let mut relay_cfg = get_test_relay_config(&server, "test_forward_buffer_messages_retries_configured_status");
+    relay_cfg.config.forwarding.max_retries = Some(2);
+    relay_cfg.config.forwarding.backoff_base_ms = Some(1);
+    relay_cfg.config.forwarding.retry_status_codes = Some(vec![409]);
+    let event = Publish::new("test/topic", QoS::AtLeastOnce, "hello");
+
+    let mock = server
+      .mock("POST", "/integration/network/data")
+      .match_query(Matcher::Any)
+      .with_status(409)
+      .expect(3)
+      .create_async()
+      .await;
+
+    let result = forward_buffer_messages(&relay_cfg, &event, &AliasNames::default(), 0).await;
+    assert!(result.is_err());
+    mock.assert_async().await;
+  }
+
+  #[test]
+  fn test_circuit_breaker_opens_and_probes() {
+    let breaker = CircuitBreaker::new(
+      "test",
+      CircuitBreakerPolicy {
+        failure_threshold: Some(2),
+        open_duration_secs: Some(0),
+        ..Default::default()
+      },
+    );
+
+    breaker.record_failure();
+    assert_eq!(breaker.state(), BreakerState::Closed);
+    breaker.record_failure();
+    assert_eq!(breaker.state(), BreakerState::Open);
+
+    // Open period is over: a single probe goes through
+    assert!(breaker.allow_request());
+    assert_eq!(breaker.state(), BreakerState::HalfOpen);
+    assert!(!breaker.allow_request());
+
+    breaker.record_success();
+    assert_eq!(breaker.state(), BreakerState::Closed);
+    assert!(breaker.allow_request());
+  }
+
+  #[test]
+  fn test_circuit_breaker_fallback() {
+    let buffered = |body: serde_json::Value| BufferedRequest {
+      destination: Destination::Route("sensors/1".to_string()),
+      body,
+    };
+    let buffering = CircuitBreaker::new(
+      "test",
+      CircuitBreakerPolicy {
+        buffer_size: Some(1),
+        ..Default::default()
+      },
+    );
+    buffering.fallback(buffered(serde_json::json!([1])));
+    buffering.fallback(buffered(serde_json::json!([2])));
+    assert_eq!(buffering.pop_buffered(), Some(buffered(serde_json::json!([2]))));
+    assert_eq!(buffering.status()["dropped"], 1);
+
+    let dropping = CircuitBreaker::new(
+      "test",
+      CircuitBreakerPolicy {
+        fallback: Some("drop".to_string()),
+        ..Default::default()
+      },
+    );
+    dropping.fallback(buffered(serde_json::json!([1])));
+    assert!(!dropping.has_buffered());
+    assert_eq!(dropping.status()["dropped"], 1);
+  }
+
+  #[tokio::test]
+  async fn test_forward_buffer_messages_skips_api_while_breaker_open() {
+    let mut server = mockito::Server::new_async().await;
+    let mut relay_cfg = get_test_relay_config(&server, "test_forward_buffer_messages_skips_api_while_breaker_open");
+    relay_cfg.config.forwarding.max_retries = Some(0);
+    relay_cfg.config.forwarding.circuit_breaker.failure_threshold = Some(1);
+    let event = Publish::new("test/topic", QoS::AtLeastOnce, "hello");
+
+    let mock = server
+      .mock("POST", "/integration/network/data")
+      .match_query(Matcher::Any)
+      .with_status(503)
+      .expect(1)
+      .create_async()
+      .await;
+
+    assert!(forward_buffer_messages(&relay_cfg, &event, &AliasNames::default(), 0)
+      .await
+      .is_err());
+    assert!(forward_buffer_messages(&relay_cfg, &event, &AliasNames::default(), 0)
+      .await
+      .is_ok());
+    mock.assert_async().await;
+    assert_eq!(circuit_breaker(&relay_cfg, &server.url()).status()["buffered"], 1);
+  }
+
+  #[tokio::test]
+  async fn test_deadband_baseline_waits_for_delivery() {
+    let mut server = mockito::Server::new_async().await;
+    let mut relay_cfg = get_test_relay_config(&server, "test_deadband_baseline_waits_for_delivery");
+    relay_cfg.config.forwarding.max_retries = Some(0);
+    relay_cfg.config.forwarding.circuit_breaker.failure_threshold = Some(1);
+    relay_cfg.config.forwarding.circuit_breaker.open_duration_secs = Some(3600);
+    relay_cfg.config.mqtt.subscriptions = vec![Subscription {
+      topic: "sensors/1".to_string(),
+      deadband: Some(Deadband {
+        absolute: Some(0.5),
+        ..Default::default()
+      }),
+      ..Default::default()
+    }];
+    let records = vec![serde_json::json!({ "variable": "temperature", "value": 21.0 })];
+
+    let failing = server
+      .mock("POST", "/integration/network/data")
+      .match_query(Matcher::Any)
+      .with_status(503)
+      .expect(1)
+      .create_async()
+      .await;
+    assert!(send_records(&relay_cfg, "sensors/1", records.clone()).await.is_err());
+    // The breaker is open: the records are only buffered, so the same value still passes the deadband
+    assert!(send_records(&relay_cfg, "sensors/1", records.clone()).await.is_ok());
+    failing.assert_async().await;
+    assert_eq!(circuit_breaker(&relay_cfg, &server.url()).status()["buffered"], 1);
+    assert_eq!(post_aggregation(&relay_cfg, "sensors/1", records.clone()).len(), 1);
+
+    circuit_breaker(&relay_cfg, &server.url()).record_success();
+    let delivered = server
+      .mock("POST", "/integration/network/data")
+      .match_query(Matcher::Any)
+      .with_status(200)
+      .with_body("{}")
+      .create_async()
+      .await;
+    assert!(send_records(&relay_cfg, "sensors/1", records.clone()).await.is_ok());
+    delivered.assert_async().await;
+    assert!(post_aggregation(&relay_cfg, "sensors/1", records).is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_buffered_requests_use_current_tokens() {
+    let mut server = mockito::Server::new_async().await;
+    let mut relay_cfg = get_test_relay_config(&server, "test_buffered_requests_use_current_tokens");
+    relay_cfg.config.forwarding.max_retries = Some(0);
+    relay_cfg.config.forwarding.circuit_breaker.failure_threshold = Some(1);
+    relay_cfg.config.forwarding.circuit_breaker.open_duration_secs = Some(3600);
+    let event = Publish::new("test/topic", QoS::AtLeastOnce, "hello");
+
+    let failing = server
+      .mock("POST", "/integration/network/data")
+      .match_query(Matcher::Any)
+      .with_status(503)
+      .create_async()
+      .await;
+    assert!(forward_buffer_messages(&relay_cfg, &event, &AliasNames::default(), 0)
+      .await
+      .is_err());
+    assert!(forward_buffer_messages(&relay_cfg, &event, &AliasNames::default(), 0)
+      .await
+      .is_ok());
+    failing.remove_async().await;
+
+    // The token rotates while the message waits in the buffer
+    relay_cfg.config.network_token = "rotated_network_token".into();
+    relay_cfg.config.forwarding.circuit_breaker.open_duration_secs = Some(0);
+    let recovered = server
+      .mock("POST", "/integration/network/data")
+      .match_query(Matcher::Any)
+      .match_header("authorization", "rotated_network_token")
+      .with_status(200)
+      .expect(2)
+      .create_async()
+      .await;
+    assert!(forward_buffer_messages(&relay_cfg, &event, &AliasNames::default(), 0)
+      .await
+      .is_ok());
+    for _ in 0..50 {
+      if recovered.matched_async().await {
+        break;
+      }
+      sleep(Duration::from_millis(20)).await;
+    }
+    recovered.assert_async().await;
+  }
+
+  #[tokio::test]
+  async fn test_routes_to_other_regions_have_their_own_breaker() {
+    let mut server = mockito::Server::new_async().await;
+    let mut relay_cfg = get_test_relay_config(&server, "test_routes_to_other_regions_have_their_own_breaker");
+    relay_cfg.config.forwarding.max_retries = Some(0);
+    relay_cfg.config.forwarding.circuit_breaker.failure_threshold = Some(1);
+    relay_cfg.config.routes = vec![Route {
+      topic: "eu/#".to_string(),
+      tagoio_url: Some(format!("{}/eu", server.url())),
+      ..Default::default()
+    }];
+
+    let down = server
+      .mock("POST", "/eu/integration/network/data")
+      .match_query(Matcher::Any)
+      .with_status(503)
+      .expect(1)
+      .create_async()
+      .await;
+    let up = server
+      .mock("POST", "/integration/network/data")
+      .match_query(Matcher::Any)
+      .with_status(200)
+      .expect(1)
+      .create_async()
+      .await;
+    let records = vec![serde_json::json!({"variable": "temperature", "value": 21})];
+    assert!(forward_records(&relay_cfg, "eu/sensor", records.clone()).await.is_err());
+    assert!(forward_records(&relay_cfg, "us/sensor", records).await.is_ok());
+    down.assert_async().await;
+    up.assert_async().await;
+  }
+
+  #[test]
+  fn test_build_http_client_options() {
+    let config = ConfigFile {
+      tagoio_proxy: Some("socks5://127.0.0.1:1080".to_string()),
+      tagoio_no_proxy: Some(vec!["localhost".to_string(), "10.0.0.0/8".to_string()]),
+      ..Default::default()
+    };
+    assert!(build_http_client(&config).is_ok());
+
+    let config = ConfigFile {
+      tagoio_tls_ca: Some("/nonexistent/ca.pem".to_string()),
+      ..Default::default()
+    };
+    let error = build_http_client(&config).unwrap_err();
+    assert!(error.to_string().contains("tagoio_tls_ca"));
+
+    let config = ConfigFile {
+      tagoio_tls_cert: Some("/nonexistent/client.pem".to_string()),
+      ..Default::default()
+    };
+    assert!(build_http_client(&config).is_err());
+  }
+
+  #[test]
+  fn test_retry_backoff_is_capped() {
+    let forwarding = Forwarding {
+      backoff_base_ms: Some(500),
+      backoff_max_ms: Some(2_000),
+      backoff_jitter: Some(false),
+      ..Default::default()
+    };
+
+    assert_eq!(retry_backoff(&forwarding, 1), Duration::from_millis(1_000));
+    assert_eq!(retry_backoff(&forwarding, 10), Duration::from_millis(2_000));
+
+    let jittered = retry_backoff(&Forwarding::default().with_defaults().unwrap(), 1);
+    assert!(jittered >= Duration::from_millis(500) && jittered <= Duration::from_millis(1_000));
+  }
+
   #[tokio::test]
   async fn test_verify_network_token() {
     let mut server = mockito::Server::new_async().await;
-    let relay_cfg = get_test_relay_config(&server);
+    let relay_cfg = get_test_relay_config(&server, "test_verify_network_token");
     let _m = server
       .mock("GET", "/info")
       .match_header("AUTHORIZATION", "test_network_token")
@@ -363,7 +1530,7 @@ mod tests {
   #[tokio::test]
   async fn test_verify_network_token_invalid() {
     let mut server = mockito::Server::new_async().await;
-    let relay_cfg = get_test_relay_config(&server);
+    let relay_cfg = get_test_relay_config(&server, "test_verify_network_token_invalid");
 
     let _m = server
       .mock("GET", "/info")
@@ -0,0 +1,238 @@
+use crate::{
+  schema::{RelayConfig, WasmLimits},
+  services::{
+    metrics,
+    payload::{merge_defaults, FileCache},
+  },
+};
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use rumqttc::Publish;
+use serde_json::Value;
+use std::sync::Arc;
+use wasmi::{core::TrapCode, Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+/**
+ * Fuel granted per millisecond of `timeout_ms`, a pace the interpreter keeps up with on slow hardware.
+ * Fuel is what stops a decoder, so the timeout is turned into a fuel budget.
+ */
+const FUEL_PER_MS: u64 = 100_000;
+
+/**
+ * Fuel of a run: `fuel`, lowered to what fits in `timeout_ms`
+ */
+fn fuel(limits: &WasmLimits) -> u64 {
+  let timeout_ms = limits.timeout_ms.unwrap_or(100);
+  limits
+    .fuel
+    .unwrap_or(10_000_000)
+    .min(timeout_ms.saturating_mul(FUEL_PER_MS))
+}
+
+/**
+ * Each module gets its own engine: a module that runs until its fuel is exhausted
+ * then cannot hold up compiling or running other decoders.
+ */
+fn engine() -> Engine {
+  let mut config = Config::default();
+  config.consume_fuel(true);
+  Engine::new(&config)
+}
+
+static DECODERS: Lazy<FileCache<Arc<Module>>> = Lazy::new(FileCache::default);
+
+/**
+ * Compile a decoder module, reusing the cached one while the file is unchanged
+ */
+pub fn compile(path: &str) -> anyhow::Result<Arc<Module>> {
+  DECODERS.get_or_load(path, || {
+    let wasm = std::fs::read(path).with_context(|| format!("Failed to read decoder {}", path))?;
+    Ok(Arc::new(
+      compile_bytes(&wasm).with_context(|| format!("Failed to compile decoder {}", path))?,
+    ))
+  })
+}
+
+fn compile_bytes(wasm: &[u8]) -> anyhow::Result<Module> {
+  let module = Module::new(&engine(), wasm)?;
+  for export in ["memory", "alloc", "decode"] {
+    if module.get_export(export).is_none() {
+      anyhow::bail!("the module must export `{}`", export);
+    }
+  }
+  Ok(module)
+}
+
+/**
+ * Call `decode` on a fresh instance of the module. The ABI is:
+ * - `alloc(len: i32) -> i32` returns a pointer to `len` bytes of the exported `memory`
+ * - `decode(topic_ptr: i32, topic_len: i32, payload_ptr: i32, payload_len: i32) -> i64`
+ *   returns `(ptr << 32) | len` of a UTF-8 JSON document: an array of records, one record or `null`
+ */
+fn call(module: &Module, limits: &WasmLimits, topic: &str, payload: &[u8]) -> anyhow::Result<Value> {
+  let store_limits = StoreLimitsBuilder::new()
+    .memory_size(limits.max_memory_bytes.unwrap_or(16 * 1024 * 1024))
+    .instances(1)
+    .build();
+  let mut store = Store::new(module.engine(), store_limits);
+  store.limiter(|limits: &mut StoreLimits| limits);
+  let fuel = fuel(limits);
+  store.set_fuel(fuel).map_err(|e| anyhow::anyhow!("{}", e))?;
+
+  let instance = Linker::<StoreLimits>::new(module.engine())
+    .instantiate(&mut store, module)?
+    .start(&mut store)?;
+  let memory = instance
+    .get_memory(&store, "memory")
+    .context("the module must export `memory`")?;
+  let alloc = instance.get_typed_func::<i32, i32>(&store, "alloc")?;
+  let decode = instance.get_typed_func::<(i32, i32, i32, i32), i64>(&store, "decode")?;
+
+  let write = |store: &mut Store<StoreLimits>, bytes: &[u8]| -> anyhow::Result<(i32, i32)> {
+    let len = i32::try_from(bytes.len()).context("input too large")?;
+    let ptr = alloc.call(&mut *store, len)?;
+    memory
+      .write(&mut *store, ptr as u32 as usize, bytes)
+      .map_err(|e| anyhow::anyhow!("{}", e))?;
+    Ok((ptr, len))
+  };
+  let (topic_ptr, topic_len) = write(&mut store, topic.as_bytes())?;
+  let (payload_ptr, payload_len) = write(&mut store, payload)?;
+
+  let output = decode
+    .call(&mut store, (topic_ptr, topic_len, payload_ptr, payload_len))
+    .map_err(|e| match e.as_trap_code() {
+      Some(TrapCode::OutOfFuel) => anyhow::anyhow!("ran out of its {} fuel (`fuel` and `timeout_ms`)", fuel),
+      _ => e.into(),
+    })? as u64;
+  let (ptr, len) = ((output >> 32) as usize, (output & 0xFFFF_FFFF) as usize);
+  let data = memory.data(&store);
+  let json = ptr
+    .checked_add(len)
+    .filter(|end| *end <= data.len() && len <= limits.max_memory_bytes.unwrap_or(16 * 1024 * 1024))
+    .map(|end| &data[ptr..end])
+    .with_context(|| format!("the decoder output ({} bytes at {}) is outside its memory", len, ptr))?;
+  serde_json::from_slice(json).context("the decoder output is not valid JSON")
+}
+
+/**
+ * Run the decoder on the blocking pool, until it returns or runs out of fuel
+ */
+async fn decode(module: Arc<Module>, limits: &WasmLimits, publish: &Publish) -> anyhow::Result<Vec<Value>> {
+  let (limits, topic, payload) = (limits.clone(), publish.topic.clone(), publish.payload.clone());
+  let output = tokio::task::spawn_blocking(move || call(&module, &limits, &topic, &payload)).await??;
+
+  let records = match output {
+    Value::Null => Vec::new(),
+    Value::Array(records) => records,
+    record => vec![record],
+  };
+  for record in &records {
+    if record.get("variable").and_then(Value::as_str).is_none() {
+      anyhow::bail!("every record needs a `variable` string, got {}", record);
+    }
+  }
+  Ok(records)
+}
+
+/**
+ * Replace the default records of an uplink with the ones built by the subscription decoder.
+ * On error the default records are kept.
+ */
+pub async fn transform(relay_cfg: &RelayConfig, path: &str, publish: &Publish, records: Vec<Value>) -> Vec<Value> {
+  let limits = &relay_cfg.config.mqtt.wasm_limits;
+  let result = match compile(path) {
+    Ok(module) => decode(module, limits, publish).await,
+    Err(e) => Err(e),
+  };
+
+  match result {
+    Ok(decoded) => merge_defaults(decoded, &records),
+    Err(e) => {
+      log::error!(target: "mqtt", relay_id = relay_cfg.id.as_str(), topic = publish.topic.as_str(), error:% = format!("{:#}", e); "Decoder {} failed on topic {}: {:#}", path, publish.topic, e);
+      metrics::increment_counter(
+        "decoder_errors_total",
+        &[("relay_id", &relay_cfg.id), ("decoder", path)],
+      );
+      records
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rumqttc::QoS;
+  use serde_json::json;
+
+  /**
+   * Copies the payload after the output prefix and closes the JSON string
+   */
+  const ECHO_DECODER: &str = r#"
+    (module
+      (memory (export "memory") 1)
+      (global $next (mut i32) (i32.const 1024))
+      (data (i32.const 0) "{\"variable\":\"raw\",\"value\":\"")
+      (func (export "alloc") (param $len i32) (result i32)
+        (local $ptr i32)
+        (local.set $ptr (global.get $next))
+        (global.set $next (i32.add (global.get $next) (local.get $len)))
+        (local.get $ptr))
+      (func (export "decode") (param i32 i32 i32 i32) (result i64)
+        (memory.copy (i32.const 27) (local.get 2) (local.get 3))
+        (i32.store16 (i32.add (i32.const 27) (local.get 3)) (i32.const 0x7D22))
+        (i64.extend_i32_u (i32.add (local.get 3) (i32.const 29)))))
+  "#;
+
+  async fn decode_source(source: &str, limits: &WasmLimits, payload: &str) -> anyhow::Result<Vec<Value>> {
+    let module = Arc::new(compile_bytes(&wat::parse_str(source).unwrap())?);
+    decode(module, limits, &Publish::new("sensors/1", QoS::AtMostOnce, payload)).await
+  }
+
+  #[tokio::test]
+  async fn test_wasm_decoder_records() {
+    let records = decode_source(ECHO_DECODER, &WasmLimits::default(), "21.5")
+      .await
+      .unwrap();
+    assert_eq!(records, vec![json!({"variable": "raw", "value": "21.5"})]);
+
+    assert!(compile_bytes(&wat::parse_str("(module)").unwrap()).is_err());
+
+    // Output length far past the memory of the module
+    let source = r#"
+      (module
+        (memory (export "memory") 1)
+        (func (export "alloc") (param i32) (result i32) (i32.const 0))
+        (func (export "decode") (param i32 i32 i32 i32) (result i64) (i64.const 0x7FFFFFFF)))
+    "#;
+    let error = decode_source(source, &WasmLimits::default(), "x").await.unwrap_err();
+    assert!(error.to_string().contains("outside its memory"), "{}", error);
+  }
+
+  #[tokio::test]
+  async fn test_wasm_decoder_limits() {
+    let source = r#"
+      (module
+        (memory (export "memory") 1)
+        (func (export "alloc") (param i32) (result i32) (i32.const 0))
+        (func (export "decode") (param i32 i32 i32 i32) (result i64)
+          (loop $forever (br $forever))
+          (i64.const 0)))
+    "#;
+    let limits = WasmLimits {
+      fuel: Some(10_000),
+      ..Default::default()
+    };
+    assert!(decode_source(source, &limits, "x").await.is_err());
+
+    // The timeout lowers the fuel
+    let limits = WasmLimits {
+      fuel: Some(1_000_000_000),
+      timeout_ms: Some(1),
+      ..Default::default()
+    };
+    assert_eq!(fuel(&limits), FUEL_PER_MS);
+    let error = decode_source(source, &limits, "x").await.unwrap_err();
+    assert!(error.to_string().contains("100000 fuel"), "{}", error);
+  }
+}
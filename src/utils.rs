@@ -3,7 +3,8 @@ use figment::{
   Figment,
 };
 use home::home_dir;
-use std::time::Duration;
+use log::kv::{Key, Value, VisitSource};
+use std::{io::Write, time::Duration};
 
 use crate::schema::ConfigFile;
 
@@ -64,23 +65,56 @@ pub fn init_config(user_path: Option<impl AsRef<str>>) {
     std::process::exit(1);
   });
 
-  log::info!("Configuration file created at {}", config_path.display());
+  log::info!(target: "info", "Configuration file created at {}", config_path.display());
 }
 
 /**
- * Fetch the configuration file
+ * Keys without a default value, relative to `[relay]`
+ */
+const REQUIRED_KEYS: [&str; 6] = [
+  "network_token",
+  "authorization_token",
+  "mqtt.tls_enabled",
+  "mqtt.address",
+  "mqtt.port",
+  "mqtt.subscribe",
+];
+
+/**
+ * Required keys missing from every provider, with the matching environment variable
+ */
+fn missing_required_keys(figment: &Figment) -> Vec<String> {
+  REQUIRED_KEYS
+    .iter()
+    .filter(|key| figment.find_value(&format!("relay.{}", key)).is_err())
+    .map(|key| format!("{} (TAGOIO__RELAY__{})", key, key.replace('.', "__").to_uppercase()))
+    .collect()
+}
+
+/**
+ * Fetch the configuration file.
+ * Without a file at the default path, the configuration is read from the environment variables only.
  */
 pub fn fetch_config_file(user_path: Option<String>) -> Option<ConfigFile> {
+  let explicit_path = user_path.is_some() || std::env::var("TAGOIO__RELAY__CONFIG_PATH").is_ok();
   let config_path = get_config_path(user_path);
-  // If the config file doesn't exist, create it
-  if !config_path.exists() {
-    log::error!(target: "error", "Configuration file not found.");
+
+  let mut figment = Figment::new();
+  if config_path.exists() {
+    figment = figment.merge(Toml::file(&config_path));
+  } else if explicit_path {
+    log::error!(target: "error", "Configuration file not found at {}.", config_path.display());
     std::process::exit(1);
+  } else {
+    log::info!(target: "info", "No configuration file at {}: using the TAGOIO__RELAY__* environment variables", config_path.display());
   }
+  let figment = figment.merge(Env::prefixed("TAGOIO__").split("__"));
 
-  let figment = Figment::new()
-    .merge(Toml::file(&config_path))
-    .merge(Env::prefixed("TAGOIO__").split("__"));
+  let missing = missing_required_keys(&figment);
+  if !missing.is_empty() {
+    log::error!(target: "error", "Missing required configuration keys: {}", missing.join(", "));
+    std::process::exit(1);
+  }
 
   let config: ConfigFileResponse = figment.extract().unwrap_or_else(|err| {
     log::error!(target: "error", "Failed to initialize configuration: {}", err);
@@ -90,6 +124,50 @@ pub fn fetch_config_file(user_path: Option<String>) -> Option<ConfigFile> {
   config.relay
 }
 
+/**
+ * Collect the structured fields of a log record (`relay_id`, `topic`, `message_id`, `error`, ...)
+ */
+struct JsonFields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+  fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+    let value = if let Some(v) = value.to_u64() {
+      serde_json::json!(v)
+    } else if let Some(v) = value.to_i64() {
+      serde_json::json!(v)
+    } else if let Some(v) = value.to_f64() {
+      serde_json::json!(v)
+    } else if let Some(v) = value.to_bool() {
+      serde_json::json!(v)
+    } else {
+      serde_json::json!(value.to_string())
+    };
+    self.0.insert(key.as_str().to_string(), value);
+    Ok(())
+  }
+}
+
+/**
+ * Build the JSON object logged for a record when `--log-format json` is used
+ */
+pub fn json_log_record(timestamp: &str, record: &log::Record) -> serde_json::Value {
+  let mut fields = serde_json::Map::new();
+  fields.insert("timestamp".to_string(), serde_json::json!(timestamp));
+  fields.insert("level".to_string(), serde_json::json!(record.level().as_str()));
+  fields.insert("target".to_string(), serde_json::json!(record.target()));
+  fields.insert("message".to_string(), serde_json::json!(record.args().to_string()));
+  let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+  serde_json::Value::Object(fields)
+}
+
+/**
+ * env_logger format writing one JSON object per line
+ */
+pub fn format_json_log(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> std::io::Result<()> {
+  let timestamp = buf.timestamp_millis().to_string();
+  writeln!(buf, "{}", json_log_record(&timestamp, record))
+}
+
 pub fn calculate_backoff(attempt: u32) -> Duration {
   let base_delay = Duration::from_secs(5);
   let max_delay = Duration::from_secs(60);
@@ -118,4 +196,49 @@ mod tests {
 
     assert_eq!(result, expected_path);
   }
+
+  #[test]
+  fn test_missing_required_keys() {
+    let figment = Figment::new().merge(Toml::string(
+      r#"
+        [relay]
+        network_token = "token"
+        [relay.mqtt]
+        address = "localhost"
+        port = 1883
+      "#,
+    ));
+
+    assert_eq!(
+      missing_required_keys(&figment),
+      vec![
+        "authorization_token (TAGOIO__RELAY__AUTHORIZATION_TOKEN)",
+        "mqtt.tls_enabled (TAGOIO__RELAY__MQTT__TLS_ENABLED)",
+        "mqtt.subscribe (TAGOIO__RELAY__MQTT__SUBSCRIBE)",
+      ]
+    );
+  }
+
+  #[test]
+  fn test_json_log_record() {
+    let fields: [(&str, &dyn log::kv::ToValue); 3] = [
+      ("relay_id", &"self-hosted"),
+      ("topic", &"sensors/1"),
+      ("message_id", &42u16),
+    ];
+    let record = log::Record::builder()
+      .args(format_args!("Received message"))
+      .level(log::Level::Info)
+      .target("mqtt")
+      .key_values(&fields)
+      .build();
+
+    let json = json_log_record("2024-01-01T00:00:00.000Z", &record);
+    assert_eq!(json["level"], "INFO");
+    assert_eq!(json["target"], "mqtt");
+    assert_eq!(json["message"], "Received message");
+    assert_eq!(json["relay_id"], "self-hosted");
+    assert_eq!(json["topic"], "sensors/1");
+    assert_eq!(json["message_id"], 42);
+  }
 }